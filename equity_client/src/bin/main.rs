@@ -22,6 +22,9 @@ enum Command {
         address: String,
     },
     Health,
+    GetTransaction {
+        hash: String,
+    },
     Transaction {
         key_domain: u64,
         value_range: u64,
@@ -44,6 +47,10 @@ pub async fn main() {
             let response = client.health().await.unwrap();
             info!("Health Response is: {:?}", response);
         }
+        Command::GetTransaction { hash } => match client.get_transaction(hash).await {
+            Ok(response) => info!("{:?}", response),
+            Err(e) => error!("{:?}", e),
+        },
         Command::Transaction {
             key_domain,
             value_range,
@@ -52,9 +59,9 @@ pub async fn main() {
             println!("DB Key Domain: {:?}", key_domain);
             println!("DB Key Range: {:?}", value_range);
             println!("Iterations: {:?}", iterations);
-            client.noncer();
             let tester = client.test_transaction(key_domain, value_range, iterations);
             let transaction = client.create_transaction(&tester);
+            client.noncer();
             let response = client.post_transaction(transaction).await.unwrap();
             info!("Transaction Response is: {:?}", response);
         }
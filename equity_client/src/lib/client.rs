@@ -7,10 +7,12 @@ use std::{
 
 use borsh::BorshDeserialize;
 use equity_types::{
-    Body, Credentials, EquityAddressResponse, FullMessage, HealthResponse, PostTransactionResponse,
+    Body, Credentials, DedupStats, EquityAddressResponse, EquivocationProof, FullMessage,
+    HealthResponse, PeerReputation, PeerStatus, PostTransactionResponse, Reconfiguration, TxRecord,
+    ValidatorSet, WaitingTransaction,
 };
 use rand::Rng;
-use serde::de::DeserializeOwned;
+use serde::{de::DeserializeOwned, Serialize};
 use surf::Url;
 use tokio::time::sleep;
 use tracing::info;
@@ -38,7 +40,12 @@ pub async fn borsh_post<T: BorshDeserialize>(url: &Url, body: FullMessage) -> cr
         .map_err(|e| Error::BorshDeserializeError(e, response))
 }
 
-pub async fn serde_post<T: DeserializeOwned>(url: &Url, body: FullMessage) -> crate::Result<T> {
+pub async fn serde_get<T: DeserializeOwned>(url: &Url) -> crate::Result<T> {
+    let response = surf::get(url).recv_bytes().await?;
+    serde_json::from_slice(&response).map_err(|e| Error::SerdeDeserializeError(e, response))
+}
+
+pub async fn serde_post<T: DeserializeOwned>(url: &Url, body: impl Serialize) -> crate::Result<T> {
     let response = surf::post(url).body_json(&body)?.recv_bytes().await?;
     serde_json::from_slice(&response).map_err(|e| Error::SerdeDeserializeError(e, response))
 }
@@ -122,19 +129,12 @@ impl EquityClient {
             public_key: self.credentials.public_key,
             nonce: self.nonce,
             keys_values,
+            dependencies: vec![],
         }
     }
 
     pub fn create_transaction(&self, message: &Body) -> FullMessage {
-        let message_string = serde_json::to_string(message).unwrap();
-
-        let (digest_string, signature) = self.credentials.hash_sign(&message_string);
-
-        FullMessage {
-            body: message.clone(),
-            hash: digest_string,
-            signature,
-        }
+        FullMessage::sign(message.clone(), &self.credentials).unwrap()
     }
 
     pub async fn post_transaction(
@@ -145,6 +145,59 @@ impl EquityClient {
         url.set_path(&self.url_transaction);
         serde_post(&url.join(&transaction.hash)?, transaction).await
     }
+
+    /// Gets a delivered transaction together with its quorum certificate
+    pub async fn get_transaction(&self, hash: &str) -> crate::Result<TxRecord> {
+        let mut url = self.surf_url.clone();
+        url.set_path(&self.url_transaction);
+        serde_get(&url.join(hash)?).await
+    }
+
+    /// Gets the validators of the current epoch
+    pub async fn get_validators(&self) -> crate::Result<ValidatorSet> {
+        serde_get(&self.surf_url.join("validators")?).await
+    }
+
+    /// Gets the evidence of equivocations the node knows about
+    pub async fn get_equivocations(&self) -> crate::Result<Vec<EquivocationProof>> {
+        serde_get(&self.surf_url.join("equivocations")?).await
+    }
+
+    /// Gets the delivered transactions the node holds back and what they are
+    /// waiting on
+    pub async fn get_waiting(&self) -> crate::Result<Vec<WaitingTransaction>> {
+        serde_get(&self.surf_url.join("waiting")?).await
+    }
+
+    /// The peers of the node by address, with whether they are connected
+    pub async fn get_peers(&self) -> crate::Result<BTreeMap<String, PeerStatus>> {
+        serde_get(&self.surf_url.join("peers")?).await
+    }
+
+    /// How many handshakes the node refused because of its allowlist
+    pub async fn get_rejected_peers(&self) -> crate::Result<u64> {
+        serde_get(&self.surf_url.join("peers/rejected")?).await
+    }
+
+    /// The peers the node holds offenses against and their bans, with the
+    /// reasons
+    pub async fn get_reputations(&self) -> crate::Result<Vec<PeerReputation>> {
+        serde_get(&self.surf_url.join("peers/reputation")?).await
+    }
+
+    /// How many repeated consensus messages the node dropped, to tune the
+    /// gossip fanout
+    pub async fn get_dedup_stats(&self) -> crate::Result<DedupStats> {
+        serde_get(&self.surf_url.join("peers/dedup")?).await
+    }
+
+    /// Moves the node to the next epoch, returning its new validators
+    pub async fn reconfigure(
+        &self,
+        reconfiguration: Reconfiguration,
+    ) -> crate::Result<ValidatorSet> {
+        serde_post(&self.surf_url.join("reconfigure")?, reconfiguration).await
+    }
 }
 
 impl FromStr for EquityClient {
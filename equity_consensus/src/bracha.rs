@@ -0,0 +1,950 @@
+//! Bracha's Byzantine reliable broadcast. Every `(public_key, nonce)` of a
+//! `Body` is its own broadcast instance, so honest nodes deliver at most one
+//! message per account nonce. The engine does no IO itself, it is handed
+//! messages and returns what needs to be broadcast or delivered.
+//!
+//! A sender signing two messages for one nonce, or a validator voting for two
+//! of them in the same phase, is reported with an `EquivocationProof`. Only the
+//! first vote of a validator per phase is counted.
+//!
+//! Once an instance delivered and our READY is out, only a marker with the
+//! hash of the delivered message is kept of it. The few messages that still
+//! need the delivered message come out as `Output::Late` and are handed back
+//! with it by whoever stored it. An instance that stays idle without
+//! delivering is expired, only our votes in it are kept.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use ed25519_consensus::{VerificationKey, VerificationKeyBytes};
+#[cfg(feature = "probabilistic")]
+use equity_types::Subscription;
+use equity_types::{
+    ConsensusMessage, Credentials, EquityError, Equivocation, EquivocationProof, FullMessage,
+    QuorumCertificate, Reconfiguration, ValidatorSet, Vote, VoteKind,
+};
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "byzantine")]
+use crate::byzantine::{Behavior, Byzantine};
+#[cfg(feature = "probabilistic")]
+use crate::sampled::{self, SampleParameters, Sampler, Samples};
+
+/// Quorum weights for a total weight of `n` tolerating faulty validators with
+/// up to `f = (n - 1) / 3` of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    pub n: u64,
+    pub f: u64,
+}
+
+impl Thresholds {
+    pub fn new(validators: &ValidatorSet) -> Self {
+        Self {
+            n: validators.total_weight(),
+            f: validators.faulty(),
+        }
+    }
+
+    /// ECHO weight needed before sending READY, more than `(n + f) / 2`
+    pub fn echo(&self) -> u64 {
+        (self.n + self.f) / 2 + 1
+    }
+
+    /// READY weight needed to send our own READY without having seen enough
+    /// ECHOs
+    pub fn ready(&self) -> u64 {
+        self.f + 1
+    }
+
+    /// READY weight needed to deliver
+    pub fn deliver(&self) -> u64 {
+        2 * self.f + 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// Send to every peer
+    Broadcast(ConsensusMessage),
+    /// Send to the peer with the key, only used by the probabilistic broadcast
+    Send(VerificationKeyBytes, ConsensusMessage),
+    /// The message has been reliably broadcast and can be applied
+    Deliver(FullMessage, QuorumCertificate),
+    /// A new epoch started with these validators
+    Reconfigured(ValidatorSet),
+    /// Verified evidence that was not seen before
+    Equivocation(Box<EquivocationProof>),
+    /// The peer with the key sent a message or vote whose signature does not
+    /// verify
+    Invalid(VerificationKeyBytes),
+    /// The peer with the key sent a message or subscription for an instance
+    /// that delivered the message with the hash, which is not kept. It is
+    /// handled by `Bracha::late` once the delivered message is looked up.
+    Late(VerificationKeyBytes, String, ConsensusMessage),
+}
+
+/// The account and nonce a broadcast instance is for
+pub type InstanceId = (VerificationKeyBytes, u64);
+
+#[derive(Debug)]
+enum Mode {
+    /// Every validator talks to every other and votes count by weight
+    Quorum,
+    #[cfg(feature = "probabilistic")]
+    Sampled(Box<Sampler>),
+}
+
+/// The steps that need enough votes
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    /// Sending READY after ECHOs
+    Echo,
+    /// Sending READY after READYs
+    Ready,
+    Deliver,
+}
+
+/// The account and nonce a `message` is for, if any
+fn instance_id(message: &ConsensusMessage) -> Option<InstanceId> {
+    match (message, message.message()) {
+        (ConsensusMessage::Subscribe(subscription), _) => {
+            Some((subscription.public_key.into(), subscription.nonce))
+        }
+        (_, Some(m)) => Some((m.body.public_key.into(), m.body.nonce)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+struct Instance {
+    /// The hash of the message we echoed, only one message is ever echoed per
+    /// instance
+    echoed: Option<String>,
+    /// The hash of the message we sent READY for
+    readied: Option<String>,
+    /// The hash of the delivered message
+    delivered: Option<String>,
+    /// Every verified message of the instance, more than one means the sender
+    /// equivocated
+    messages: HashMap<String, FullMessage>,
+    /// The first vote of every voter and the hash it is for
+    echoes: HashMap<VerificationKeyBytes, (String, Vote)>,
+    readies: HashMap<VerificationKeyBytes, (String, Vote)>,
+    /// Whether a message came in since the last `Bracha::expire`
+    active: bool,
+    #[cfg(feature = "probabilistic")]
+    samples: Samples,
+}
+
+/// What is kept of a delivered instance, enough to drop copies of the message
+/// and its votes, and to tell which late messages need the delivered one
+#[derive(Debug)]
+struct Delivered {
+    hash: String,
+    /// Whether we echoed the delivered message
+    #[cfg(feature = "probabilistic")]
+    echoed: bool,
+    /// Whether we sent READY for the delivered message
+    #[cfg(feature = "probabilistic")]
+    readied: bool,
+}
+
+/// What is kept of an instance that expired before it delivered, our votes
+/// so that we never vote for another message of the instance
+#[derive(Debug, Default)]
+struct Expired {
+    echoed: Option<String>,
+    readied: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Bracha {
+    credentials: Credentials,
+    validators: ValidatorSet,
+    mode: Mode,
+    instances: HashMap<InstanceId, Instance>,
+    delivered: HashMap<InstanceId, Delivered>,
+    expired: HashMap<InstanceId, Expired>,
+    /// Hashes of messages of undelivered instances that have passed
+    /// `FullMessage::verify`
+    verified: HashSet<String>,
+    /// The offences reported so far, one proof per offender, kind and instance
+    /// is enough
+    proofs: HashSet<(Equivocation, VerificationKeyBytes, InstanceId)>,
+    #[cfg(feature = "byzantine")]
+    byzantine: Option<Byzantine>,
+}
+
+impl Bracha {
+    pub fn new(credentials: Credentials, validators: ValidatorSet) -> Self {
+        Self {
+            credentials,
+            validators,
+            mode: Mode::Quorum,
+            instances: HashMap::new(),
+            delivered: HashMap::new(),
+            expired: HashMap::new(),
+            verified: HashSet::new(),
+            proofs: HashSet::new(),
+            #[cfg(feature = "byzantine")]
+            byzantine: None,
+        }
+    }
+
+    /// Switches to the probabilistic broadcast with samples drawn by an RNG
+    /// seeded with `seed`. Every node in a network has to use the same mode.
+    /// Certificates then only hold the READYs that were received, which are
+    /// not necessarily a quorum.
+    #[cfg(feature = "probabilistic")]
+    pub fn with_sampling(mut self, parameters: SampleParameters, seed: u64) -> Self {
+        self.mode = Mode::Sampled(Box::new(Sampler::new(parameters, seed)));
+        self
+    }
+
+    /// Makes this node show the consensus `behaviors`, only for testing how
+    /// the honest nodes cope
+    #[cfg(feature = "byzantine")]
+    pub fn with_byzantine(mut self, behaviors: Vec<Behavior>) -> Self {
+        self.byzantine = Some(Byzantine::new(self.credentials.clone(), behaviors));
+        self
+    }
+
+    /// The validators of the current epoch
+    pub fn validators(&self) -> &ValidatorSet {
+        &self.validators
+    }
+
+    /// Starts a broadcast of `message` with this node as the sender
+    pub fn broadcast(&mut self, message: FullMessage) -> Vec<Output> {
+        let own = self.credentials.public_key.into();
+        let outputs = match self.mode {
+            Mode::Quorum => {
+                let send = ConsensusMessage::Send(message);
+                let mut outputs = vec![Output::Broadcast(send.clone())];
+                outputs.extend(self.process(own, send));
+                outputs
+            }
+            #[cfg(feature = "probabilistic")]
+            Mode::Sampled(_) => self.process(own, ConsensusMessage::Gossip(message)),
+        };
+        self.misbehave(None, outputs)
+    }
+
+    /// Moves to the next epoch if `reconfiguration` is signed by a quorum of
+    /// the current validators, and passes it on to the peers
+    pub fn reconfigure(
+        &mut self,
+        reconfiguration: Reconfiguration,
+    ) -> Result<Vec<Output>, EquityError> {
+        let outputs = self.apply_reconfiguration(reconfiguration)?;
+        Ok(self.misbehave(None, outputs))
+    }
+
+    fn apply_reconfiguration(
+        &mut self,
+        reconfiguration: Reconfiguration,
+    ) -> Result<Vec<Output>, EquityError> {
+        reconfiguration.verify(&self.validators)?;
+
+        info!(
+            target: "equity-consensus",
+            "Starting epoch {} with {} validators",
+            reconfiguration.validators.epoch,
+            reconfiguration.validators.validators.len()
+        );
+        self.validators = reconfiguration.validators.clone();
+
+        Ok(vec![
+            Output::Reconfigured(self.validators.clone()),
+            Output::Broadcast(ConsensusMessage::Reconfigure(Box::new(reconfiguration))),
+        ])
+    }
+
+    /// Handles a `message` from the peer with the `from` key. Our own ECHOs and
+    /// READYs are counted as they are produced, so they never need to be
+    /// fed back in. Votes count with the weight their sender has in the current
+    /// epoch.
+    pub fn handle(&mut self, from: VerificationKey, message: ConsensusMessage) -> Vec<Output> {
+        #[cfg(feature = "byzantine")]
+        if self.byzantine.is_some() {
+            let seen = message.message().cloned();
+            let outputs = self.process(from.into(), message);
+            return self.misbehave(seen, outputs)
+        }
+        self.process(from.into(), message)
+    }
+
+    fn process(&mut self, from: VerificationKeyBytes, message: ConsensusMessage) -> Vec<Output> {
+        let own = VerificationKeyBytes::from(self.credentials.public_key);
+        let mut outputs = vec![];
+        let mut queue = VecDeque::from([(from, message)]);
+
+        while let Some((from, message)) = queue.pop_front() {
+            let message = match message {
+                ConsensusMessage::Reconfigure(reconfiguration) => {
+                    match self.apply_reconfiguration(*reconfiguration) {
+                        Ok(reconfigured) => outputs.extend(reconfigured),
+                        // repeats of an applied reconfiguration also end here
+                        Err(e) => {
+                            debug!(target: "equity-consensus", "Ignoring reconfiguration: {}", e)
+                        }
+                    }
+                    continue
+                }
+                ConsensusMessage::Equivocation(proof) => {
+                    outputs.extend(self.record_proof(*proof));
+                    continue
+                }
+                message => message,
+            };
+
+            let delivered = instance_id(&message)
+                .and_then(|id| self.delivered.get(&id).map(|delivered| (id, delivered)));
+            if let Some((id, delivered)) = delivered {
+                let late = match (&message, message.message()) {
+                    #[cfg(feature = "probabilistic")]
+                    (ConsensusMessage::Subscribe(subscription), _) => {
+                        delivered.voted(subscription.kind)
+                    }
+                    // a conflicting message is only looked at until the sender
+                    // is proven
+                    (_, Some(m)) => {
+                        m.hash != delivered.hash
+                            && !self.proofs.contains(&(Equivocation::Sender, id.0, id))
+                    }
+                    _ => false,
+                };
+                if late {
+                    outputs.push(Output::Late(from, delivered.hash.clone(), message));
+                }
+                continue
+            }
+
+            let account = match (&message, message.message()) {
+                (ConsensusMessage::Subscribe(subscription), _) => {
+                    (subscription.public_key, subscription.nonce)
+                }
+                (_, Some(m)) if self.check(m) => (m.body.public_key, m.body.nonce),
+                (_, Some(_)) => {
+                    if from != own {
+                        outputs.push(Output::Invalid(from));
+                    }
+                    continue
+                }
+                _ => continue,
+            };
+            let id: InstanceId = (account.0.into(), account.1);
+
+            let vote = match &message {
+                ConsensusMessage::Echo(message, vote) => Some((VoteKind::Echo, message, vote)),
+                ConsensusMessage::Ready(message, vote) => Some((VoteKind::Ready, message, vote)),
+                _ => None,
+            };
+            if let Some((kind, message, vote)) = vote {
+                // votes only count for the node that signed them
+                if VerificationKeyBytes::from(vote.public_key) != from
+                    || vote.verify(kind, &self.validators, &message.hash).is_err()
+                {
+                    warn!(target: "equity-consensus", "Dropping {:?} with an invalid vote", kind);
+                    if from != own {
+                        outputs.push(Output::Invalid(from));
+                    }
+                    continue
+                }
+            }
+
+            let Self {
+                credentials,
+                validators,
+                mode,
+                instances,
+                expired,
+                ..
+            } = self;
+            let mut next = vec![];
+            let instance = instances.entry(id).or_insert_with(|| {
+                let voted = expired.remove(&id).unwrap_or_default();
+                let instance = Instance {
+                    echoed: voted.echoed,
+                    readied: voted.readied,
+                    ..Instance::default()
+                };
+                match mode {
+                    Mode::Quorum => instance,
+                    #[cfg(feature = "probabilistic")]
+                    Mode::Sampled(sampler) => {
+                        let instance = Instance {
+                            samples: sampler.samples(validators),
+                            ..instance
+                        };
+                        next.extend(instance.subscribe(account));
+                        instance
+                    }
+                }
+            });
+            instance.active = true;
+
+            let mut proofs = vec![];
+            if let Some(message) = message.message() {
+                if !instance.messages.contains_key(&message.hash) {
+                    // one proof per instance is enough
+                    if let (1, Some(first)) =
+                        (instance.messages.len(), instance.messages.values().next())
+                    {
+                        proofs.push(EquivocationProof::sender(first.clone(), message.clone()));
+                    }
+                    instance
+                        .messages
+                        .insert(message.hash.clone(), message.clone());
+                }
+            }
+
+            let (mut echo, mut ready) = (None, None);
+            match message {
+                ConsensusMessage::Send(message) | ConsensusMessage::Gossip(message) => {
+                    if instance.echoed.is_none() {
+                        instance.echoed = Some(message.hash.clone());
+                        echo = Some(message);
+                    }
+                }
+                ConsensusMessage::Echo(message, vote) => {
+                    proofs.extend(record_vote(
+                        Equivocation::Echo,
+                        &instance.messages,
+                        &mut instance.echoes,
+                        from,
+                        &message,
+                        *vote,
+                    ));
+                    if instance.readied.is_none()
+                        && instance.reached(mode, validators, Step::Echo, &message.hash)
+                    {
+                        ready = Some(message);
+                    }
+                }
+                ConsensusMessage::Ready(message, vote) => {
+                    proofs.extend(record_vote(
+                        Equivocation::Ready,
+                        &instance.messages,
+                        &mut instance.readies,
+                        from,
+                        &message,
+                        *vote,
+                    ));
+                    if instance.delivered.is_none()
+                        && instance.reached(mode, validators, Step::Deliver, &message.hash)
+                    {
+                        instance.delivered = Some(message.hash.clone());
+                        outputs.push(Output::Deliver(
+                            message.clone(),
+                            certificate(&message.hash, validators, &instance.readies),
+                        ));
+                    }
+                    if instance.readied.is_none()
+                        && instance.reached(mode, validators, Step::Ready, &message.hash)
+                    {
+                        ready = Some(message);
+                    }
+                }
+                #[cfg(feature = "probabilistic")]
+                ConsensusMessage::Subscribe(subscription) => {
+                    next.extend(instance.subscribed(
+                        credentials,
+                        validators,
+                        from,
+                        subscription.kind,
+                    ));
+                }
+                #[cfg(not(feature = "probabilistic"))]
+                ConsensusMessage::Subscribe(_) => (),
+                // handled above
+                ConsensusMessage::Reconfigure(_) | ConsensusMessage::Equivocation(_) => (),
+            }
+
+            if let Some(message) = echo {
+                let vote = Vote::sign(VoteKind::Echo, validators, &message.hash, credentials);
+                next.extend(instance.echo(mode, message, vote));
+            }
+            if let Some(message) = ready {
+                instance.readied = Some(message.hash.clone());
+                let vote = Vote::sign(VoteKind::Ready, validators, &message.hash, credentials);
+                next.extend(instance.ready(mode, message, vote));
+            }
+            let done = instance.delivered.is_some() && instance.readied.is_some();
+
+            for proof in proofs {
+                outputs.extend(self.record_proof(proof));
+            }
+            if done {
+                self.prune(id);
+            }
+
+            for (target, message) in next {
+                match target {
+                    Target::All => {
+                        outputs.push(Output::Broadcast(message.clone()));
+                        queue.push_back((own, message));
+                    }
+                    Target::Peer(peer) if peer == own => queue.push_back((own, message)),
+                    Target::Peer(peer) => outputs.push(Output::Send(peer, message)),
+                }
+            }
+        }
+
+        outputs
+    }
+
+    /// The message that is being broadcast but not yet delivered for an
+    /// instance
+    pub fn pending(&self, id: &InstanceId) -> Option<&FullMessage> {
+        self.instances
+            .get(id)
+            .filter(|instance| instance.delivered.is_none())
+            .and_then(|instance| {
+                instance
+                    .echoed
+                    .as_ref()
+                    .and_then(|hash| instance.messages.get(hash))
+            })
+    }
+
+    /// Marks the instance of `message` as delivered before a restart, so that
+    /// copies of it are dropped and conflicting messages reported
+    pub fn restore_delivered(&mut self, message: FullMessage) {
+        let id = (message.body.public_key.into(), message.body.nonce);
+        self.instances.remove(&id);
+        self.expired.remove(&id);
+        let delivered = Delivered {
+            hash: message.hash,
+            #[cfg(feature = "probabilistic")]
+            echoed: false,
+            #[cfg(feature = "probabilistic")]
+            readied: false,
+        };
+        self.delivered.insert(id, delivered);
+    }
+
+    /// Takes back our own ECHO or READY of an undelivered instance from
+    /// before a restart, so that we never vote for another message of the
+    /// instance. The vote is sent to the peers again.
+    pub fn restore_vote(&mut self, vote: ConsensusMessage) -> Vec<Output> {
+        let own = VerificationKeyBytes::from(self.credentials.public_key);
+        let (echo, message, signed) = match &vote {
+            ConsensusMessage::Echo(message, vote) => (true, message, vote),
+            ConsensusMessage::Ready(message, vote) => (false, message, vote),
+            _ => return vec![],
+        };
+        if VerificationKeyBytes::from(signed.public_key) != own {
+            return vec![]
+        }
+        let hash = message.hash.clone();
+        let id = match instance_id(&vote) {
+            Some(id) if !self.delivered.contains_key(&id) => id,
+            _ => return vec![],
+        };
+
+        let mut outputs = vec![Output::Broadcast(vote.clone())];
+        outputs.extend(self.process(own, vote));
+        if let Some(instance) = self.instances.get_mut(&id) {
+            let voted = match echo {
+                true => &mut instance.echoed,
+                false => &mut instance.readied,
+            };
+            voted.get_or_insert(hash);
+        }
+        outputs
+    }
+
+    /// Outputs `proof` and passes it on to the peers if it is valid and was
+    /// not reported before
+    pub fn report(&mut self, proof: EquivocationProof) -> Vec<Output> {
+        let outputs = self.record_proof(proof);
+        self.misbehave(None, outputs)
+    }
+
+    /// Handles a `message` from the peer with the `from` key that came out
+    /// as `Output::Late`, given the message its instance delivered
+    #[cfg_attr(not(feature = "probabilistic"), allow(unused_variables))]
+    pub fn late(
+        &mut self,
+        from: VerificationKeyBytes,
+        delivered: FullMessage,
+        message: ConsensusMessage,
+    ) -> Vec<Output> {
+        let id = (delivered.body.public_key.into(), delivered.body.nonce);
+        // only the delivered message is voted for
+        let marker = match self.delivered.get(&id) {
+            Some(marker) if marker.hash == delivered.hash => marker,
+            _ => return vec![],
+        };
+        let outputs = match (&message, message.message()) {
+            #[cfg(feature = "probabilistic")]
+            (ConsensusMessage::Subscribe(subscription), _) => marker
+                .vote(
+                    &self.credentials,
+                    &self.validators,
+                    delivered,
+                    subscription.kind,
+                )
+                .map(|vote| vec![Output::Send(from, vote)])
+                .unwrap_or_default(),
+            (_, Some(m)) if m.hash != marker.hash => {
+                self.record_proof(EquivocationProof::sender(delivered, m.clone()))
+            }
+            _ => vec![],
+        };
+        self.misbehave(None, outputs)
+    }
+
+    /// Expires the undelivered instances that got no message since the last
+    /// call, so that broadcasts that never finish don't pile up. Returns the
+    /// expired instances.
+    pub fn expire(&mut self) -> Vec<InstanceId> {
+        let idle: Vec<InstanceId> = self
+            .instances
+            .iter()
+            .filter(|(_, instance)| !instance.active)
+            .map(|(id, _)| *id)
+            .collect();
+        for instance in self.instances.values_mut() {
+            instance.active = false;
+        }
+        for id in &idle {
+            self.prune(*id);
+        }
+        idle
+    }
+
+    /// Replaces the instance `id` with a marker of what it delivered, or of
+    /// our votes if it did not deliver
+    fn prune(&mut self, id: InstanceId) {
+        let Some(instance) = self.instances.remove(&id) else {
+            return
+        };
+        for hash in instance.messages.keys() {
+            self.verified.remove(hash);
+        }
+        let Some(hash) = instance.delivered else {
+            if instance.echoed.is_some() || instance.readied.is_some() {
+                let expired = Expired {
+                    echoed: instance.echoed,
+                    readied: instance.readied,
+                };
+                self.expired.insert(id, expired);
+            }
+            return
+        };
+        let delivered = Delivered {
+            #[cfg(feature = "probabilistic")]
+            echoed: instance.echoed.as_ref() == Some(&hash),
+            #[cfg(feature = "probabilistic")]
+            readied: instance.readied.as_ref() == Some(&hash),
+            hash,
+        };
+        self.delivered.insert(id, delivered);
+    }
+
+    fn record_proof(&mut self, proof: EquivocationProof) -> Vec<Output> {
+        let id = (
+            proof.kind,
+            proof.offender().into(),
+            (proof.first.body.public_key.into(), proof.first.body.nonce),
+        );
+        if self.proofs.contains(&id) {
+            return vec![]
+        }
+        if let Err(e) = proof.verify(&self.validators) {
+            warn!(target: "equity-consensus", "Dropping equivocation proof: {}", e);
+            return vec![]
+        }
+        self.proofs.insert(id);
+
+        let proof = Box::new(proof);
+        vec![
+            Output::Equivocation(proof.clone()),
+            Output::Broadcast(ConsensusMessage::Equivocation(proof)),
+        ]
+    }
+
+    /// Lets a faulty node tamper with the `outputs` of handling `seen`
+    #[cfg(feature = "byzantine")]
+    fn misbehave(&mut self, seen: Option<FullMessage>, outputs: Vec<Output>) -> Vec<Output> {
+        let seen = seen.filter(|message| {
+            let id = (message.body.public_key.into(), message.body.nonce);
+            self.verified.contains(&message.hash)
+                || self
+                    .delivered
+                    .get(&id)
+                    .is_some_and(|delivered| delivered.hash == message.hash)
+        });
+        match &mut self.byzantine {
+            Some(byzantine) => byzantine.tamper(&self.validators, seen, outputs),
+            None => outputs,
+        }
+    }
+
+    #[cfg(not(feature = "byzantine"))]
+    fn misbehave(&mut self, _seen: Option<FullMessage>, outputs: Vec<Output>) -> Vec<Output> {
+        outputs
+    }
+
+    /// Verifies a message the first time its hash is seen
+    fn check(&mut self, message: &FullMessage) -> bool {
+        if self.verified.contains(&message.hash) {
+            return true
+        }
+        match message.verify() {
+            Ok(()) => {
+                self.verified.insert(message.hash.clone());
+                true
+            }
+            Err(e) => {
+                warn!(target: "equity-consensus", "Dropping invalid message {}: {}", message.hash, e);
+                false
+            }
+        }
+    }
+}
+
+/// Where a message produced while handling another one goes
+#[derive(Debug)]
+enum Target {
+    All,
+    #[cfg_attr(not(feature = "probabilistic"), allow(dead_code))]
+    Peer(VerificationKeyBytes),
+}
+
+impl Instance {
+    /// Whether the votes for `hash` suffice for `step`
+    fn reached(&self, mode: &Mode, validators: &ValidatorSet, step: Step, hash: &str) -> bool {
+        match mode {
+            Mode::Quorum => {
+                let thresholds = Thresholds::new(validators);
+                match step {
+                    Step::Echo => weight(validators, &self.echoes, hash) >= thresholds.echo(),
+                    Step::Ready => weight(validators, &self.readies, hash) >= thresholds.ready(),
+                    Step::Deliver => {
+                        weight(validators, &self.readies, hash) >= thresholds.deliver()
+                    }
+                }
+            }
+            #[cfg(feature = "probabilistic")]
+            Mode::Sampled(sampler) => {
+                let (samples, parameters) = (&self.samples, &sampler.parameters);
+                match step {
+                    Step::Echo => {
+                        sampled::count(&samples.echo, &self.echoes, hash)
+                            >= parameters.echo_threshold
+                    }
+                    Step::Ready => {
+                        sampled::count(&samples.ready, &self.readies, hash)
+                            >= parameters.ready_threshold
+                    }
+                    Step::Deliver => {
+                        sampled::count(&samples.delivery, &self.readies, hash)
+                            >= parameters.delivery_threshold
+                    }
+                }
+            }
+        }
+    }
+
+    /// Our ECHO for `message`, gossiping the message first when sampling
+    fn echo(
+        &self,
+        mode: &Mode,
+        message: FullMessage,
+        vote: Vote,
+    ) -> Vec<(Target, ConsensusMessage)> {
+        let echo = ConsensusMessage::Echo(message, Box::new(vote));
+        match mode {
+            Mode::Quorum => vec![(Target::All, echo)],
+            #[cfg(feature = "probabilistic")]
+            Mode::Sampled(_) => {
+                let gossip = match &echo {
+                    ConsensusMessage::Echo(message, _) => ConsensusMessage::Gossip(message.clone()),
+                    _ => unreachable!(),
+                };
+                self.samples
+                    .gossip
+                    .keys()
+                    .map(|peer| (Target::Peer(*peer), gossip.clone()))
+                    .chain(
+                        self.samples
+                            .echo_subscribers
+                            .iter()
+                            .map(|peer| (Target::Peer(*peer), echo.clone())),
+                    )
+                    .collect()
+            }
+        }
+    }
+
+    /// Our READY for `message`
+    fn ready(
+        &self,
+        mode: &Mode,
+        message: FullMessage,
+        vote: Vote,
+    ) -> Vec<(Target, ConsensusMessage)> {
+        let ready = ConsensusMessage::Ready(message, Box::new(vote));
+        match mode {
+            Mode::Quorum => vec![(Target::All, ready)],
+            #[cfg(feature = "probabilistic")]
+            Mode::Sampled(_) => self
+                .samples
+                .ready_subscribers
+                .iter()
+                .map(|peer| (Target::Peer(*peer), ready.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "probabilistic")]
+impl Instance {
+    /// Subscriptions to the votes of the samples, sent when an instance is
+    /// first seen
+    fn subscribe(&self, account: (VerificationKey, u64)) -> Vec<(Target, ConsensusMessage)> {
+        let subscribe = |kind| {
+            ConsensusMessage::Subscribe(Subscription {
+                kind,
+                public_key: account.0,
+                nonce: account.1,
+            })
+        };
+        let readies: std::collections::BTreeSet<_> = self
+            .samples
+            .ready
+            .keys()
+            .chain(self.samples.delivery.keys())
+            .collect();
+        self.samples
+            .echo
+            .keys()
+            .map(|peer| (Target::Peer(*peer), subscribe(VoteKind::Echo)))
+            .chain(
+                readies
+                    .into_iter()
+                    .map(|peer| (Target::Peer(*peer), subscribe(VoteKind::Ready))),
+            )
+            .collect()
+    }
+
+    /// Records a subscription of `from` and sends it our vote if we already
+    /// have one
+    fn subscribed(
+        &mut self,
+        credentials: &Credentials,
+        validators: &ValidatorSet,
+        from: VerificationKeyBytes,
+        kind: VoteKind,
+    ) -> Vec<(Target, ConsensusMessage)> {
+        let (subscribers, voted) = match kind {
+            VoteKind::Echo => (&mut self.samples.echo_subscribers, &self.echoed),
+            VoteKind::Ready => (&mut self.samples.ready_subscribers, &self.readied),
+            VoteKind::Reconfiguration => return vec![],
+        };
+        subscribers.insert(from);
+        let message = match voted.as_ref().and_then(|hash| self.messages.get(hash)) {
+            Some(message) => message.clone(),
+            None => return vec![],
+        };
+        let vote = Box::new(Vote::sign(kind, validators, &message.hash, credentials));
+        let message = match kind {
+            VoteKind::Echo => ConsensusMessage::Echo(message, vote),
+            _ => ConsensusMessage::Ready(message, vote),
+        };
+        vec![(Target::Peer(from), message)]
+    }
+}
+
+#[cfg(feature = "probabilistic")]
+impl Delivered {
+    /// Whether we cast a vote of `kind` for the delivered message
+    fn voted(&self, kind: VoteKind) -> bool {
+        match kind {
+            VoteKind::Echo => self.echoed,
+            VoteKind::Ready => self.readied,
+            VoteKind::Reconfiguration => false,
+        }
+    }
+
+    /// Our vote of `kind` for the delivered `message`, if we cast one
+    fn vote(
+        &self,
+        credentials: &Credentials,
+        validators: &ValidatorSet,
+        message: FullMessage,
+        kind: VoteKind,
+    ) -> Option<ConsensusMessage> {
+        match kind {
+            VoteKind::Echo if self.echoed => {
+                let vote = Vote::sign(kind, validators, &message.hash, credentials);
+                Some(ConsensusMessage::Echo(message, Box::new(vote)))
+            }
+            VoteKind::Ready if self.readied => {
+                let vote = Vote::sign(kind, validators, &message.hash, credentials);
+                Some(ConsensusMessage::Ready(message, Box::new(vote)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Records the vote of `from` for `message` unless it already voted, returns a
+/// proof if that was for another message
+fn record_vote(
+    kind: Equivocation,
+    messages: &HashMap<String, FullMessage>,
+    votes: &mut HashMap<VerificationKeyBytes, (String, Vote)>,
+    from: VerificationKeyBytes,
+    message: &FullMessage,
+    vote: Vote,
+) -> Option<EquivocationProof> {
+    match votes.get(&from) {
+        Some((hash, _)) if *hash == message.hash => None,
+        Some((hash, first)) => Some(EquivocationProof::votes(
+            kind,
+            (messages[hash].clone(), *first),
+            (message.clone(), vote),
+        )),
+        None => {
+            votes.insert(from, (message.hash.clone(), vote));
+            None
+        }
+    }
+}
+
+/// The weight of the votes for `hash`
+fn weight(
+    validators: &ValidatorSet,
+    votes: &HashMap<VerificationKeyBytes, (String, Vote)>,
+    hash: &str,
+) -> u64 {
+    votes
+        .iter()
+        .filter(|(_, (voted, _))| voted == hash)
+        .map(|(voter, _)| validators.weight(*voter))
+        .sum()
+}
+
+/// Collects the votes of validators into a certificate, ordered by key so every
+/// node builds the same one from the same votes
+fn certificate(
+    hash: &str,
+    validators: &ValidatorSet,
+    votes: &HashMap<VerificationKeyBytes, (String, Vote)>,
+) -> QuorumCertificate {
+    let mut votes: Vec<Vote> = votes
+        .iter()
+        .filter(|(voter, (voted, _))| voted == hash && validators.contains(**voter))
+        .map(|(_, (_, vote))| *vote)
+        .collect();
+    votes.sort_unstable_by_key(|vote| vote.public_key.to_bytes());
+    QuorumCertificate {
+        hash: hash.to_owned(),
+        epoch: validators.epoch,
+        votes,
+    }
+}
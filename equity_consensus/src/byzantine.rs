@@ -0,0 +1,143 @@
+//! Faulty behaviors a node can be told to show on purpose, for testing that
+//! honest nodes stay safe around it. The consensus behaviors tamper with the
+//! outputs of `Bracha`, the P2P ones are left to the networking code.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+use equity_types::{ConsensusMessage, Credentials, FullMessage, ValidatorSet, Vote, VoteKind};
+use thiserror::Error;
+
+use crate::Output;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Behavior {
+    /// Never send anything, but keep receiving and delivering
+    Silent,
+    /// ECHO every message seen for an instance instead of only the first one
+    EquivocateEcho,
+    /// Hold back READYs until this many more messages were handled
+    DelayReady(u64),
+    /// Send an undecodable `InitMessage` before every handshake
+    MalformedInit,
+    /// Add an entry for a peer that does not exist to every `InitResponse`
+    ForgePeerMap,
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("Unknown byzantine behavior `{0}`")]
+pub struct ParseBehaviorError(String);
+
+impl FromStr for Behavior {
+    type Err = ParseBehaviorError;
+
+    /// Parses the names `Display` gives, `delay-ready:<messages>` for
+    /// `DelayReady`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "silent" => Ok(Self::Silent),
+            "equivocate-echo" => Ok(Self::EquivocateEcho),
+            "malformed-init" => Ok(Self::MalformedInit),
+            "forge-peer-map" => Ok(Self::ForgePeerMap),
+            _ => s
+                .strip_prefix("delay-ready:")
+                .and_then(|messages| messages.parse().ok())
+                .map(Self::DelayReady)
+                .ok_or_else(|| ParseBehaviorError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Behavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Silent => write!(f, "silent"),
+            Self::EquivocateEcho => write!(f, "equivocate-echo"),
+            Self::DelayReady(messages) => write!(f, "delay-ready:{}", messages),
+            Self::MalformedInit => write!(f, "malformed-init"),
+            Self::ForgePeerMap => write!(f, "forge-peer-map"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Byzantine {
+    credentials: Credentials,
+    behaviors: Vec<Behavior>,
+    /// Hashes of the messages we sent an ECHO for
+    echoed: HashSet<String>,
+    /// Held back READYs with the number of handled messages they are due at
+    delayed: VecDeque<(u64, Output)>,
+    handled: u64,
+}
+
+impl Byzantine {
+    pub fn new(credentials: Credentials, behaviors: Vec<Behavior>) -> Self {
+        Self {
+            credentials,
+            behaviors,
+            echoed: HashSet::new(),
+            delayed: VecDeque::new(),
+            handled: 0,
+        }
+    }
+
+    /// Tampers with the `outputs` of handling a message, `seen` is the
+    /// verified message that came with it
+    pub fn tamper(
+        &mut self,
+        validators: &ValidatorSet,
+        seen: Option<FullMessage>,
+        outputs: Vec<Output>,
+    ) -> Vec<Output> {
+        self.handled += 1;
+        let mut tampered = vec![];
+        while self
+            .delayed
+            .front()
+            .is_some_and(|(due, _)| *due <= self.handled)
+        {
+            tampered.extend(self.delayed.pop_front().map(|(_, output)| output));
+        }
+
+        let delay = self.behaviors.iter().find_map(|behavior| match behavior {
+            Behavior::DelayReady(messages) => Some(*messages),
+            _ => None,
+        });
+        for output in outputs {
+            match &output {
+                Output::Broadcast(ConsensusMessage::Echo(message, _))
+                | Output::Send(_, ConsensusMessage::Echo(message, _)) => {
+                    self.echoed.insert(message.hash.clone());
+                }
+                Output::Broadcast(ConsensusMessage::Ready(..))
+                | Output::Send(_, ConsensusMessage::Ready(..)) => {
+                    if let Some(messages) = delay {
+                        self.delayed.push_back((self.handled + messages, output));
+                        continue
+                    }
+                }
+                _ => (),
+            }
+            tampered.push(output);
+        }
+
+        if self.behaviors.contains(&Behavior::EquivocateEcho) {
+            if let Some(message) = seen.filter(|m| self.echoed.insert(m.hash.clone())) {
+                let vote = Vote::sign(VoteKind::Echo, validators, &message.hash, &self.credentials);
+                tampered.push(Output::Broadcast(ConsensusMessage::Echo(
+                    message,
+                    Box::new(vote),
+                )));
+            }
+        }
+
+        if self.behaviors.contains(&Behavior::Silent) {
+            tampered.retain(|output| !matches!(output, Output::Broadcast(_) | Output::Send(..)));
+        }
+        tampered
+    }
+}
@@ -1 +1,15 @@
+mod bracha;
+#[cfg(feature = "byzantine")]
+mod byzantine;
+#[cfg(feature = "probabilistic")]
+mod sampled;
+mod sequencer;
+mod state_machine;
 
+pub use bracha::*;
+#[cfg(feature = "byzantine")]
+pub use byzantine::{Behavior, ParseBehaviorError};
+#[cfg(feature = "probabilistic")]
+pub use sampled::{SampleError, SampleParameters};
+pub use sequencer::*;
+pub use state_machine::*;
@@ -0,0 +1,195 @@
+//! Probabilistic reliable broadcast in the style of Guerraoui et al.,
+//! "Scalable Byzantine Reliable Broadcast". Murmur gossips a message to a
+//! random sample of peers, Sieve collects ECHOs from an echo sample and
+//! Contagion spreads READYs through a ready and a delivery sample. Nodes only
+//! talk to their samples instead of to every validator, the guarantees of
+//! Bracha then hold with a probability set by the sample sizes.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use ed25519_consensus::VerificationKeyBytes;
+use equity_types::{ValidatorSet, Vote};
+use rand::{distributions::WeightedIndex, prelude::Distribution, rngs::StdRng, SeedableRng};
+
+/// Sample sizes and how many votes from a sample are needed for each step.
+/// Samples are drawn by weight with replacement, so a validator can fill
+/// several places of a sample and its vote counts for each of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleParameters {
+    /// Peers a message is gossiped to
+    pub gossip: usize,
+    /// Size of the echo sample
+    pub echo: usize,
+    /// ECHOs from the echo sample needed to send READY
+    pub echo_threshold: usize,
+    /// Size of the ready sample
+    pub ready: usize,
+    /// READYs from the ready sample needed to send READY
+    pub ready_threshold: usize,
+    /// Size of the delivery sample
+    pub delivery: usize,
+    /// READYs from the delivery sample needed to deliver
+    pub delivery_threshold: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SampleError {
+    #[error("The {step} threshold {threshold} does not fit a sample of {size}")]
+    OutOfRange {
+        step: &'static str,
+        threshold: usize,
+        size: usize,
+    },
+    #[error("The {step} threshold {threshold} is not more than half of a sample of {size}")]
+    NoMajority {
+        step: &'static str,
+        threshold: usize,
+        size: usize,
+    },
+    #[error("The ready threshold {ready} is above the delivery threshold {delivery}")]
+    ReadyAboveDelivery { ready: usize, delivery: usize },
+}
+
+impl SampleParameters {
+    /// Samples of `size` with the thresholds Bracha uses for `size` nodes,
+    /// more than two thirds for ECHO and delivery and more than a third for
+    /// READY
+    pub fn new(size: usize) -> Self {
+        Self {
+            gossip: size,
+            echo: size,
+            echo_threshold: 2 * size / 3 + 1,
+            ready: size,
+            ready_threshold: size / 3 + 1,
+            delivery: size,
+            delivery_threshold: 2 * size / 3 + 1,
+        }
+    }
+
+    /// Samples of `size` with other thresholds, which trade the chance that a
+    /// broadcast stalls for the chance that faulty samples break it
+    pub fn with_thresholds(
+        size: usize,
+        echo: usize,
+        ready: usize,
+        delivery: usize,
+    ) -> Result<Self, SampleError> {
+        let parameters = Self {
+            echo_threshold: echo,
+            ready_threshold: ready,
+            delivery_threshold: delivery,
+            ..Self::new(size)
+        };
+        parameters.check()?;
+        Ok(parameters)
+    }
+
+    /// Checks that every threshold can be reached within its sample, that
+    /// two messages of an instance can't both get the ECHOs or READYs of a
+    /// majority of the same sample, and that READYs can spread before nodes
+    /// deliver
+    pub fn check(&self) -> Result<(), SampleError> {
+        let steps = [
+            ("echo", self.echo_threshold, self.echo),
+            ("ready", self.ready_threshold, self.ready),
+            ("delivery", self.delivery_threshold, self.delivery),
+        ];
+        for (step, threshold, size) in steps {
+            if threshold == 0 || threshold > size {
+                return Err(SampleError::OutOfRange {
+                    step,
+                    threshold,
+                    size,
+                })
+            }
+        }
+        for (step, threshold, size) in [steps[0], steps[2]] {
+            if 2 * threshold <= size {
+                return Err(SampleError::NoMajority {
+                    step,
+                    threshold,
+                    size,
+                })
+            }
+        }
+        if self.ready_threshold > self.delivery_threshold {
+            return Err(SampleError::ReadyAboveDelivery {
+                ready: self.ready_threshold,
+                delivery: self.delivery_threshold,
+            })
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Sampler {
+    pub parameters: SampleParameters,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(parameters: SampleParameters, seed: u64) -> Self {
+        Self {
+            parameters,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Draws `size` validators by weight with replacement, with the number of
+    /// times each was drawn
+    pub fn sample(
+        &mut self,
+        validators: &ValidatorSet,
+        size: usize,
+    ) -> BTreeMap<VerificationKeyBytes, usize> {
+        let mut sample = BTreeMap::new();
+        let weights = validators.validators.iter().map(|v| v.weight);
+        let index = match WeightedIndex::new(weights) {
+            Ok(index) => index,
+            // no validator has any weight
+            Err(_) => return sample,
+        };
+        for _ in 0..size {
+            let validator = &validators.validators[index.sample(&mut self.rng)];
+            *sample.entry(validator.public_key.into()).or_default() += 1;
+        }
+        sample
+    }
+
+    /// Draws the samples of a new instance
+    pub fn samples(&mut self, validators: &ValidatorSet) -> Samples {
+        Samples {
+            gossip: self.sample(validators, self.parameters.gossip),
+            echo: self.sample(validators, self.parameters.echo),
+            ready: self.sample(validators, self.parameters.ready),
+            delivery: self.sample(validators, self.parameters.delivery),
+            ..Samples::default()
+        }
+    }
+}
+
+/// The samples of an instance and the peers that subscribed to our votes. They
+/// are ordered so that the same seed always produces the same outputs.
+#[derive(Debug, Default)]
+pub(crate) struct Samples {
+    pub gossip: BTreeMap<VerificationKeyBytes, usize>,
+    pub echo: BTreeMap<VerificationKeyBytes, usize>,
+    pub ready: BTreeMap<VerificationKeyBytes, usize>,
+    pub delivery: BTreeMap<VerificationKeyBytes, usize>,
+    pub echo_subscribers: BTreeSet<VerificationKeyBytes>,
+    pub ready_subscribers: BTreeSet<VerificationKeyBytes>,
+}
+
+/// How many places of `sample` voted for `hash`
+pub(crate) fn count(
+    sample: &BTreeMap<VerificationKeyBytes, usize>,
+    votes: &HashMap<VerificationKeyBytes, (String, Vote)>,
+    hash: &str,
+) -> usize {
+    votes
+        .iter()
+        .filter(|(_, (voted, _))| voted == hash)
+        .filter_map(|(voter, _)| sample.get(voter))
+        .sum()
+}
@@ -0,0 +1,225 @@
+//! Reliable broadcast delivers each account nonce at most once, but in no
+//! particular order. The `Sequencer` buffers delivered messages and releases
+//! them per account in consecutive nonce order, starting from nonce 1. A
+//! message that lists dependencies is also held back until every one of them
+//! was released, which lets accounts spend funds sent by other accounts.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    ops::Range,
+};
+
+use ed25519_consensus::VerificationKeyBytes;
+use equity_types::{FullMessage, WaitingTransaction};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SequenceError {
+    #[error("Nonce {nonce} was already used by this exact message")]
+    Duplicate { nonce: u64 },
+    #[error("Nonce {nonce} was already used by message {existing}")]
+    Conflict { nonce: u64, existing: String },
+    #[error("Nonces start at 1")]
+    ZeroNonce,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sequenced {
+    /// Messages that can be applied, in nonce order
+    Released(Vec<FullMessage>),
+    /// The message is held back until the nonces in the range are delivered
+    Buffered(Range<u64>),
+    /// The message is held back until the messages with these hashes are
+    /// released
+    Waiting(Vec<String>),
+}
+
+#[derive(Debug, Default)]
+struct Account {
+    /// The last released nonce, 0 before anything is released
+    last: u64,
+    /// Hashes of released messages by nonce
+    released: HashMap<u64, String>,
+    /// Delivered messages waiting on lower nonces
+    buffered: BTreeMap<u64, FullMessage>,
+}
+
+impl Account {
+    fn check(&self, message: &FullMessage) -> Result<(), SequenceError> {
+        let nonce = message.body.nonce;
+        let existing = if nonce <= self.last {
+            self.released.get(&nonce)
+        } else {
+            self.buffered.get(&nonce).map(|buffered| &buffered.hash)
+        };
+        match existing {
+            Some(existing) if *existing == message.hash => Err(SequenceError::Duplicate { nonce }),
+            Some(existing) => Err(SequenceError::Conflict {
+                nonce,
+                existing: existing.clone(),
+            }),
+            None if nonce == 0 => Err(SequenceError::ZeroNonce),
+            None => Ok(()),
+        }
+    }
+
+    /// The nonces missing before the lowest buffered one
+    fn gap(&self) -> Option<Range<u64>> {
+        self.buffered
+            .keys()
+            .next()
+            .map(|first| (self.last + 1)..*first)
+            .filter(|gap| !gap.is_empty())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Sequencer {
+    accounts: HashMap<VerificationKeyBytes, Account>,
+    /// Hashes of every released message
+    released: HashSet<String>,
+    /// Accounts whose next message waits on the message with the hash
+    dependents: HashMap<String, HashSet<VerificationKeyBytes>>,
+}
+
+impl Sequencer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks that `message` does not reuse a nonce of its account, without
+    /// recording anything
+    pub fn check(&self, message: &FullMessage) -> Result<(), SequenceError> {
+        match self
+            .accounts
+            .get(&VerificationKeyBytes::from(message.body.public_key))
+        {
+            Some(account) => account.check(message),
+            None => Account::default().check(message),
+        }
+    }
+
+    /// Records a delivered `message` and returns everything it allows to be
+    /// released
+    pub fn push(&mut self, message: FullMessage) -> Result<Sequenced, SequenceError> {
+        let public_key = VerificationKeyBytes::from(message.body.public_key);
+        let account = self.accounts.entry(public_key).or_default();
+        account.check(&message)?;
+        account.buffered.insert(message.body.nonce, message);
+
+        let released = self.release(public_key);
+        if !released.is_empty() {
+            return Ok(Sequenced::Released(released))
+        }
+        let account = &self.accounts[&public_key];
+        match account.gap() {
+            Some(gap) => Ok(Sequenced::Buffered(gap)),
+            None => Ok(Sequenced::Waiting(
+                account
+                    .buffered
+                    .values()
+                    .next()
+                    .map(|next| self.missing(next))
+                    .unwrap_or_default(),
+            )),
+        }
+    }
+
+    /// Releases what it can starting from the account of `public_key`,
+    /// following released messages to the accounts that waited on them
+    fn release(&mut self, public_key: VerificationKeyBytes) -> Vec<FullMessage> {
+        let mut released = vec![];
+        let mut accounts = VecDeque::from([public_key]);
+        while let Some(public_key) = accounts.pop_front() {
+            let account = match self.accounts.get_mut(&public_key) {
+                Some(account) => account,
+                None => continue,
+            };
+            while let Some(next) = account.buffered.get(&(account.last + 1)) {
+                let missing: Vec<_> = next
+                    .body
+                    .dependencies
+                    .iter()
+                    .filter(|hash| !self.released.contains(*hash))
+                    .collect();
+                if !missing.is_empty() {
+                    for hash in missing {
+                        self.dependents
+                            .entry(hash.clone())
+                            .or_default()
+                            .insert(public_key);
+                    }
+                    break
+                }
+
+                let message = account.buffered.remove(&(account.last + 1)).unwrap();
+                account.last += 1;
+                account.released.insert(account.last, message.hash.clone());
+                self.released.insert(message.hash.clone());
+                accounts.extend(self.dependents.remove(&message.hash).unwrap_or_default());
+                released.push(message);
+            }
+        }
+        released
+    }
+
+    /// The dependencies of `message` that were not released yet
+    fn missing(&self, message: &FullMessage) -> Vec<String> {
+        message
+            .body
+            .dependencies
+            .iter()
+            .filter(|hash| !self.released.contains(*hash))
+            .cloned()
+            .collect()
+    }
+
+    /// Records that the message with `hash` was released for `nonce` of the
+    /// account of `public_key` before a restart
+    pub fn restore(&mut self, public_key: VerificationKeyBytes, nonce: u64, hash: String) {
+        let account = self.accounts.entry(public_key).or_default();
+        account.last = account.last.max(nonce);
+        account.released.insert(nonce, hash.clone());
+        self.released.insert(hash);
+    }
+
+    /// The last released nonce of `public_key`
+    pub fn last_nonce(&self, public_key: &VerificationKeyBytes) -> u64 {
+        self.accounts
+            .get(public_key)
+            .map(|account| account.last)
+            .unwrap_or(0)
+    }
+
+    /// Every account with buffered messages and the nonces it is missing
+    pub fn gaps(&self) -> Vec<(VerificationKeyBytes, Range<u64>)> {
+        self.accounts
+            .iter()
+            .filter_map(|(public_key, account)| account.gap().map(|gap| (*public_key, gap)))
+            .collect()
+    }
+
+    /// Every buffered message with what it is waiting on, ordered by account
+    /// and nonce
+    pub fn waiting(&self) -> Vec<WaitingTransaction> {
+        let mut waiting = vec![];
+        for account in self.accounts.values() {
+            let mut missing_nonces = vec![];
+            let mut last = account.last;
+            for (nonce, message) in &account.buffered {
+                if *nonce > last + 1 {
+                    missing_nonces.push((last + 1)..*nonce);
+                }
+                last = *nonce;
+                waiting.push(WaitingTransaction {
+                    hash: message.hash.clone(),
+                    public_key: message.body.public_key,
+                    nonce: *nonce,
+                    missing_nonces: missing_nonces.clone(),
+                    missing_dependencies: self.missing(message),
+                });
+            }
+        }
+        waiting.sort_unstable_by_key(|waiting| (waiting.public_key.to_bytes(), waiting.nonce));
+        waiting
+    }
+}
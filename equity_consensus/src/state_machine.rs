@@ -0,0 +1,97 @@
+//! Applying delivered messages to the node's state. Consensus only decides
+//! which messages are delivered, a `StateMachine` decides what they mean.
+
+use ed25519_consensus::VerificationKey;
+use equity_storage::{Batch, DatabaseResult, EquityDatabase};
+use equity_types::{hash, FullMessage, Receipt};
+
+/// Applies delivered messages to the state in `EquityDatabase`. Messages of an
+/// account arrive in nonce order, but there is no order between accounts, so
+/// the result of `apply` must not depend on it for every node to reach the same
+/// state root.
+pub trait StateMachine: Send {
+    /// Applies `message` to the state in `db`. Its writes go to `batch`, which
+    /// is written together with the record of the message, so a crash never
+    /// leaves half of them behind.
+    fn apply(
+        &mut self,
+        db: &EquityDatabase,
+        batch: &mut Batch,
+        message: &FullMessage,
+    ) -> DatabaseResult<Receipt>;
+
+    /// The root after the last applied message
+    fn state_root(&self, db: &EquityDatabase) -> DatabaseResult<String>;
+}
+
+/// Writes `Body.keys_values` into a key space of the sending account, so that
+/// accounts never overwrite each other. Every account has a root chaining the
+/// hashes of its messages, and the state root is the hash of all account roots
+/// ordered by key.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyValueState;
+
+/// Account roots are stored as a list since public keys can't be JSON object
+/// keys
+type AccountRoots = Vec<(VerificationKey, String)>;
+
+impl KeyValueState {
+    /// The value an account has set for `key`
+    pub fn get(
+        db: &EquityDatabase,
+        account: VerificationKey,
+        key: u64,
+    ) -> DatabaseResult<Option<u64>> {
+        db.get(("state", account, key))
+    }
+
+    fn account_roots(db: &EquityDatabase) -> DatabaseResult<AccountRoots> {
+        Ok(db.get("account_roots")?.unwrap_or_default())
+    }
+
+    fn root(account_roots: &AccountRoots) -> DatabaseResult<String> {
+        Ok(hash(&serde_json::to_string(account_roots)?))
+    }
+}
+
+impl StateMachine for KeyValueState {
+    fn apply(
+        &mut self,
+        db: &EquityDatabase,
+        batch: &mut Batch,
+        message: &FullMessage,
+    ) -> DatabaseResult<Receipt> {
+        let account = message.body.public_key;
+        for (key, value) in &message.body.keys_values {
+            batch.set(("state", account, key), *value)?;
+        }
+
+        let mut account_roots = Self::account_roots(db)?;
+        let position = account_roots
+            .binary_search_by_key(&account.to_bytes(), |(public_key, _)| public_key.to_bytes());
+        let account_root = match position {
+            Ok(i) => {
+                let root = hash(&format!("{}{}", account_roots[i].1, message.hash));
+                account_roots[i].1 = root.clone();
+                root
+            }
+            Err(i) => {
+                let root = hash(&message.hash);
+                account_roots.insert(i, (account, root.clone()));
+                root
+            }
+        };
+        let state_root = Self::root(&account_roots)?;
+        batch.set("account_roots", account_roots)?;
+
+        Ok(Receipt {
+            hash: message.hash.clone(),
+            account_root,
+            state_root,
+        })
+    }
+
+    fn state_root(&self, db: &EquityDatabase) -> DatabaseResult<String> {
+        Self::root(&Self::account_roots(db)?)
+    }
+}
@@ -1,10 +1,28 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{
+    collections::BTreeSet,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use clap::Parser;
+use ed25519_consensus::{VerificationKey, VerificationKeyBytes};
+#[cfg(feature = "byzantine")]
+use equity_consensus::Behavior;
+use equity_consensus::Bracha;
+#[cfg(feature = "probabilistic")]
+use equity_consensus::SampleParameters;
 use equity_core::{EquityService, Error};
-use equity_storage::EquityDatabase;
-use equity_types::Value;
-use tracing::info;
+#[cfg(feature = "byzantine")]
+use equity_p2p::Faults;
+use equity_p2p::{
+    Allowlist, Limits, NetworkConfig, Overflow, Protocol, Scoring, Transport, DEFAULT_CHAIN_ID,
+};
+use equity_storage::{DatabaseType, EquityDatabase};
+use equity_types::{Credentials, EquityError, ValidatorSet, Value};
+use tracing::{info, warn};
 
 #[derive(Parser)]
 #[clap(name = "equity_core", about = "Equity", version)]
@@ -13,8 +31,89 @@ struct CliArgs {
     api_listener: String,
     #[clap(name = "p2p_listener", default_value = "127.0.0.1:5050")]
     p2p_listener: String,
-    #[clap(name = "seed", default_value = "0.0.0.0:0000")]
-    seed: String,
+    /// Nodes to join the network through as hostnames or IPs with a port,
+    /// none for the first node. An unspecified address like `0.0.0.0:0` is
+    /// ignored.
+    #[clap(name = "seed")]
+    seeds: Vec<String>,
+    /// The hostname or IP and port peers should dial this node at, the
+    /// `p2p_listener` if not given. Needed when listening on `0.0.0.0`.
+    #[clap(long)]
+    advertise_address: Option<String>,
+    /// JSON file with the node's `Credentials`, created if it does not exist.
+    /// Without one the node gets a new identity every start.
+    #[clap(long)]
+    credentials: Option<PathBuf>,
+    /// JSON file with the `ValidatorSet` of the genesis epoch. Without one
+    /// this node is the only validator.
+    #[clap(long)]
+    genesis: Option<PathBuf>,
+    /// File to keep transactions and state in across restarts, created if it
+    /// does not exist. Without one everything is lost when the node stops.
+    #[clap(long)]
+    database: Option<PathBuf>,
+    /// How to protect peer connections, `noise` or `plaintext`. Every node of
+    /// the network has to use the same one, plaintext is only meant for local
+    /// debugging.
+    #[clap(long, default_value = "noise")]
+    transport: Transport,
+    /// Only let the validators of the genesis epoch and the keys of
+    /// `--allowlist` connect
+    #[clap(long)]
+    permissioned: bool,
+    /// JSON file with more public keys that may connect, implies
+    /// `--permissioned`
+    #[clap(long)]
+    allowlist: Option<PathBuf>,
+    /// Let keys outside of the allowlist dial this node as read-only
+    /// observers
+    #[clap(long)]
+    observers: bool,
+    /// Messages a peer may send per second, the rest is dropped
+    #[clap(long, default_value = "500")]
+    peer_rate: u32,
+    /// Bytes a peer may send per second, messages over it are dropped
+    #[clap(long, default_value = "4194304")]
+    peer_bytes: u32,
+    /// Frames that may wait to be written to a peer
+    #[clap(long, default_value = "1000")]
+    peer_queue: usize,
+    /// What happens when the queue of a peer is full, `drop-oldest` or
+    /// `disconnect`
+    #[clap(long, default_value = "drop-oldest")]
+    queue_overflow: Overflow,
+    /// Seconds a peer stays banned once its score drops too low
+    #[clap(long, default_value = "3600")]
+    ban_seconds: u64,
+    /// Only peers of the same chain connect
+    #[clap(long, default_value = DEFAULT_CHAIN_ID)]
+    chain_id: String,
+    /// Use the probabilistic broadcast with samples of this size, every node
+    /// of the network has to use the same size and thresholds
+    #[cfg(feature = "probabilistic")]
+    #[clap(long)]
+    sample_size: Option<usize>,
+    /// ECHOs from the echo sample needed to send READY, more than two thirds
+    /// of the sample by default
+    #[cfg(feature = "probabilistic")]
+    #[clap(long, requires = "sample-size")]
+    echo_threshold: Option<usize>,
+    /// READYs from the ready sample needed to send READY, more than a third
+    /// of the sample by default
+    #[cfg(feature = "probabilistic")]
+    #[clap(long, requires = "sample-size")]
+    ready_threshold: Option<usize>,
+    /// READYs from the delivery sample needed to deliver, more than two
+    /// thirds of the sample by default
+    #[cfg(feature = "probabilistic")]
+    #[clap(long, requires = "sample-size")]
+    delivery_threshold: Option<usize>,
+    /// Misbehave on purpose, can be given several times. One of `silent`,
+    /// `equivocate-echo`, `delay-ready:<messages>`, `malformed-init` or
+    /// `forge-peer-map`.
+    #[cfg(feature = "byzantine")]
+    #[clap(long)]
+    byzantine: Vec<Behavior>,
 }
 
 #[tokio::main]
@@ -22,16 +121,143 @@ async fn main() -> Result<(), Error> {
     let args = CliArgs::parse();
     let api_listener = SocketAddr::from_str(&args.api_listener)?;
     let p2p_listener = SocketAddr::from_str(&args.p2p_listener)?;
-    let seed_address = SocketAddr::from_str(&args.seed)?;
+    let seeds: Vec<String> = args
+        .seeds
+        .iter()
+        .filter(|seed| !SocketAddr::from_str(seed).is_ok_and(|seed| seed.ip().is_unspecified()))
+        .cloned()
+        .collect();
 
     initialize_logger();
     info!(target: "equity-core", "Initializing equity-core");
 
-    // todo: read from config and initialize correct db
-    let db = EquityDatabase::in_memory();
+    let database = match &args.database {
+        Some(path) => DatabaseType::File(path.clone()),
+        None => DatabaseType::InMemory,
+    };
+    let db = EquityDatabase::open(&database)?;
     genesis_data(&db);
 
-    let service = EquityService::new(api_listener, p2p_listener, seed_address, db).await?;
+    let credentials = match &args.credentials {
+        Some(path) => load_credentials(path)?,
+        None => Credentials::new(),
+    };
+    let genesis: ValidatorSet = match &args.genesis {
+        Some(path) => serde_json::from_slice(&fs::read(path)?)?,
+        None => ValidatorSet {
+            chain_id: args.chain_id.clone(),
+            ..ValidatorSet::equal_weight(0, [credentials.public_key])
+        },
+    };
+    // reconfigurations of an earlier run outlive the genesis file
+    let genesis = match db.get::<_, u64>("epoch")? {
+        Some(epoch) => {
+            info!(target: "equity-core", "Resuming at epoch {}", epoch);
+            db.get(("validators", epoch))?.unwrap_or(genesis)
+        }
+        None => genesis,
+    };
+    // votes are signed for the chain of the validators
+    if genesis.chain_id != args.chain_id {
+        return Err(EquityError::ChainMismatch {
+            expected: args.chain_id.clone(),
+            found: genesis.chain_id,
+        }
+        .into())
+    }
+
+    let allowlist = if args.permissioned || args.allowlist.is_some() {
+        let mut keys: BTreeSet<_> = genesis
+            .validators
+            .iter()
+            .map(|validator| VerificationKeyBytes::from(validator.public_key))
+            .collect();
+        if let Some(path) = &args.allowlist {
+            let listed: Vec<VerificationKey> = serde_json::from_slice(&fs::read(path)?)?;
+            keys.extend(listed.into_iter().map(VerificationKeyBytes::from));
+        }
+        info!(target: "equity-core", "Permissioned with {} allowlisted keys", keys.len());
+        Some(Allowlist {
+            keys,
+            observers: args.observers,
+        })
+    } else {
+        None
+    };
+
+    // nodes of different broadcasts can't agree with each other
+    let broadcast = "quorum-broadcast".to_owned();
+    let consensus = Bracha::new(credentials.clone(), genesis);
+    #[cfg(feature = "probabilistic")]
+    let (consensus, broadcast) = match args.sample_size {
+        Some(size) => {
+            let defaults = SampleParameters::new(size);
+            let parameters = SampleParameters::with_thresholds(
+                size,
+                args.echo_threshold.unwrap_or(defaults.echo_threshold),
+                args.ready_threshold.unwrap_or(defaults.ready_threshold),
+                args.delivery_threshold
+                    .unwrap_or(defaults.delivery_threshold),
+            )?;
+            let broadcast = format!(
+                "sampled-broadcast/{}/{}/{}/{}",
+                size,
+                parameters.echo_threshold,
+                parameters.ready_threshold,
+                parameters.delivery_threshold
+            );
+            (
+                consensus.with_sampling(parameters, rand::random()),
+                broadcast,
+            )
+        }
+        None => (consensus, broadcast),
+    };
+    #[cfg(feature = "byzantine")]
+    let consensus = if args.byzantine.is_empty() {
+        consensus
+    } else {
+        warn!(target: "equity-core", "Running with byzantine behaviors {:?}", args.byzantine);
+        consensus.with_byzantine(args.byzantine.clone())
+    };
+
+    if args.transport == Transport::Plaintext {
+        warn!(target: "equity-core", "Peer connections are not encrypted");
+    }
+    let network = NetworkConfig {
+        advertise: args.advertise_address.clone(),
+        allowlist,
+        limits: Limits {
+            rate: args.peer_rate,
+            bytes: args.peer_bytes,
+            queue: args.peer_queue,
+            overflow: args.queue_overflow,
+            ..Limits::default()
+        },
+        scoring: Scoring {
+            ban: Duration::from_secs(args.ban_seconds),
+            ..Scoring::default()
+        },
+        transport: args.transport,
+        protocol: Protocol {
+            chain_id: args.chain_id.clone(),
+            capabilities: BTreeSet::from([broadcast.clone()]),
+            required: BTreeSet::from([broadcast]),
+            ..Protocol::default()
+        },
+        seeds,
+        ..NetworkConfig::new(p2p_listener, vec![])
+    };
+    #[cfg(feature = "byzantine")]
+    let network = NetworkConfig {
+        faults: Faults {
+            malformed_init: args.byzantine.contains(&Behavior::MalformedInit),
+            forge_peer_map: args.byzantine.contains(&Behavior::ForgePeerMap),
+        },
+        ..network
+    };
+
+    let service = EquityService::new(api_listener, network, db, credentials, consensus).await?;
 
     service.run().await;
 
@@ -48,6 +274,19 @@ fn initialize_logger() {
         .init();
 }
 
+fn load_credentials(path: &Path) -> Result<Credentials, Error> {
+    if path.exists() {
+        return Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+    let credentials = Credentials::new();
+    fs::write(path, serde_json::to_vec(&credentials)?)?;
+    info!(
+        target: "equity-core",
+        "Wrote new credentials to {}", path.display()
+    );
+    Ok(credentials)
+}
+
 fn genesis_data(db: &EquityDatabase) {
     let _ = db.set("testkey", Value(1337));
 }
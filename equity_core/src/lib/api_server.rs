@@ -1,22 +1,31 @@
 use std::{
+    collections::BTreeMap,
     net::{SocketAddr, TcpListener},
     sync::Arc,
+    time::Duration,
 };
 
 use axum::{extract::Path, routing, Extension, Json, Router};
 use ed25519_consensus::VerificationKey;
+use equity_p2p::Network;
 use equity_storage::EquityDatabase;
 use equity_types::{
-    Credentials, EquityAddressResponse, EquityError, FullMessage, HealthResponse, PeerMap,
-    PostTransactionResponse,
+    Credentials, DedupStats, EquityAddressResponse, EquityError, EquivocationProof, FullMessage,
+    HealthResponse, PeerReputation, PeerStatus, PostTransactionResponse, Reconfiguration, TxRecord,
+    ValidatorSet, WaitingTransaction,
 };
 use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha512};
-use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::{
+    task::{spawn_blocking, JoinHandle},
+    time::timeout,
+};
 use tracing::info;
 
-use crate::{borsh::Borsh, Error};
+use crate::{borsh::Borsh, ConsensusHandle, Error, Submission};
+
+/// How long the transaction API waits for a transaction to be delivered
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Peer {
@@ -27,19 +36,31 @@ pub struct Peer {
 pub async fn start_api_server(
     listener: SocketAddr,
     db: EquityDatabase,
-    _peers: PeerMap,
+    network: Network,
     _credentials: Arc<Credentials>,
+    consensus: ConsensusHandle,
 ) -> Result<(SocketAddr, JoinHandle<Result<(), EquityError>>), Error> {
     let router = Router::new()
         .route("/health", routing::get(health))
         .route("/address/:key", routing::get(get_address).post(set_address))
         .route(
             "/transaction/:id",
-            routing::get(transaction).post(transaction),
+            routing::get(get_transaction).post(transaction),
         )
-        .layer(Extension(db));
+        .route("/validators", routing::get(get_validators))
+        .route("/validators/:epoch", routing::get(get_epoch_validators))
+        .route("/reconfigure", routing::post(reconfigure))
+        .route("/equivocations", routing::get(get_equivocations))
+        .route("/waiting", routing::get(get_waiting))
+        .route("/peers", routing::get(get_peers))
+        .route("/peers/rejected", routing::get(get_rejected_peers))
+        .route("/peers/reputation", routing::get(get_reputations))
+        .route("/peers/dedup", routing::get(get_dedup_stats))
+        .layer(Extension(db))
+        .layer(Extension(network))
+        .layer(Extension(consensus));
 
-    let listener = TcpListener::bind(&listener)?;
+    let listener = TcpListener::bind(listener)?;
     let bound_addr = listener.local_addr().unwrap();
 
     let (tx, rx) = tokio::sync::oneshot::channel();
@@ -69,16 +90,19 @@ async fn health() -> Borsh<HealthResponse> {
 async fn transaction(
     Json(payload): Json<FullMessage>,
     Extension(state): Extension<EquityDatabase>,
+    Extension(consensus): Extension<ConsensusHandle>,
 ) -> Result<Json<PostTransactionResponse>, StatusCode> {
     info!(target = "equity-core", "Transaction API");
 
     // Check database if Mapping [hash -> tx_record] exists
     // If value exists revert transaction
 
-    if let Ok(Some(_value)) = state.get::<FullMessage>(payload.hash.as_bytes()) {
+    if let Ok(Some(record)) = state.get::<_, TxRecord>(&payload.hash) {
         return Ok(Json(PostTransactionResponse {
             success: false,
             msg: "Revert: TX already exists".to_string(),
+            certificate: Some(record.certificate),
+            receipt: Some(record.receipt),
         }))
     };
 
@@ -87,41 +111,191 @@ async fn transaction(
 
     let payload_verify = payload.clone();
 
-    if let Ok(Err(e)) = spawn_blocking(move || verify_body(payload_verify)).await {
+    if let Ok(Err(e)) = spawn_blocking(move || payload_verify.verify()).await {
         return Ok(Json(PostTransactionResponse {
             success: false,
             msg: e.to_string(),
+            certificate: None,
+            receipt: None,
         }))
     }
 
-    // Post transaction record to db
-    let payload_entry = payload.clone();
-    // let payload_hash = payload.hash;
+    // Reliably broadcast the transaction, it is recorded to the db once delivered
 
-    if let Ok(None) = state.set(&payload.hash, payload_entry) {
-        return Ok(Json(PostTransactionResponse {
+    let submission = match consensus.submit(payload).await {
+        Ok(submission) => submission,
+        Err(e) => {
+            return Ok(Json(PostTransactionResponse {
+                success: false,
+                msg: e.to_string(),
+                certificate: None,
+                receipt: None,
+            }))
+        }
+    };
+
+    let response = match timeout(DELIVERY_TIMEOUT, submission).await {
+        Ok(Ok(Submission::Delivered(record))) => PostTransactionResponse {
             success: true,
-            msg: "Transaction entry recorded to db".to_string(),
-        }))
+            msg: "Transaction delivered and applied".to_string(),
+            certificate: Some(record.certificate),
+            receipt: Some(record.receipt),
+        },
+        Ok(Ok(Submission::Buffered(gap))) => PostTransactionResponse {
+            success: false,
+            msg: format!(
+                "Transaction delivered but buffered until nonces {}..{} are delivered",
+                gap.start, gap.end
+            ),
+            certificate: None,
+            receipt: None,
+        },
+        Ok(Ok(Submission::Waiting(dependencies))) => PostTransactionResponse {
+            success: false,
+            msg: format!(
+                "Transaction delivered but waiting until {} are applied",
+                dependencies.join(", ")
+            ),
+            certificate: None,
+            receipt: None,
+        },
+        Ok(Ok(Submission::Failed(e))) => PostTransactionResponse {
+            success: false,
+            msg: format!("Transaction delivered but not applied: {}", e),
+            certificate: None,
+            receipt: None,
+        },
+        Ok(Ok(Submission::Rejected(e))) => PostTransactionResponse {
+            success: false,
+            msg: format!("Revert: {}", e),
+            certificate: None,
+            receipt: None,
+        },
+        _ => PostTransactionResponse {
+            success: false,
+            msg: "Transaction not delivered in time".to_string(),
+            certificate: None,
+            receipt: None,
+        },
     };
 
-    Ok(Json(PostTransactionResponse {
-        success: false,
-        msg: "Transaction not recorded to db".to_string(),
-    }))
+    Ok(Json(response))
 }
 
-fn verify_body(payload: FullMessage) -> Result<(), ed25519_consensus::Error> {
-    let mut digest: Sha512 = Sha512::new();
+async fn get_transaction(
+    Path(hash): Path<String>,
+    Extension(state): Extension<EquityDatabase>,
+) -> Result<Json<TxRecord>, StatusCode> {
+    info!(
+        target = "equity-core",
+        "Get Transaction API: hash is: `{}`", hash
+    );
+
+    match state.get(&hash) {
+        Ok(Some(record)) => Ok(Json(record)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            info!("error: {}", e);
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
 
-    digest.update(serde_json::to_string(&payload.body).unwrap());
+async fn get_validators(
+    Extension(state): Extension<EquityDatabase>,
+) -> Result<Json<ValidatorSet>, StatusCode> {
+    info!(target = "equity-core", "Get Validators API");
+
+    match state.get::<_, u64>("epoch") {
+        Ok(Some(epoch)) => get_epoch_validators(Path(epoch), Extension(state)).await,
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn get_epoch_validators(
+    Path(epoch): Path<u64>,
+    Extension(state): Extension<EquityDatabase>,
+) -> Result<Json<ValidatorSet>, StatusCode> {
+    info!(
+        target = "equity-core",
+        "Get Validators API: epoch is: `{}`", epoch
+    );
+
+    match state.get(("validators", epoch)) {
+        Ok(Some(validators)) => Ok(Json(validators)),
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn reconfigure(
+    Json(reconfiguration): Json<Reconfiguration>,
+    Extension(consensus): Extension<ConsensusHandle>,
+) -> Result<Json<ValidatorSet>, (StatusCode, String)> {
+    info!(
+        target = "equity-core",
+        "Reconfigure API: epoch is: `{}`", reconfiguration.validators.epoch
+    );
+
+    consensus
+        .reconfigure(reconfiguration)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+async fn get_equivocations(
+    Extension(state): Extension<EquityDatabase>,
+) -> Result<Json<Vec<EquivocationProof>>, StatusCode> {
+    info!(target = "equity-core", "Get Equivocations API");
+
+    let ids: Vec<String> = match state.get("equivocations") {
+        Ok(ids) => ids.unwrap_or_default(),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let mut proofs = vec![];
+    for id in ids {
+        match state.get(("equivocation", &id)) {
+            Ok(Some(proof)) => proofs.push(proof),
+            _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+    Ok(Json(proofs))
+}
+
+async fn get_waiting(
+    Extension(consensus): Extension<ConsensusHandle>,
+) -> Result<Json<Vec<WaitingTransaction>>, StatusCode> {
+    info!(target = "equity-core", "Get Waiting Transactions API");
+
+    consensus
+        .waiting()
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn get_peers(Extension(network): Extension<Network>) -> Json<BTreeMap<String, PeerStatus>> {
+    info!(target = "equity-core", "Get Peers API");
+
+    Json(network.status())
+}
+
+async fn get_rejected_peers(Extension(network): Extension<Network>) -> Json<u64> {
+    info!(target = "equity-core", "Get Rejected Peers API");
+
+    Json(network.rejected())
+}
+
+async fn get_reputations(Extension(network): Extension<Network>) -> Json<Vec<PeerReputation>> {
+    info!(target = "equity-core", "Get Reputations API");
+
+    Json(network.reputations())
+}
 
-    let digest_string: String = format!("{:X}", digest.clone().finalize());
+async fn get_dedup_stats(Extension(network): Extension<Network>) -> Json<DedupStats> {
+    info!(target = "equity-core", "Get Dedup Stats API");
 
-    payload
-        .body
-        .public_key
-        .verify(&payload.signature, digest_string.as_bytes())
+    Json(network.dedup_stats())
 }
 
 // TODO should we use some binary instead of a path?
@@ -135,7 +309,7 @@ async fn get_address(
         "Get Address API: address is: `{}`", key
     );
 
-    match state.get(key.as_bytes()) {
+    match state.get(&key) {
         Ok(Some(value)) => {
             let response = Borsh(EquityAddressResponse { owner: key, value });
             Ok(response)
@@ -160,7 +334,7 @@ async fn set_address(
         "Get Address API: address is: `{}`", key
     );
 
-    match state.get(key.as_bytes()) {
+    match state.get(&key) {
         Ok(Some(value)) => {
             let response = Borsh(EquityAddressResponse { owner: key, value });
             Ok(response)
@@ -0,0 +1,621 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    time::Duration,
+};
+
+use ed25519_consensus::VerificationKey;
+use equity_consensus::{
+    Bracha, InstanceId, Output, SequenceError, Sequenced, Sequencer, StateMachine,
+};
+use equity_p2p::{Network, P2pMessage};
+use equity_storage::{Batch, DatabaseResult, EquityDatabase};
+use equity_types::{
+    ConsensusMessage, EquityError, Equivocation, EquivocationProof, FullMessage, Offense,
+    QuorumCertificate, Reconfiguration, TxRecord, ValidatorSet, VoteKind, WaitingTransaction,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{interval_at, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::Error;
+
+#[derive(Debug)]
+pub enum ConsensusInput {
+    /// A transaction from the API, the sender is notified once it is delivered
+    Submit(FullMessage, oneshot::Sender<Submission>),
+    /// A message from the peer with the given key
+    Message(VerificationKey, ConsensusMessage),
+    /// A reconfiguration from the API, the sender gets the new validators
+    Reconfigure(
+        Reconfiguration,
+        oneshot::Sender<Result<ValidatorSet, EquityError>>,
+    ),
+    /// A query for the delivered transactions that are held back
+    Waiting(oneshot::Sender<Vec<WaitingTransaction>>),
+}
+
+/// What happened to a submitted transaction
+#[derive(Debug)]
+pub enum Submission {
+    /// Delivered and applied in nonce order
+    Delivered(Box<TxRecord>),
+    /// Delivered, but held back until the nonces in the range are delivered
+    Buffered(Range<u64>),
+    /// Delivered, but held back until the transactions with these hashes are
+    /// applied
+    Waiting(Vec<String>),
+    Rejected(SequenceError),
+    /// Delivered, but the state machine could not apply it
+    Failed(String),
+}
+
+/// What consensus has to remember across restarts besides the records of
+/// applied transactions and our votes, stored under `pending`
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Pending {
+    /// Delivered transactions the sequencer holds back, with their
+    /// certificates, by hash
+    delivered: HashMap<String, (FullMessage, QuorumCertificate)>,
+}
+
+/// Our ECHOs and READYs, each stored under `("vote", hash, kind)` before it is
+/// sent so that we never vote for another message of an instance after a
+/// restart. The storage can't list its keys, so `("voted", n)` lists the hash
+/// and kind of every vote, from `"first_vote"` up to `"votes"`.
+#[derive(Debug, Default)]
+struct Votes {
+    /// The hash we voted for by instance and kind, until the instance delivers
+    recorded: HashMap<(InstanceId, VoteKind), String>,
+    /// Where the next vote is listed
+    next: u64,
+}
+
+impl Pending {
+    fn save(&self, db: &EquityDatabase) -> DatabaseResult<()> {
+        let mut batch = Batch::default();
+        batch.set("pending", self)?;
+        db.write(batch)
+    }
+}
+
+/// How long a broadcast that did not deliver may go without a message before
+/// it is expired, it is expired within twice that
+const INSTANCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Handle to the task running the broadcast engine
+#[derive(Debug, Clone)]
+pub struct ConsensusHandle {
+    send: mpsc::Sender<ConsensusInput>,
+}
+
+impl ConsensusHandle {
+    /// Starts reliably broadcasting `message`. The returned receiver resolves
+    /// when it is delivered locally or rejected.
+    pub async fn submit(
+        &self,
+        message: FullMessage,
+    ) -> Result<oneshot::Receiver<Submission>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send
+            .send(ConsensusInput::Submit(message, tx))
+            .await
+            .map_err(|_| Error::ConsensusClosed)?;
+        Ok(rx)
+    }
+
+    /// Moves to the epoch of `reconfiguration` if it is valid
+    pub async fn reconfigure(
+        &self,
+        reconfiguration: Reconfiguration,
+    ) -> Result<ValidatorSet, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send
+            .send(ConsensusInput::Reconfigure(reconfiguration, tx))
+            .await
+            .map_err(|_| Error::ConsensusClosed)?;
+        Ok(rx.await.map_err(|_| Error::ConsensusClosed)??)
+    }
+
+    /// The delivered transactions that are held back and what they wait on
+    pub async fn waiting(&self) -> Result<Vec<WaitingTransaction>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send
+            .send(ConsensusInput::Waiting(tx))
+            .await
+            .map_err(|_| Error::ConsensusClosed)?;
+        rx.await.map_err(|_| Error::ConsensusClosed)
+    }
+
+    pub async fn message(
+        &self,
+        from: VerificationKey,
+        message: ConsensusMessage,
+    ) -> Result<(), Error> {
+        self.send
+            .send(ConsensusInput::Message(from, message))
+            .await
+            .map_err(|_| Error::ConsensusClosed)
+    }
+}
+
+pub fn start_consensus_server<S: StateMachine + 'static>(
+    db: EquityDatabase,
+    network: Network,
+    mut bracha: Bracha,
+    mut state: S,
+) -> (ConsensusHandle, JoinHandle<Result<(), EquityError>>) {
+    let (send, mut recv) = mpsc::channel(1000);
+
+    info!(target: "equity-core", "Starting Consensus Server");
+
+    let handle = tokio::spawn(async move {
+        record_validators(&db, bracha.validators());
+        // the allowlist follows the validators from one epoch to the next
+        let mut validators = bracha.validators().clone();
+        let mut sequencer = Sequencer::new();
+        let (mut pending, mut votes, mut outputs) = restore(&db, &mut bracha, &mut sequencer)?;
+        let mut waiting: HashMap<String, Vec<oneshot::Sender<Submission>>> = HashMap::new();
+        // how many messages were waiting after the last sweep of `waiting`
+        let mut swept = 0;
+        let mut expiry = interval_at(Instant::now() + INSTANCE_TIMEOUT, INSTANCE_TIMEOUT);
+
+        loop {
+            // what handling late messages needs done
+            let mut late = vec![];
+            for output in outputs.drain(..) {
+                match output {
+                    // every vote bracha outputs is ours
+                    Output::Broadcast(message) => {
+                        if record_vote(&db, &mut votes, &message) {
+                            network.broadcast(&P2pMessage::Consensus(message)).await
+                        }
+                    }
+                    Output::Send(to, message) => {
+                        if !network.is_reachable(to) {
+                            debug!(target: "equity-consensus", "Validator {:?} is unreachable", to);
+                        }
+                        if record_vote(&db, &mut votes, &message) {
+                            network.send_to(to, &P2pMessage::Consensus(message)).await
+                        }
+                    }
+                    Output::Reconfigured(next) => {
+                        if let Some(mut allowlist) = network.allowlist() {
+                            allowlist.reconfigure(&validators, &next);
+                            network.set_allowlist(Some(allowlist));
+                        }
+                        record_validators(&db, &next);
+                        validators = next;
+                    }
+                    Output::Equivocation(proof) => {
+                        // a sender equivocating is not the fault of a peer
+                        if proof.kind != Equivocation::Sender {
+                            network.report(proof.offender().into(), Offense::ConflictingVote);
+                        }
+                        record_equivocation(&db, *proof)
+                    }
+                    Output::Invalid(from) => network.report(from, Offense::InvalidSignature),
+                    Output::Late(from, hash, message) => {
+                        if let Some(delivered) = delivered(&db, &pending, &hash) {
+                            late.extend(bracha.late(from, delivered, message));
+                        }
+                    }
+                    Output::Deliver(message, certificate) => {
+                        let hash = message.hash.clone();
+                        // the votes of a delivered instance are no longer needed
+                        let id = (message.body.public_key.into(), message.body.nonce);
+                        for kind in [VoteKind::Echo, VoteKind::Ready] {
+                            votes.recorded.remove(&(id, kind));
+                        }
+                        pending
+                            .delivered
+                            .insert(hash.clone(), (message.clone(), certificate));
+                        match sequencer.push(message) {
+                            Ok(Sequenced::Released(released)) => {
+                                for message in released {
+                                    deliver(&db, &mut state, &mut waiting, &mut pending, message);
+                                }
+                            }
+                            Ok(Sequenced::Buffered(gap)) => {
+                                info!(
+                                    target: "equity-core",
+                                    "Buffered transaction {} until nonces {:?} are delivered",
+                                    hash, gap
+                                );
+                                save_pending(&db, &pending);
+                                notify(&mut waiting, &hash, || Submission::Buffered(gap.clone()));
+                            }
+                            Ok(Sequenced::Waiting(dependencies)) => {
+                                info!(
+                                    target: "equity-core",
+                                    "Holding transaction {} until {:?} are applied",
+                                    hash, dependencies
+                                );
+                                save_pending(&db, &pending);
+                                notify(&mut waiting, &hash, || {
+                                    Submission::Waiting(dependencies.clone())
+                                });
+                            }
+                            Err(e) => {
+                                pending.delivered.remove(&hash);
+                                save_pending(&db, &pending);
+                                warn!(target: "equity-core", "Rejected transaction {}: {}", hash, e);
+                                notify(&mut waiting, &hash, || Submission::Rejected(e.clone()));
+                            }
+                        }
+                    }
+                }
+            }
+
+            if !late.is_empty() {
+                outputs = late;
+                continue
+            }
+
+            let input = tokio::select! {
+                input = recv.recv() => match input {
+                    Some(input) => input,
+                    None => break,
+                },
+                _ = expiry.tick() => {
+                    let expired = bracha.expire();
+                    if !expired.is_empty() {
+                        debug!(
+                            target: "equity-consensus",
+                            "Expired {} idle broadcasts", expired.len()
+                        );
+                    }
+                    for id in expired {
+                        for kind in [VoteKind::Echo, VoteKind::Ready] {
+                            votes.recorded.remove(&(id, kind));
+                        }
+                    }
+                    continue
+                }
+            };
+            outputs = match input {
+                ConsensusInput::Submit(message, notify) => {
+                    // reject reuse of a nonce before anything is broadcast
+                    let id = (message.body.public_key.into(), message.body.nonce);
+                    let checked = match bracha.pending(&id) {
+                        Some(pending) if pending.hash != message.hash => {
+                            Err(SequenceError::Conflict {
+                                nonce: message.body.nonce,
+                                existing: pending.hash.clone(),
+                            })
+                        }
+                        _ => sequencer.check(&message),
+                    };
+                    match checked {
+                        Ok(()) => {
+                            if waiting.len() > 2 * swept {
+                                forget_closed(&mut waiting);
+                                swept = waiting.len();
+                            }
+                            waiting
+                                .entry(message.hash.clone())
+                                .or_default()
+                                .push(notify);
+                            bracha.broadcast(message)
+                        }
+                        // a conflicting message is evidence against the sender
+                        Err(SequenceError::Conflict { nonce, existing }) => {
+                            let outputs = match conflicting(&db, &bracha, &pending, &id, &existing)
+                            {
+                                Some(existing) => {
+                                    bracha.report(EquivocationProof::sender(existing, message))
+                                }
+                                None => vec![],
+                            };
+                            let _ = notify.send(Submission::Rejected(SequenceError::Conflict {
+                                nonce,
+                                existing,
+                            }));
+                            outputs
+                        }
+                        Err(e) => {
+                            let _ = notify.send(Submission::Rejected(e));
+                            continue
+                        }
+                    }
+                }
+                ConsensusInput::Message(from, message) => bracha.handle(from, message),
+                ConsensusInput::Reconfigure(reconfiguration, notify) => {
+                    match bracha.reconfigure(reconfiguration) {
+                        Ok(outputs) => {
+                            let _ = notify.send(Ok(bracha.validators().clone()));
+                            outputs
+                        }
+                        Err(e) => {
+                            let _ = notify.send(Err(e));
+                            continue
+                        }
+                    }
+                }
+                ConsensusInput::Waiting(notify) => {
+                    let _ = notify.send(sequencer.waiting());
+                    continue
+                }
+            };
+        }
+
+        Ok(())
+    });
+
+    (ConsensusHandle { send }, handle)
+}
+
+/// Seeds `sequencer` and `bracha` with what was released, delivered and voted
+/// for before a restart. Returns what is pending, our votes and what the
+/// restored deliveries and votes need done.
+fn restore(
+    db: &EquityDatabase,
+    bracha: &mut Bracha,
+    sequencer: &mut Sequencer,
+) -> Result<(Pending, Votes, Vec<Output>), serde_json::Error> {
+    let accounts: Vec<VerificationKey> = db.get("accounts")?.unwrap_or_default();
+    for account in &accounts {
+        let last: u64 = db.get(("last_nonce", account))?.unwrap_or_default();
+        for nonce in 1..=last {
+            let hash: String = match db.get(("released", account, nonce))? {
+                Some(hash) => hash,
+                None => continue,
+            };
+            if let Some(record) = db.get::<_, TxRecord>(&hash)? {
+                bracha.restore_delivered(record.message);
+            }
+            sequencer.restore((*account).into(), nonce, hash);
+        }
+    }
+
+    let pending: Pending = db.get("pending")?.unwrap_or_default();
+    let mut outputs = vec![];
+    let mut delivered = HashSet::new();
+    for (message, certificate) in pending.delivered.values() {
+        bracha.restore_delivered(message.clone());
+        delivered.insert((message.body.public_key.into(), message.body.nonce));
+        outputs.push(Output::Deliver(message.clone(), certificate.clone()));
+    }
+
+    let mut votes = Votes {
+        recorded: HashMap::new(),
+        next: db.get("votes")?.unwrap_or_default(),
+    };
+    let first: u64 = db.get("first_vote")?.unwrap_or_default();
+    // votes of instances that delivered since are skipped, and no longer
+    // looked at once no vote before them is needed
+    let mut needed = None;
+    for n in first..votes.next {
+        let (hash, kind): (String, VoteKind) = match db.get(("voted", n))? {
+            Some(voted) => voted,
+            None => continue,
+        };
+        let vote: ConsensusMessage = match db.get(("vote", &hash, kind))? {
+            Some(vote) => vote,
+            None => continue,
+        };
+        let (account, nonce) = match vote.message() {
+            Some(message) => (message.body.public_key, message.body.nonce),
+            None => continue,
+        };
+        let id: InstanceId = (account.into(), nonce);
+        if delivered.contains(&id) || db.get::<_, String>(("released", account, nonce))?.is_some() {
+            continue
+        }
+        needed.get_or_insert(n);
+        votes.recorded.insert((id, kind), hash);
+        outputs.extend(bracha.restore_vote(vote));
+    }
+    let needed = needed.unwrap_or(votes.next);
+    if needed != first {
+        db.set("first_vote", needed)?;
+    }
+
+    if !accounts.is_empty() || !outputs.is_empty() {
+        info!(
+            target: "equity-core",
+            "Restored {} accounts, {} held back transactions and {} votes",
+            accounts.len(),
+            pending.delivered.len(),
+            votes.recorded.len()
+        );
+    }
+    Ok((pending, votes, outputs))
+}
+
+/// Records our vote in `message` before it is sent, if it is one and not
+/// recorded yet. Returns whether the message may be sent.
+fn record_vote(db: &EquityDatabase, votes: &mut Votes, message: &ConsensusMessage) -> bool {
+    let (kind, voted) = match message {
+        ConsensusMessage::Echo(voted, _) => (VoteKind::Echo, voted),
+        ConsensusMessage::Ready(voted, _) => (VoteKind::Ready, voted),
+        _ => return true,
+    };
+    let id = ((voted.body.public_key.into(), voted.body.nonce), kind);
+    if votes.recorded.get(&id) == Some(&voted.hash) {
+        return true
+    }
+
+    let mut batch = Batch::default();
+    let recorded = batch
+        .set(("vote", &voted.hash, kind), message)
+        .and_then(|_| batch.set(("voted", votes.next), (&voted.hash, kind)))
+        .and_then(|_| batch.set("votes", votes.next + 1))
+        .and_then(|_| db.write(batch));
+    match recorded {
+        Ok(()) => {
+            votes.recorded.insert(id, voted.hash.clone());
+            votes.next += 1;
+            true
+        }
+        // a vote we might forget must not be sent
+        Err(e) => {
+            warn!(target: "equity-core", "Could not record our vote: {}", e);
+            false
+        }
+    }
+}
+
+fn save_pending(db: &EquityDatabase, pending: &Pending) {
+    if let Err(e) = pending.save(db) {
+        warn!(target: "equity-core", "Could not record pending transactions: {}", e);
+    }
+}
+
+/// Records that a message was released for the nonce of its account
+fn record_release(
+    db: &EquityDatabase,
+    batch: &mut Batch,
+    message: &FullMessage,
+) -> DatabaseResult<()> {
+    let (account, nonce) = (message.body.public_key, message.body.nonce);
+    // nonces are released from 1 on
+    if nonce == 1 {
+        let mut accounts: Vec<VerificationKey> = db.get("accounts")?.unwrap_or_default();
+        accounts.push(account);
+        batch.set("accounts", accounts)?;
+    }
+    batch.set(("released", account, nonce), &message.hash)?;
+    batch.set(("last_nonce", account), nonce)
+}
+
+/// Applies a released message to the state and records it with its receipt,
+/// together with its release
+fn deliver(
+    db: &EquityDatabase,
+    state: &mut impl StateMachine,
+    waiting: &mut HashMap<String, Vec<oneshot::Sender<Submission>>>,
+    pending: &mut Pending,
+    message: FullMessage,
+) {
+    let hash = message.hash.clone();
+    let certificate = match pending.delivered.remove(&hash) {
+        Some((_, certificate)) => certificate,
+        None => return,
+    };
+    let mut batch = Batch::default();
+    if let Err(e) =
+        record_release(db, &mut batch, &message).and_then(|_| batch.set("pending", &*pending))
+    {
+        warn!(target: "equity-core", "Could not record the release of {}: {}", hash, e);
+    }
+    // the release is recorded even if the message can't be applied
+    let mut applied = batch.clone();
+    let receipt = match state.apply(db, &mut applied, &message) {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            warn!(target: "equity-core", "Could not apply transaction {}: {}", hash, e);
+            if let Err(e) = db.write(batch) {
+                warn!(target: "equity-core", "Could not record the release of {}: {}", hash, e);
+            }
+            notify(waiting, &hash, || Submission::Failed(e.to_string()));
+            return
+        }
+    };
+    info!(
+        target: "equity-core",
+        "Delivered transaction {}, state root is {}", hash, receipt.state_root
+    );
+
+    let record = TxRecord {
+        message,
+        certificate,
+        receipt,
+    };
+    if let Err(e) = applied.set(&hash, &record).and_then(|_| db.write(applied)) {
+        warn!(target: "equity-core", "Could not record transaction {}: {}", hash, e);
+        notify(waiting, &hash, || Submission::Failed(e.to_string()));
+        return
+    }
+
+    notify(waiting, &hash, || {
+        Submission::Delivered(Box::new(record.clone()))
+    });
+}
+
+/// Records `validators` under their epoch and as the current ones
+fn record_validators(db: &EquityDatabase, validators: &ValidatorSet) {
+    let mut batch = Batch::default();
+    let recorded = batch
+        .set(("validators", validators.epoch), validators)
+        .and_then(|_| batch.set("epoch", validators.epoch))
+        .and_then(|_| db.write(batch));
+    if let Err(e) = recorded {
+        warn!(target: "equity-core", "Could not record validators: {}", e);
+    }
+}
+
+/// The message an account already used a nonce for, either still being
+/// broadcast or already recorded
+fn conflicting(
+    db: &EquityDatabase,
+    bracha: &Bracha,
+    pending: &Pending,
+    id: &InstanceId,
+    hash: &str,
+) -> Option<FullMessage> {
+    match bracha.pending(id) {
+        Some(message) if message.hash == hash => Some(message.clone()),
+        _ => delivered(db, pending, hash),
+    }
+}
+
+/// The delivered message with `hash`, whether it is held back or applied
+fn delivered(db: &EquityDatabase, pending: &Pending, hash: &str) -> Option<FullMessage> {
+    match pending.delivered.get(hash) {
+        Some((message, _)) => Some(message.clone()),
+        None => db
+            .get::<_, TxRecord>(hash)
+            .ok()
+            .flatten()
+            .map(|record| record.message),
+    }
+}
+
+/// Stores `proof` under its id and adds it to the list of known equivocations
+fn record_equivocation(db: &EquityDatabase, proof: EquivocationProof) {
+    let id = proof.id();
+    warn!(
+        target: "equity-core",
+        "{:?} equivocation by {:?}, evidence {}",
+        proof.kind,
+        proof.offender().as_bytes(),
+        id
+    );
+
+    let recorded = db.get::<_, Vec<String>>("equivocations").and_then(|ids| {
+        let mut ids = ids.unwrap_or_default();
+        ids.push(id.clone());
+        let mut batch = Batch::default();
+        batch.set(("equivocation", &id), proof)?;
+        batch.set("equivocations", ids)?;
+        db.write(batch)
+    });
+    if let Err(e) = recorded {
+        warn!(target: "equity-core", "Could not record equivocation {}: {}", id, e);
+    }
+}
+
+/// Drops the senders of submissions whose API request is gone, usually after
+/// it timed out, so that messages that are never delivered don't pile up.
+/// Sweeping once the map doubled keeps the cost per submission constant.
+fn forget_closed(waiting: &mut HashMap<String, Vec<oneshot::Sender<Submission>>>) {
+    waiting.retain(|_, senders| {
+        senders.retain(|sender| !sender.is_closed());
+        !senders.is_empty()
+    });
+}
+
+fn notify(
+    waiting: &mut HashMap<String, Vec<oneshot::Sender<Submission>>>,
+    hash: &str,
+    submission: impl Fn() -> Submission,
+) {
+    for notify in waiting.remove(hash).unwrap_or_default() {
+        let _ = notify.send(submission());
+    }
+}
@@ -4,6 +4,17 @@ pub enum Error {
     StdIoError(#[from] std::io::Error),
     #[error("AddrParseError")]
     AddrParseError(#[from] std::net::AddrParseError),
+    #[error("SerdeJsonError")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("ConsensusClosed")]
+    ConsensusClosed,
+    #[error("P2pError {0}")]
+    P2pError(#[from] equity_p2p::P2pError),
+    #[error("EquityError {0}")]
+    EquityError(#[from] equity_types::EquityError),
+    #[cfg(feature = "probabilistic")]
+    #[error("SampleError {0}")]
+    SampleError(#[from] equity_consensus::SampleError),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
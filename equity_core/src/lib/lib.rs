@@ -1,11 +1,13 @@
 mod api_server;
 mod borsh;
+mod consensus_server;
 mod error;
 mod p2p_server;
 mod ron;
 mod service;
 
 pub use api_server::*;
+pub use consensus_server::*;
 pub use error::*;
 pub use p2p_server::*;
 pub use service::*;
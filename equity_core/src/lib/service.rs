@@ -1,15 +1,18 @@
-use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-};
+use std::{net::SocketAddr, sync::Arc};
 
+use equity_consensus::{Bracha, KeyValueState};
+use equity_p2p::{Network, NetworkConfig};
 use equity_storage::EquityDatabase;
-use equity_types::{Credentials, EquityError, PeerMap};
+use equity_types::{Credentials, EquityError};
 use futures::future::join_all;
 use tokio::task::JoinHandle;
 
-use crate::{api_server::start_api_server, p2p_server::start_p2p_server, Error};
+use crate::{
+    api_server::start_api_server,
+    consensus_server::start_consensus_server,
+    p2p_server::{load_address_book, load_bans, start_p2p_server, start_peer_store},
+    Error,
+};
 
 pub struct EquityService {
     pub api_address: std::net::SocketAddr,
@@ -20,29 +23,39 @@ pub struct EquityService {
 impl EquityService {
     pub async fn new(
         api_listener: SocketAddr,
-        p2p_listener: SocketAddr,
-        seed_address: SocketAddr,
+        mut network: NetworkConfig,
         db: EquityDatabase,
+        credentials: Credentials,
+        consensus: Bracha,
     ) -> Result<Self, Error> {
-        let peers = PeerMap::new(Mutex::new(HashMap::new()));
-        let credentials = Arc::new(Credentials::new());
-
-        let (api_address, api_server_handle) =
-            start_api_server(api_listener, db.clone(), peers.clone(), credentials.clone()).await?;
-        let (p2p_address, p2p_server_handle) = start_p2p_server(
-            p2p_listener,
-            seed_address,
+        let credentials = Arc::new(credentials);
+
+        network.address_book.extend(load_address_book(&db));
+        network.bans.extend(load_bans(&db));
+        let (network, inbound) = Network::start(network, credentials.clone()).await?;
+        let (consensus, consensus_server_handle) =
+            start_consensus_server(db.clone(), network.clone(), consensus, KeyValueState);
+        let (api_address, api_server_handle) = start_api_server(
+            api_listener,
             db.clone(),
-            peers.clone(),
+            network.clone(),
             credentials.clone(),
+            consensus.clone(),
         )
         .await?;
+        let p2p_server_handle = start_p2p_server(inbound, consensus);
+        let peer_store_handle = start_peer_store(db, network.clone());
 
-        let tasks = vec![api_server_handle, p2p_server_handle];
+        let tasks = vec![
+            consensus_server_handle,
+            api_server_handle,
+            p2p_server_handle,
+            peer_store_handle,
+        ];
 
         Ok(Self {
             api_address,
-            p2p_address,
+            p2p_address: network.address(),
             tasks,
         })
     }
@@ -0,0 +1,16 @@
+//! Feeds arbitrary bytes through the decoding of inbound frames, which has to
+//! return errors instead of panicking
+
+#![no_main]
+
+use equity_p2p::{decode, decode_prefix};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode(data);
+
+    let mut buffer = data;
+    while let Ok(Some((_, length))) = decode_prefix(buffer) {
+        buffer = &buffer[length..];
+    }
+});
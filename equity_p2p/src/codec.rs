@@ -0,0 +1,157 @@
+//! Frames are a big endian `u32` length of the rest of the frame, a `u16`
+//! protocol version, a `FrameKind` byte and the JSON of a `P2pMessage`.
+
+use std::io;
+
+use equity_types::ConsensusMessage;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{CodecError, P2pMessage, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// Longer frames are rejected before anything is allocated for them
+pub const MAX_FRAME_LENGTH: u32 = 16 * 1024 * 1024;
+
+/// Longest frame a peer may send before it completed the handshake, so that
+/// a socket nobody authenticated can't make us hold much memory
+pub const MAX_HANDSHAKE_FRAME_LENGTH: u32 = 16 * 1024;
+
+/// The version and kind in front of the JSON
+const HEADER_LENGTH: usize = 3;
+
+/// What a frame holds, so that it can be handled before it is decoded. A
+/// frame whose kind does not match its message fails to decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Anything but a consensus message
+    Other = 0,
+    /// A consensus message that means the same whoever relays it
+    Consensus = 1,
+    /// A vote or subscription, which only counts for the peer that sent it
+    PerSender = 2,
+}
+
+impl FrameKind {
+    pub fn of(message: &P2pMessage) -> Self {
+        match message {
+            P2pMessage::Consensus(
+                ConsensusMessage::Echo(..)
+                | ConsensusMessage::Ready(..)
+                | ConsensusMessage::Subscribe(_),
+            ) => Self::PerSender,
+            P2pMessage::Consensus(_) => Self::Consensus,
+            _ => Self::Other,
+        }
+    }
+
+    /// The kind in the header of a frame without its length prefix, nothing
+    /// if the header is missing or holds an unknown kind
+    pub fn read(frame: &[u8]) -> Option<Self> {
+        match frame.get(2)? {
+            0 => Some(Self::Other),
+            1 => Some(Self::Consensus),
+            2 => Some(Self::PerSender),
+            _ => None,
+        }
+    }
+}
+
+/// The frame of `message`, length prefix included
+pub fn encode(message: &P2pMessage) -> Result<Vec<u8>, CodecError> {
+    Ok(frame(FrameKind::of(message), &serde_json::to_vec(message)?))
+}
+
+/// Frames `payload` of `kind` with the current protocol version
+pub fn frame(kind: FrameKind, payload: &[u8]) -> Vec<u8> {
+    let length = (payload.len() + HEADER_LENGTH) as u32;
+    let mut frame = Vec::with_capacity(payload.len() + 4 + HEADER_LENGTH);
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    frame.push(kind as u8);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Replaces the protocol version in the header of `frame`, which has its
+/// length prefix
+pub(crate) fn stamp(frame: &mut [u8], version: u16) {
+    if let Some(header) = frame.get_mut(4..6) {
+        header.copy_from_slice(&version.to_be_bytes());
+    }
+}
+
+/// Decodes a frame without its length prefix, of any version this build
+/// reads
+pub fn decode(frame: &[u8]) -> Result<P2pMessage, CodecError> {
+    if frame.len() < HEADER_LENGTH {
+        return Err(CodecError::Truncated)
+    }
+    let version = u16::from_be_bytes([frame[0], frame[1]]);
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(CodecError::Version(version))
+    }
+    let message = serde_json::from_slice(&frame[HEADER_LENGTH..])?;
+    if FrameKind::read(frame) != Some(FrameKind::of(&message)) {
+        return Err(CodecError::Kind(frame[2]))
+    }
+    Ok(message)
+}
+
+/// Decodes the frame at the start of `buffer`, returning the message and the
+/// length of its frame, or nothing while the frame is incomplete
+pub fn decode_prefix(buffer: &[u8]) -> Result<Option<(P2pMessage, usize)>, CodecError> {
+    match frame_end(buffer, MAX_FRAME_LENGTH)? {
+        Some(end) => Ok(Some((decode(&buffer[4..end])?, end))),
+        None => Ok(None),
+    }
+}
+
+/// Where the frame at the start of `buffer` ends, nothing while it is
+/// incomplete. Frames longer than `limit` are errors.
+pub(crate) fn frame_end(buffer: &[u8], limit: u32) -> Result<Option<usize>, CodecError> {
+    if buffer.len() < 4 {
+        return Ok(None)
+    }
+    let length = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+    if length > limit {
+        return Err(CodecError::TooLong(length))
+    }
+    let end = 4 + length as usize;
+    if buffer.len() < end {
+        return Ok(None)
+    }
+    Ok(Some(end))
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<P2pMessage, CodecError> {
+    decode(&read_frame(reader, MAX_FRAME_LENGTH).await?)
+}
+
+/// Reads a frame of at most `limit` bytes without decoding it, the length
+/// prefix is left out. The frame grows as its bytes arrive, a length the
+/// peer never sends the bytes for costs us nothing.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limit: u32,
+) -> Result<Vec<u8>, CodecError> {
+    let length = reader.read_u32().await?;
+    if length > limit {
+        return Err(CodecError::TooLong(length))
+    }
+    let mut frame = vec![];
+    reader
+        .take(u64::from(length))
+        .read_to_end(&mut frame)
+        .await?;
+    if frame.len() < length as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+    Ok(frame)
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &P2pMessage,
+) -> Result<(), CodecError> {
+    writer.write_all(&encode(message)?).await?;
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use std::{io, ops::RangeInclusive};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("IO error {0}")]
+    Io(#[from] io::Error),
+    #[error("Frame of {0} bytes is longer than allowed")]
+    TooLong(u32),
+    #[error("Frame is missing its header")]
+    Truncated,
+    #[error("Unsupported protocol version {0}")]
+    Version(u16),
+    #[error("Frame kind {0} does not match its message")]
+    Kind(u8),
+    #[error("Undecodable message {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Could not decrypt a transport message")]
+    Decrypt,
+    #[error("Could not encrypt a transport message")]
+    Encrypt,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum P2pError {
+    #[error("IO error {0}")]
+    Io(#[from] io::Error),
+    #[error("Codec error {0}")]
+    Codec(#[from] CodecError),
+    #[error("EquityError {0}")]
+    EquityError(#[from] equity_types::EquityError),
+    #[error("Handshake failed, {0}")]
+    Handshake(&'static str),
+    #[error("Peer sent nothing for too long")]
+    Timeout,
+    #[error("No seed or known peer could be reached")]
+    Unreachable,
+    #[error("Peer is not allowlisted")]
+    NotAllowed,
+    #[error("Peer did not keep up with the messages sent to it")]
+    Overloaded,
+    #[error("Peer is banned")]
+    Banned,
+    #[error("Peer is on chain `{theirs}`, not `{ours}`")]
+    WrongChain { ours: String, theirs: String },
+    #[error("Peer speaks protocol versions {theirs:?}, which don't work with our {ours:?}")]
+    IncompatibleVersion {
+        ours: RangeInclusive<u16>,
+        theirs: RangeInclusive<u16>,
+    },
+    #[error("Peer lacks the capabilities {0:?}")]
+    MissingCapabilities(Vec<String>),
+    #[error("Peer requires the capabilities {0:?}, which we lack")]
+    UnsupportedCapabilities(Vec<String>),
+}
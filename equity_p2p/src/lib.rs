@@ -1 +1,23 @@
+//! The peer to peer layer. Peers exchange `P2pMessage`s framed by the codec
+//! over TCP, encrypted with Noise unless asked otherwise, and the rest of the
+//! node only talks to the network through a `Network` handle, so the
+//! transport can change without touching consensus.
 
+mod codec;
+mod error;
+mod limits;
+mod message;
+mod network;
+mod noise;
+mod peer_map;
+mod reputation;
+mod seen;
+mod transport;
+
+pub use codec::*;
+pub use error::*;
+pub use limits::*;
+pub use message::*;
+pub use network::*;
+pub use noise::{CipherState, NoiseHandshake, NoiseKeypair};
+pub use transport::*;
@@ -0,0 +1,209 @@
+//! What keeps one peer from degrading the network for the others. Frames to
+//! a peer wait in a bounded `Outbox` instead of a channel whose sends await,
+//! so a slow peer never stalls a broadcast, and messages from a peer are
+//! metered by a `TokenBucket`.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use thiserror::Error;
+use tokio::sync::{watch, Notify};
+
+/// What happens to a frame sent to a peer whose outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// The oldest queued frame is dropped to make room
+    #[default]
+    DropOldest,
+    /// The peer is disconnected
+    Disconnect,
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("Unknown overflow policy `{0}`")]
+pub struct ParseOverflowError(String);
+
+impl FromStr for Overflow {
+    type Err = ParseOverflowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(ParseOverflowError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DropOldest => write!(f, "drop-oldest"),
+            Self::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    frames: VecDeque<Vec<u8>>,
+    closed: bool,
+    /// Closed because it was full with `Overflow::Disconnect`
+    overflowed: bool,
+    /// Frames dropped because the queue was full
+    dropped: u64,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    pushed: Notify,
+    /// Flips to true when the outbox closes
+    closing: watch::Sender<bool>,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+/// The frames waiting to be written to a peer. Clones share the queue.
+#[derive(Debug, Clone)]
+pub(crate) struct Outbox {
+    shared: Arc<Shared>,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue::default()),
+                pushed: Notify::new(),
+                closing: watch::channel(false).0,
+                capacity,
+                overflow,
+            }),
+        }
+    }
+
+    /// Queues `frame` without waiting. Returns false if the outbox is closed,
+    /// which it becomes when it overflows with `Overflow::Disconnect`.
+    pub fn push(&self, frame: Vec<u8>) -> bool {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return false
+        }
+        if queue.frames.len() >= self.shared.capacity {
+            queue.dropped += 1;
+            match self.shared.overflow {
+                Overflow::DropOldest => {
+                    queue.frames.pop_front();
+                }
+                Overflow::Disconnect => {
+                    queue.overflowed = true;
+                    drop(queue);
+                    self.close();
+                    return false
+                }
+            }
+        }
+        queue.frames.push_back(frame);
+        drop(queue);
+        self.shared.pushed.notify_one();
+        true
+    }
+
+    /// The next frame to write, nothing once the outbox is closed. Queued
+    /// frames are not lost if the future is dropped.
+    pub async fn pop(&self) -> Option<Vec<u8>> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if queue.closed {
+                    return None
+                }
+                if let Some(frame) = queue.frames.pop_front() {
+                    return Some(frame)
+                }
+            }
+            self.shared.pushed.notified().await;
+        }
+    }
+
+    /// Drops the queued frames and refuses new ones
+    pub fn close(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.closed = true;
+        queue.frames.clear();
+        drop(queue);
+        self.shared.pushed.notify_one();
+        self.shared.closing.send_replace(true);
+    }
+
+    /// Waits until the outbox is closed
+    pub async fn closed(&self) {
+        let mut closing = self.shared.closing.subscribe();
+        while !*closing.borrow() {
+            if closing.changed().await.is_err() {
+                return
+            }
+        }
+    }
+
+    /// Whether the outbox closed because the peer could not keep up
+    pub fn overflowed(&self) -> bool {
+        self.shared.queue.lock().unwrap().overflowed
+    }
+
+    /// Frames waiting to be written
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().frames.len()
+    }
+
+    /// Frames dropped because the outbox was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.queue.lock().unwrap().dropped
+    }
+
+    /// Whether both share the same queue
+    pub fn same_outbox(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+}
+
+/// Lets `rate` messages or bytes through per second on average, and up to
+/// `burst` at once
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes `count` tokens if that many are left
+    pub fn take(&mut self, count: usize) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+        let count = count as f64;
+        if self.tokens < count {
+            return false
+        }
+        self.tokens -= count;
+        true
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::RangeInclusive,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_consensus::{Signature, VerificationKey};
+pub use equity_types::DEFAULT_CHAIN_ID;
+use equity_types::{ConsensusMessage, Credentials, EquityError};
+use serde::{Deserialize, Serialize};
+
+use crate::P2pError;
+
+/// The newest version this build reads and writes, sent in the header of
+/// every frame. Bumped whenever messages change in a way older builds can't
+/// read.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// The oldest version this build reads, frames of older versions are
+/// dropped. Connections to peers that only speak older versions are refused.
+/// Only lower it while what this build sends is still readable by those
+/// versions, which lets a network upgrade one node at a time.
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+
+/// Everything peers send each other
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum P2pMessage {
+    /// The first message of both sides of a connection
+    Challenge(Challenge),
+    /// The answer of the dialing side to the challenge of the listening side
+    Init(InitMessage),
+    /// The answer of the listening side to `Init`, with the peers it knows
+    InitResponse(InitResponse),
+    /// Asks for the peers a node is connected to
+    GetPeers,
+    /// The listener addresses and keys of the peers a node is connected to
+    Peers(BTreeMap<String, VerificationKey>),
+    Consensus(ConsensusMessage),
+    Ping(u64),
+    /// The answer to the `Ping` with the same nonce
+    Pong(u64),
+    /// Gossip of the fresh records a node knows, its own included
+    PeerRecords(Vec<PeerRecord>),
+}
+
+/// Fresh random bytes the other side of a handshake has to sign, so that an
+/// old handshake can't be replayed
+pub type Challenge = [u8; 32];
+
+pub fn challenge() -> Challenge {
+    rand::random()
+}
+
+/// What a node tells its peers about its build in the handshake
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Protocol {
+    /// The oldest protocol version the node speaks
+    pub min_version: u16,
+    /// The newest protocol version the node speaks
+    pub version: u16,
+    /// Nodes of different chains never connect
+    pub chain_id: String,
+    /// Optional features of the node, peers ignore the ones they don't know
+    pub capabilities: BTreeSet<String>,
+    /// Capabilities peers have to offer to connect
+    pub required: BTreeSet<String>,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self {
+            min_version: MIN_PROTOCOL_VERSION,
+            version: PROTOCOL_VERSION,
+            chain_id: DEFAULT_CHAIN_ID.to_owned(),
+            capabilities: BTreeSet::new(),
+            required: BTreeSet::new(),
+        }
+    }
+}
+
+impl Protocol {
+    /// The versions the node speaks
+    pub fn versions(&self) -> RangeInclusive<u16> {
+        self.min_version..=self.version
+    }
+
+    /// The newest version we and a peer announcing `theirs` both speak. Both
+    /// have to be on the same chain and offer what the other requires, so
+    /// both sides of a handshake come to the same verdict.
+    pub fn negotiate(&self, theirs: &Protocol) -> Result<u16, P2pError> {
+        if theirs.chain_id != self.chain_id {
+            return Err(P2pError::WrongChain {
+                ours: self.chain_id.clone(),
+                theirs: theirs.chain_id.clone(),
+            })
+        }
+        let version = self.version.min(theirs.version);
+        if version < self.min_version.max(theirs.min_version) {
+            return Err(P2pError::IncompatibleVersion {
+                ours: self.versions(),
+                theirs: theirs.versions(),
+            })
+        }
+        let missing: Vec<_> = self
+            .required
+            .difference(&theirs.capabilities)
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(P2pError::MissingCapabilities(missing))
+        }
+        let unsupported: Vec<_> = theirs
+            .required
+            .difference(&self.capabilities)
+            .cloned()
+            .collect();
+        if !unsupported.is_empty() {
+            return Err(P2pError::UnsupportedCapabilities(unsupported))
+        }
+        Ok(version)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Initiate {
+    pub public_key: VerificationKey,
+    pub nonce: u64,
+}
+
+/// Signed by the dialing side over its `Initiate`, its listener address, its
+/// protocol and the challenge of the listening side
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct InitMessage {
+    pub initiate: Initiate,
+    /// The address the dialing node accepts connections on
+    pub listener: String,
+    pub protocol: Protocol,
+    pub hash: String,
+    pub signature: Signature,
+}
+
+/// Signed by the listening side over its peers, its key, its listener
+/// address, its protocol and the challenge of the dialing side. Sent even when
+/// the protocols don't match, without peers, so both sides know why the
+/// connection is refused.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct InitResponse {
+    pub peer_map: BTreeMap<String, VerificationKey>,
+    pub public_key: VerificationKey,
+    pub listener: String,
+    pub protocol: Protocol,
+    pub hash: String,
+    pub signature: Signature,
+}
+
+impl InitMessage {
+    pub fn sign(
+        credentials: &Credentials,
+        listener: &str,
+        protocol: &Protocol,
+        challenge: &Challenge,
+    ) -> Result<Self, EquityError> {
+        let initiate = Initiate {
+            public_key: credentials.public_key,
+            nonce: credentials.nonce,
+        };
+        let (hash, signature) =
+            credentials.hash_sign(&Self::payload(&initiate, listener, protocol, challenge)?);
+        Ok(Self {
+            initiate,
+            listener: listener.to_owned(),
+            protocol: protocol.clone(),
+            hash,
+            signature,
+        })
+    }
+
+    /// Checks that the message is signed by its key and answers `challenge`
+    pub fn verify(&self, challenge: &Challenge) -> Result<(), EquityError> {
+        let payload = Self::payload(&self.initiate, &self.listener, &self.protocol, challenge)?;
+        verify(
+            &self.initiate.public_key,
+            &payload,
+            &self.hash,
+            &self.signature,
+        )
+    }
+
+    fn payload(
+        initiate: &Initiate,
+        listener: &str,
+        protocol: &Protocol,
+        challenge: &Challenge,
+    ) -> serde_json::Result<String> {
+        serde_json::to_string(&("INIT", initiate, listener, protocol, challenge))
+    }
+}
+
+impl InitResponse {
+    pub fn sign(
+        credentials: &Credentials,
+        listener: &str,
+        protocol: &Protocol,
+        peer_map: BTreeMap<String, VerificationKey>,
+        challenge: &Challenge,
+    ) -> Result<Self, EquityError> {
+        let payload = Self::payload(
+            &peer_map,
+            &credentials.public_key,
+            listener,
+            protocol,
+            challenge,
+        )?;
+        let (hash, signature) = credentials.hash_sign(&payload);
+        Ok(Self {
+            peer_map,
+            public_key: credentials.public_key,
+            listener: listener.to_owned(),
+            protocol: protocol.clone(),
+            hash,
+            signature,
+        })
+    }
+
+    /// Checks that the response is signed by its key and answers `challenge`
+    pub fn verify(&self, challenge: &Challenge) -> Result<(), EquityError> {
+        let payload = Self::payload(
+            &self.peer_map,
+            &self.public_key,
+            &self.listener,
+            &self.protocol,
+            challenge,
+        )?;
+        verify(&self.public_key, &payload, &self.hash, &self.signature)
+    }
+
+    fn payload(
+        peer_map: &BTreeMap<String, VerificationKey>,
+        public_key: &VerificationKey,
+        listener: &str,
+        protocol: &Protocol,
+        challenge: &Challenge,
+    ) -> serde_json::Result<String> {
+        serde_json::to_string(&(
+            "INIT_RESPONSE",
+            peer_map,
+            public_key,
+            listener,
+            protocol,
+            challenge,
+        ))
+    }
+}
+
+/// Signed by a node over the address it can be dialed at. Nodes gossip the
+/// records they know, so everyone learns about nodes that joined later.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerRecord {
+    pub public_key: VerificationKey,
+    pub address: String,
+    /// Milliseconds since the Unix epoch when the record was signed
+    pub timestamp: u64,
+    pub hash: String,
+    pub signature: Signature,
+}
+
+impl PeerRecord {
+    pub fn sign(credentials: &Credentials, address: &str) -> Result<Self, EquityError> {
+        Self::sign_at(credentials, address, now())
+    }
+
+    /// Signs a record with the given `timestamp` instead of the current time
+    pub fn sign_at(
+        credentials: &Credentials,
+        address: &str,
+        timestamp: u64,
+    ) -> Result<Self, EquityError> {
+        let payload = Self::payload(&credentials.public_key, address, timestamp)?;
+        let (hash, signature) = credentials.hash_sign(&payload);
+        Ok(Self {
+            public_key: credentials.public_key,
+            address: address.to_owned(),
+            timestamp,
+            hash,
+            signature,
+        })
+    }
+
+    /// Checks that the record is signed by its key
+    pub fn verify(&self) -> Result<(), EquityError> {
+        let payload = Self::payload(&self.public_key, &self.address, self.timestamp)?;
+        verify(&self.public_key, &payload, &self.hash, &self.signature)
+    }
+
+    /// Whether the record was signed less than `ttl` ago. Records from
+    /// further than `ttl` in the future are not fresh either.
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        let ttl = ttl.as_millis() as u64;
+        let now = now();
+        now.saturating_sub(self.timestamp) < ttl && self.timestamp.saturating_sub(now) < ttl
+    }
+
+    fn payload(
+        public_key: &VerificationKey,
+        address: &str,
+        timestamp: u64,
+    ) -> serde_json::Result<String> {
+        serde_json::to_string(&("PEER_RECORD", public_key, address, timestamp))
+    }
+}
+
+/// Milliseconds since the Unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn verify(
+    public_key: &VerificationKey,
+    payload: &str,
+    hash: &str,
+    signature: &Signature,
+) -> Result<(), EquityError> {
+    if equity_types::hash(payload) != hash {
+        return Err(EquityError::HashMismatch)
+    }
+    public_key
+        .verify(signature, hash.as_bytes())
+        .map_err(Into::into)
+}
@@ -0,0 +1,1279 @@
+//! Connections to peers. Unless the network runs in plaintext, a connection
+//! starts with a Noise handshake, and the ed25519 key it proves has to be the
+//! one of the handshake that follows. Both sides then send a fresh
+//! challenge. The side that dialed answers with a signed `Init`, the other side
+//! with a signed `InitResponse` holding the peers it is connected to. Peers
+//! whose signature does not cover our challenge are refused. A joining node
+//! dials its seeds and then every peer of the seeds, as well as the peers of
+//! its address book.
+//!
+//! Until the handshake is done, frames are limited to a few KiB and the
+//! handshake has its own short timeout. Only so many handshakes of peers
+//! that dialed us go on at once, further connections are closed right away.
+//!
+//! Both sides ping each other and drop connections that stay silent for
+//! longer than the keepalive timeout. The side that dialed then keeps dialing
+//! the peer again, backing off exponentially, until it answers or dials us.
+//!
+//! Frames to a peer wait in a bounded queue, so sending never waits on a
+//! slow peer. What happens when the queue is full is configurable. Messages
+//! a peer sends faster than its rate limit, in frames or bytes, are dropped
+//! before they are decoded.
+//!
+//! Repeats of recent consensus messages, which arrive once they are relayed,
+//! are dropped before they are decoded as well. They count against the rate
+//! limit like any other message.
+//!
+//! Peers lose score for what they do wrong, here or in consensus, and are
+//! disconnected and banned once it drops to the threshold. Banned peers are
+//! neither accepted nor dialed until the ban ends.
+//!
+//! With an allowlist, handshakes with keys outside of it are refused on both
+//! sides. If observers are allowed, other keys may still dial us, but only
+//! receive messages. What they send besides pings and `GetPeers` is dropped.
+
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use ed25519_consensus::{VerificationKey, VerificationKeyBytes};
+use equity_types::{
+    Ban, ConsensusMessage, Credentials, DedupStats, Offense, PeerReputation, PeerStatus,
+    ValidatorSet,
+};
+use rand::{seq::SliceRandom, thread_rng};
+#[cfg(feature = "byzantine")]
+use tokio::io::AsyncWriteExt;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore},
+    time::{interval, sleep, timeout},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    challenge,
+    codec::stamp,
+    decode, encode,
+    limits::{Outbox, TokenBucket},
+    noise::NoiseKeypair,
+    peer_map::{Peer, PeerMap},
+    reputation::Reputation,
+    seen::SeenCache,
+    transport::{secure, Reader, Writer},
+    Challenge, CodecError, InitMessage, InitResponse, Overflow, P2pError, P2pMessage, PeerRecord,
+    Protocol, Transport, MAX_FRAME_LENGTH, MAX_HANDSHAKE_FRAME_LENGTH, PROTOCOL_VERSION,
+};
+
+/// A consensus message from the peer with the `from` key
+#[derive(Debug)]
+pub struct Inbound {
+    pub from: VerificationKey,
+    pub message: ConsensusMessage,
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub listener: SocketAddr,
+    /// The address peers should dial us at, as a hostname or IP and port.
+    /// Defaults to the listener address, which is not dialable when binding
+    /// to an unspecified IP.
+    pub advertise: Option<String>,
+    /// Nodes to join the network through as hostnames or IPs with a port,
+    /// none for the first node. Hostnames are resolved on every dial. Joining
+    /// succeeds as long as one of them or a peer of the address book answers.
+    pub seeds: Vec<String>,
+    /// Records of peers known from an earlier run, dialed on start
+    pub address_book: Vec<PeerRecord>,
+    pub transport: Transport,
+    pub keepalive: Keepalive,
+    pub gossip: Gossip,
+    /// Only these peers may connect, anyone may if none is given
+    pub allowlist: Option<Allowlist>,
+    pub limits: Limits,
+    pub scoring: Scoring,
+    /// Bans of an earlier run, kept until they end
+    pub bans: Vec<Ban>,
+    pub dedup: Dedup,
+    /// Announced to peers in the handshake
+    pub protocol: Protocol,
+    #[cfg(feature = "byzantine")]
+    pub faults: Faults,
+}
+
+impl NetworkConfig {
+    pub fn new(listener: SocketAddr, seeds: Vec<SocketAddr>) -> Self {
+        Self {
+            listener,
+            advertise: None,
+            seeds: seeds.iter().map(ToString::to_string).collect(),
+            address_book: vec![],
+            transport: Transport::default(),
+            keepalive: Keepalive::default(),
+            gossip: Gossip::default(),
+            allowlist: None,
+            limits: Limits::default(),
+            scoring: Scoring::default(),
+            bans: vec![],
+            dedup: Dedup::default(),
+            protocol: Protocol::default(),
+            #[cfg(feature = "byzantine")]
+            faults: Faults::default(),
+        }
+    }
+}
+
+/// Timing of pings and reconnection attempts
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// How often every peer is pinged
+    pub interval: Duration,
+    /// Peers that sent nothing for this long are disconnected
+    pub timeout: Duration,
+    /// Handshakes that did not finish in this time are given up
+    pub handshake: Duration,
+    /// Wait before the first reconnection attempt, doubled after every
+    /// failed one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(20),
+            handshake: Duration::from_secs(5),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Ways a node misbehaves towards its peers, only for testing
+#[cfg(feature = "byzantine")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faults {
+    /// Send an undecodable `Init` on an extra connection before every
+    /// handshake
+    pub malformed_init: bool,
+    /// Add a peer that does not exist to every `InitResponse`
+    pub forge_peer_map: bool,
+}
+
+/// Timing and bounds of the peer record exchange
+#[derive(Debug, Clone, Copy)]
+pub struct Gossip {
+    /// How often a node sends the records it knows to its peers
+    pub interval: Duration,
+    /// Records signed longer ago than this are dropped
+    pub ttl: Duration,
+    /// Records taken from a single message, the rest are dropped
+    pub per_message: usize,
+    /// Records kept at most, the oldest make room for newer ones
+    pub capacity: usize,
+    /// Failed dials after which an address only learned from gossip is given
+    /// up
+    pub attempts: u32,
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            ttl: Duration::from_secs(10 * 60),
+            per_message: 100,
+            capacity: 1000,
+            attempts: 5,
+        }
+    }
+}
+
+/// Bounds on what a single peer can make us do
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Messages a peer may send per second on average
+    pub rate: u32,
+    /// Messages a peer may send at once after being quiet
+    pub burst: u32,
+    /// Bytes of frames a peer may send per second on average
+    pub bytes: u32,
+    /// Bytes a peer may send at once after being quiet, at least
+    /// `MAX_FRAME_LENGTH` so that every frame can get through
+    pub byte_burst: u32,
+    /// A peer over its limits is charged one `Spam` offense per window of
+    /// this length, however many messages it sends
+    pub spam_window: Duration,
+    /// Frames that may wait to be written to a peer
+    pub queue: usize,
+    pub overflow: Overflow,
+    /// Handshakes of peers that dialed us that may be going on at once,
+    /// further connections are closed until one of them finished
+    pub handshakes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            rate: 500,
+            burst: 2000,
+            bytes: 4 * 1024 * 1024,
+            byte_burst: MAX_FRAME_LENGTH,
+            spam_window: Duration::from_secs(1),
+            queue: 1000,
+            overflow: Overflow::default(),
+            handshakes: 64,
+        }
+    }
+}
+
+/// When peers are banned
+#[derive(Debug, Clone, Copy)]
+pub struct Scoring {
+    /// Peers whose score drops to this are banned, scores start at zero
+    pub threshold: i64,
+    /// How long a ban lasts
+    pub ban: Duration,
+    /// Offenses older than this no longer count
+    pub memory: Duration,
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Self {
+            threshold: -100,
+            ban: Duration::from_secs(60 * 60),
+            memory: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Bounds of the cache of seen consensus messages
+#[derive(Debug, Clone, Copy)]
+pub struct Dedup {
+    /// Messages remembered at most, the oldest are forgotten first
+    pub capacity: usize,
+    /// How long a message is remembered
+    pub ttl: Duration,
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Self {
+            capacity: 100_000,
+            ttl: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+/// The peers of a permissioned network
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    pub keys: BTreeSet<VerificationKeyBytes>,
+    /// Let other keys dial us as read-only observers
+    pub observers: bool,
+}
+
+impl Allowlist {
+    /// Swaps the keys of the `previous` validators for those of `next`. A key
+    /// that leaves the validators is removed even if it was also listed on its
+    /// own.
+    pub fn reconfigure(&mut self, previous: &ValidatorSet, next: &ValidatorSet) {
+        for validator in &previous.validators {
+            self.keys.remove(&validator.public_key.into());
+        }
+        self.keys.extend(
+            next.validators
+                .iter()
+                .map(|validator| VerificationKeyBytes::from(validator.public_key)),
+        );
+    }
+}
+
+/// A peer that dropped and is being dialed again
+#[derive(Debug)]
+struct Reconnecting {
+    public_key: VerificationKey,
+    failed_attempts: u32,
+}
+
+/// A peer that completed the handshake and was registered, ready to be served
+struct Connection {
+    listener: String,
+    public_key: VerificationKey,
+    read: Reader,
+    send: Outbox,
+}
+
+/// Handle to the peers of a node
+#[derive(Debug, Clone)]
+pub struct Network {
+    address: SocketAddr,
+    /// Sent in handshakes and peer records as the address to dial us at
+    advertised: String,
+    credentials: Arc<Credentials>,
+    transport: Transport,
+    keepalive: Keepalive,
+    /// Static Noise key, only used for this run of the node
+    keypair: Arc<NoiseKeypair>,
+    peers: Arc<Mutex<PeerMap>>,
+    reconnecting: Arc<Mutex<HashMap<String, Reconnecting>>>,
+    gossip: Gossip,
+    /// The newest fresh record of every node we heard of
+    records: Arc<Mutex<HashMap<VerificationKeyBytes, PeerRecord>>>,
+    allowlist: Arc<Mutex<Option<Allowlist>>>,
+    /// Handshakes refused because of the allowlist
+    rejected: Arc<AtomicU64>,
+    limits: Limits,
+    /// Permits for the handshakes of peers that dialed us
+    handshakes: Arc<Semaphore>,
+    reputation: Arc<Mutex<Reputation>>,
+    /// Woken whenever a peer is banned
+    banned: Arc<Notify>,
+    seen: Arc<Mutex<SeenCache>>,
+    protocol: Arc<Protocol>,
+    /// Pings carry the time since this instant
+    started: Instant,
+    inbound: mpsc::Sender<Inbound>,
+    #[cfg(feature = "byzantine")]
+    faults: Faults,
+}
+
+impl Network {
+    /// Starts accepting peers on the listener of `config` and joins the
+    /// network through its seeds and address book. Consensus messages from
+    /// peers arrive on the returned receiver.
+    pub async fn start(
+        config: NetworkConfig,
+        credentials: Arc<Credentials>,
+    ) -> Result<(Self, mpsc::Receiver<Inbound>), P2pError> {
+        let listener = TcpListener::bind(config.listener).await?;
+        let (inbound, receiver) = mpsc::channel(1000);
+        let ours = VerificationKeyBytes::from(credentials.public_key);
+        let address = listener.local_addr()?;
+        let network = Self {
+            address,
+            advertised: config.advertise.unwrap_or_else(|| address.to_string()),
+            credentials,
+            transport: config.transport,
+            keepalive: config.keepalive,
+            keypair: Arc::new(NoiseKeypair::generate()),
+            peers: Arc::new(Mutex::new(PeerMap::new(ours))),
+            reconnecting: Arc::new(Mutex::new(HashMap::new())),
+            gossip: config.gossip,
+            records: Arc::new(Mutex::new(HashMap::new())),
+            allowlist: Arc::new(Mutex::new(config.allowlist)),
+            rejected: Arc::new(AtomicU64::new(0)),
+            limits: config.limits,
+            handshakes: Arc::new(Semaphore::new(config.limits.handshakes)),
+            reputation: Arc::new(Mutex::new(Reputation::new(config.scoring, config.bans))),
+            banned: Arc::new(Notify::new()),
+            seen: Arc::new(Mutex::new(SeenCache::new(config.dedup))),
+            protocol: Arc::new(config.protocol),
+            started: Instant::now(),
+            inbound,
+            #[cfg(feature = "byzantine")]
+            faults: config.faults,
+        };
+
+        tokio::spawn(network.clone().listen(listener));
+        info!(
+            target: "equity-p2p",
+            "P2P Server started at: {}, advertised as {}", network.address, network.advertised
+        );
+
+        let mut joined = config.seeds.is_empty();
+        for seed in config.seeds {
+            match network.join(&seed).await {
+                Ok(()) => joined = true,
+                Err(e) => warn!(target: "equity-p2p", "Could not join through {}: {}", seed, e),
+            }
+        }
+        joined |= network.reconnect_to(config.address_book).await;
+        if !joined {
+            return Err(P2pError::Unreachable)
+        }
+        tokio::spawn(network.clone().exchange());
+        Ok((network, receiver))
+    }
+
+    /// The address the listener is bound to
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The address peers are told to dial us at
+    pub fn advertised_address(&self) -> &str {
+        &self.advertised
+    }
+
+    /// The listener addresses and keys of the connected peers
+    pub fn peers(&self) -> BTreeMap<String, VerificationKey> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|peer| (peer.address.clone(), peer.public_key))
+            .collect()
+    }
+
+    /// The connected peers that may be dialed by others, observers are left
+    /// out
+    fn shared_peers(&self) -> BTreeMap<String, VerificationKey> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|peer| !peer.observer)
+            .map(|peer| (peer.address.clone(), peer.public_key))
+            .collect()
+    }
+
+    /// The shared peers told to a peer in the handshake, as many as fit in
+    /// half of a handshake frame. It learns about the rest from gossip.
+    fn handshake_peers(&self) -> BTreeMap<String, VerificationKey> {
+        let mut peers: Vec<_> = self.shared_peers().into_iter().collect();
+        peers.shuffle(&mut thread_rng());
+        let mut room = MAX_HANDSHAKE_FRAME_LENGTH as usize / 2;
+        peers
+            .into_iter()
+            .filter(|peer| {
+                let length = serde_json::to_vec(peer).map_or(usize::MAX, |json| json.len());
+                match room.checked_sub(length) {
+                    Some(left) => {
+                        room = left;
+                        true
+                    }
+                    None => false,
+                }
+            })
+            .collect()
+    }
+
+    /// The peers that may connect, anyone may if there is none
+    pub fn allowlist(&self) -> Option<Allowlist> {
+        self.allowlist.lock().unwrap().clone()
+    }
+
+    /// Replaces the allowlist and disconnects the peers it no longer lets in,
+    /// peers that are still let in as observers may dial us again
+    pub fn set_allowlist(&self, allowlist: Option<Allowlist>) {
+        let observers = allowlist
+            .as_ref()
+            .is_some_and(|allowlist| allowlist.observers);
+        *self.allowlist.lock().unwrap() = allowlist;
+        let refused: Vec<Outbox> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|peer| !(self.allows(peer.public_key.into()) || peer.observer && observers))
+            .map(|peer| peer.send.clone())
+            .collect();
+        for send in refused {
+            send.close();
+        }
+    }
+
+    /// How many handshakes were refused because of the allowlist
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Takes score from the peer with `public_key` for `offense`, and
+    /// disconnects and bans it if its score drops to the threshold
+    pub fn report(&self, public_key: VerificationKeyBytes, offense: Offense) {
+        if public_key == self.credentials.public_key.into() {
+            return
+        }
+        let ban = match self.reputation.lock().unwrap().report(public_key, offense) {
+            Some(ban) => ban,
+            None => return,
+        };
+        warn!(
+            target: "equity-p2p",
+            "Banning {:?} until {} for {:?}", public_key, ban.until, ban.reasons
+        );
+        if let Some(send) = self.peers.lock().unwrap().send_to(public_key) {
+            send.close();
+        }
+        self.banned.notify_one();
+    }
+
+    /// Waits until a peer is banned, returns at once if one was banned since
+    /// the last call
+    pub async fn banned(&self) {
+        self.banned.notified().await
+    }
+
+    /// Whether the peer with `public_key` is banned
+    pub fn is_banned(&self, public_key: VerificationKeyBytes) -> bool {
+        self.reputation.lock().unwrap().ban(public_key).is_some()
+    }
+
+    /// The peers with recent offenses or a ban
+    pub fn reputations(&self) -> Vec<PeerReputation> {
+        self.reputation.lock().unwrap().reputations()
+    }
+
+    /// How many repeated consensus messages were dropped
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.seen.lock().unwrap().stats()
+    }
+
+    /// The bans that did not end yet, to be kept for the next run
+    pub fn bans(&self) -> Vec<Ban> {
+        self.reputation.lock().unwrap().bans()
+    }
+
+    /// The fresh records of the nodes we heard of, to be kept for the next run
+    pub fn address_book(&self) -> Vec<PeerRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|record| record.is_fresh(self.gossip.ttl))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether a peer with `public_key` is connected
+    pub fn is_reachable(&self, public_key: VerificationKeyBytes) -> bool {
+        self.peers.lock().unwrap().contains(public_key)
+    }
+
+    /// The connected peers and the ones being reconnected, by address
+    pub fn status(&self) -> BTreeMap<String, PeerStatus> {
+        let mut status: BTreeMap<_, _> = self
+            .reconnecting
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(address, peer)| {
+                (address.clone(), PeerStatus {
+                    public_key: peer.public_key,
+                    connected: false,
+                    version: None,
+                    observer: false,
+                    round_trip: None,
+                    failed_attempts: peer.failed_attempts,
+                    queued: 0,
+                    dropped: 0,
+                    limited: 0,
+                })
+            })
+            .collect();
+        for peer in self.peers.lock().unwrap().iter() {
+            status.insert(peer.address.clone(), PeerStatus {
+                public_key: peer.public_key,
+                connected: true,
+                version: Some(peer.version),
+                observer: peer.observer,
+                round_trip: peer.round_trip,
+                failed_attempts: 0,
+                queued: peer.send.len(),
+                dropped: peer.send.dropped(),
+                limited: peer.limited,
+            });
+        }
+        status
+    }
+
+    /// Sends `message` to every peer
+    pub async fn broadcast(&self, message: &P2pMessage) {
+        let sends = self.peers.lock().unwrap().broadcast();
+        self.send(sends, message)
+    }
+
+    /// Sends `message` to the peer with the `to` key, if it is connected
+    pub async fn send_to(&self, to: impl Into<VerificationKeyBytes>, message: &P2pMessage) {
+        let sends = self.peers.lock().unwrap().send_to(to.into());
+        self.send(sends, message)
+    }
+
+    /// Queues `message` for every outbox of `sends`, without waiting
+    fn send(&self, sends: impl IntoIterator<Item = Outbox>, message: &P2pMessage) {
+        let frame = match encode(message) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!(target: "equity-p2p", "Could not encode message: {}", e);
+                return
+            }
+        };
+
+        for send in sends {
+            // a closed outbox means the peer is being disconnected
+            send.push(frame.clone());
+        }
+    }
+
+    async fn listen(self, listener: TcpListener) {
+        while let Ok((stream, address)) = listener.accept().await {
+            let permit = match self.handshakes.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    debug!(target: "equity-p2p", "Closing {}, too many handshakes", address);
+                    continue
+                }
+            };
+            let network = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = network.accept(stream, permit).await {
+                    warn!(target: "equity-p2p", "Connection from {} failed: {}", address, e);
+                }
+            });
+        }
+    }
+
+    /// Serves a peer that dialed us once it completed the handshake in time,
+    /// the `permit` is held until then
+    async fn accept(self, stream: TcpStream, permit: OwnedSemaphorePermit) -> Result<(), P2pError> {
+        let handshake = timeout(self.keepalive.handshake, self.respond(stream)).await;
+        drop(permit);
+        match handshake.map_err(|_| P2pError::Timeout)?? {
+            Some(connection) => self.serve(connection).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn respond(&self, stream: TcpStream) -> Result<Option<Connection>, P2pError> {
+        let (mut read, mut write, authenticated) = self.secure(stream, false).await?;
+        let (ours, theirs) = exchange_challenges(&mut read, &mut write).await?;
+        let init = match read.read_handshake().await? {
+            P2pMessage::Init(init) => init,
+            _ => return Err(P2pError::Handshake("expected an Init message")),
+        };
+        if init.verify(&ours).is_err() {
+            return Err(P2pError::Handshake("invalid Init signature"))
+        }
+        if authenticated.is_some_and(|key| key != init.initiate.public_key) {
+            return Err(P2pError::Handshake(
+                "Init key differs from the Noise identity",
+            ))
+        }
+        let public_key = init.initiate.public_key;
+        let observer = self.admit(public_key, false)?;
+        let negotiated = self.protocol.negotiate(&init.protocol);
+
+        let peer_map = match negotiated {
+            Ok(_) => self.handshake_peers(),
+            Err(_) => BTreeMap::new(),
+        };
+        #[cfg(feature = "byzantine")]
+        let peer_map = self.forge(peer_map);
+        let response = InitResponse::sign(
+            &self.credentials,
+            &self.advertised,
+            &self.protocol,
+            peer_map,
+            &theirs,
+        )?;
+        write
+            .write_message(&P2pMessage::InitResponse(response))
+            .await?;
+        let version = negotiated?;
+
+        match self.register(
+            init.listener.clone(),
+            public_key,
+            version,
+            false,
+            observer,
+            write,
+        ) {
+            Some(send) => Ok(Some(Connection {
+                listener: init.listener,
+                public_key,
+                read,
+                send,
+            })),
+            None => {
+                debug!(target: "equity-p2p", "Dropping second connection from {}", init.listener);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Dials the valid records of an address book that are not connected yet,
+    /// returning whether any of them answered. Fresh records are kept for
+    /// gossip.
+    async fn reconnect_to(&self, address_book: Vec<PeerRecord>) -> bool {
+        let ours = VerificationKeyBytes::from(self.credentials.public_key);
+        let mut connected = false;
+        for record in address_book {
+            let key = VerificationKeyBytes::from(record.public_key);
+            if key == ours || record.verify().is_err() {
+                continue
+            }
+            if !self.is_reachable(key) {
+                match self.dial(record.address.clone()).await {
+                    Ok(_) => connected = true,
+                    Err(e) => {
+                        warn!(target: "equity-p2p", "Could not connect to {}: {}", record.address, e)
+                    }
+                }
+            }
+            if record.is_fresh(self.gossip.ttl) {
+                self.remember(key, record);
+            }
+        }
+        connected
+    }
+
+    /// Dials `seed` and then every peer it knows
+    async fn join(&self, seed: &str) -> Result<(), P2pError> {
+        let peer_map = self.dial(seed.to_owned()).await?;
+        for address in peer_map.into_keys() {
+            if let Err(e) = self.dial(address.clone()).await {
+                warn!(target: "equity-p2p", "Could not connect to {}: {}", address, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Connects to the peer at `address`, returning the peers it knows. Gives
+    /// up if the handshake does not finish within `Keepalive::handshake`.
+    async fn dial(&self, address: String) -> Result<BTreeMap<String, VerificationKey>, P2pError> {
+        let handshake = timeout(self.keepalive.handshake, self.initiate(&address)).await;
+        let (connection, peer_map) = handshake.map_err(|_| P2pError::Timeout)??;
+        let connection = match connection {
+            Some(connection) => connection,
+            None => {
+                debug!(target: "equity-p2p", "Already connected to {}", address);
+                return Ok(peer_map)
+            }
+        };
+        let (network, public_key) = (self.clone(), connection.public_key);
+        tokio::spawn(async move {
+            if let Err(e) = network.clone().serve(connection).await {
+                warn!(target: "equity-p2p", "Connection to {} failed: {}", address, e);
+            }
+            // a connection the peer dialed may have replaced this one
+            if !network.is_reachable(public_key.into()) {
+                network.reconnect(address, public_key, false).await
+            }
+        });
+        Ok(peer_map)
+    }
+
+    async fn initiate(
+        &self,
+        address: &str,
+    ) -> Result<(Option<Connection>, BTreeMap<String, VerificationKey>), P2pError> {
+        #[cfg(feature = "byzantine")]
+        self.send_malformed_init(address).await;
+
+        let stream = TcpStream::connect(address).await?;
+        let (mut read, mut write, authenticated) = self.secure(stream, true).await?;
+        let (ours, theirs) = exchange_challenges(&mut read, &mut write).await?;
+        let init = InitMessage::sign(&self.credentials, &self.advertised, &self.protocol, &theirs)?;
+        write.write_message(&P2pMessage::Init(init)).await?;
+        let response = match read.read_handshake().await? {
+            P2pMessage::InitResponse(response) => response,
+            _ => return Err(P2pError::Handshake("expected an InitResponse message")),
+        };
+        if response.verify(&ours).is_err() {
+            return Err(P2pError::Handshake("invalid InitResponse signature"))
+        }
+        if authenticated.is_some_and(|key| key != response.public_key) {
+            return Err(P2pError::Handshake(
+                "InitResponse key differs from the Noise identity",
+            ))
+        }
+        let version = self.protocol.negotiate(&response.protocol)?;
+        let public_key = response.public_key;
+        self.admit(public_key, true)?;
+        info!(target: "equity-p2p", "Connected to {}", address);
+
+        let connection = self
+            .register(
+                response.listener.clone(),
+                public_key,
+                version,
+                true,
+                false,
+                write,
+            )
+            .map(|send| Connection {
+                listener: response.listener,
+                public_key,
+                read,
+                send,
+            });
+        Ok((connection, response.peer_map))
+    }
+
+    /// Dials `address` until the peer answers or dials us, waiting twice as
+    /// long after every failed attempt. Does nothing if the address is already
+    /// being dialed. An address that was only `learned` from gossip is given
+    /// up after `Gossip::attempts` failures or once its record expired. Boxed
+    /// because it dials, which spawns the next reconnection.
+    fn reconnect(
+        self,
+        address: String,
+        public_key: VerificationKey,
+        learned: bool,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        // the attempts of a reconnection that is already running are kept
+        match self.reconnecting.lock().unwrap().entry(address.clone()) {
+            Entry::Occupied(_) => return Box::pin(async {}),
+            Entry::Vacant(entry) => entry.insert(Reconnecting {
+                public_key,
+                failed_attempts: 0,
+            }),
+        };
+
+        Box::pin(async move {
+            let mut backoff = self.keepalive.backoff;
+            loop {
+                sleep(backoff).await;
+                let key = public_key.into();
+                if self.is_reachable(key) || self.is_banned(key) || !self.allows(key) {
+                    break
+                }
+                if learned && !self.is_learned(key, &address) {
+                    debug!(target: "equity-p2p", "Giving up on {}", address);
+                    break
+                }
+                match self.dial(address.clone()).await {
+                    Ok(_) => {
+                        info!(target: "equity-p2p", "Reconnected to {}", address);
+                        break
+                    }
+                    Err(e) => {
+                        debug!(target: "equity-p2p", "Reconnecting to {} failed: {}", address, e)
+                    }
+                }
+                if let Some(peer) = self.reconnecting.lock().unwrap().get_mut(&address) {
+                    peer.failed_attempts += 1;
+                }
+                backoff = backoff.saturating_mul(2).min(self.keepalive.max_backoff);
+            }
+
+            self.reconnecting.lock().unwrap().remove(&address);
+        })
+    }
+
+    /// Sends our record and the fresh records we know to every peer, every
+    /// gossip interval
+    async fn exchange(self) {
+        let mut rounds = interval(self.gossip.interval);
+        loop {
+            rounds.tick().await;
+            let mut records: Vec<_> = {
+                let mut known = self.records.lock().unwrap();
+                known.retain(|_, record| record.is_fresh(self.gossip.ttl));
+                known.values().cloned().collect()
+            };
+            // the freshest fit into a message with ours
+            records.sort_unstable_by_key(|record| std::cmp::Reverse(record.timestamp));
+            records.truncate(self.gossip.per_message.saturating_sub(1));
+            match PeerRecord::sign(&self.credentials, &self.advertised) {
+                Ok(record) => records.push(record),
+                Err(e) => warn!(target: "equity-p2p", "Could not sign our peer record: {}", e),
+            }
+            self.broadcast(&P2pMessage::PeerRecords(records)).await;
+
+            let stats = self.dedup_stats();
+            debug!(
+                target: "equity-p2p",
+                "Dropped {} repeated consensus messages, hit rate {:.2}",
+                stats.hits,
+                stats.hit_rate()
+            );
+        }
+    }
+
+    /// Keeps the newest valid record of every node and dials the ones we are
+    /// not connected to. Of two nodes that learn about each other only the
+    /// one with the smaller key dials, so they don't connect twice.
+    fn learn(&self, from: VerificationKeyBytes, records: Vec<PeerRecord>) {
+        let ours = VerificationKeyBytes::from(self.credentials.public_key);
+        if records.len() > self.gossip.per_message {
+            debug!(
+                target: "equity-p2p",
+                "Dropping {} of {} peer records", records.len() - self.gossip.per_message, records.len()
+            );
+        }
+        for record in records.into_iter().take(self.gossip.per_message) {
+            let key = VerificationKeyBytes::from(record.public_key);
+            if key == ours
+                || !record.is_fresh(self.gossip.ttl)
+                || !self.allows(key)
+                || self.is_banned(key)
+            {
+                continue
+            }
+            let known = self.records.lock().unwrap().get(&key).cloned();
+            if known.is_some_and(|known| known.timestamp >= record.timestamp) {
+                continue
+            }
+            if record.verify().is_err() {
+                warn!(target: "equity-p2p", "Dropping peer record with an invalid signature");
+                self.report(from, Offense::InvalidSignature);
+                continue
+            }
+            if !self.remember(key, record.clone()) {
+                continue
+            }
+
+            if ours.as_ref() < key.as_ref() && !self.is_reachable(key) && !self.is_dialing(&record)
+            {
+                tokio::spawn(
+                    self.clone()
+                        .reconnect(record.address, record.public_key, true),
+                );
+            }
+        }
+    }
+
+    /// Keeps `record`, making room by dropping the oldest record if there are
+    /// too many. Returns false if `record` is the oldest.
+    fn remember(&self, key: VerificationKeyBytes, record: PeerRecord) -> bool {
+        let mut records = self.records.lock().unwrap();
+        if !records.contains_key(&key) && records.len() >= self.gossip.capacity {
+            let oldest = records
+                .iter()
+                .min_by_key(|(_, record)| record.timestamp)
+                .map(|(key, record)| (*key, record.timestamp));
+            match oldest {
+                Some((oldest, timestamp)) if timestamp < record.timestamp => {
+                    records.remove(&oldest);
+                }
+                _ => return false,
+            }
+        }
+        records.insert(key, record);
+        true
+    }
+
+    /// Whether the address of `record` is being dialed or belongs to a
+    /// connected peer
+    fn is_dialing(&self, record: &PeerRecord) -> bool {
+        self.reconnecting
+            .lock()
+            .unwrap()
+            .contains_key(&record.address)
+            || self
+                .peers
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|peer| peer.address == record.address)
+    }
+
+    /// Whether `address` is still the address in a fresh record of the node
+    /// with `key`, and dialing it did not fail too often
+    fn is_learned(&self, key: VerificationKeyBytes, address: &str) -> bool {
+        let failed = self
+            .reconnecting
+            .lock()
+            .unwrap()
+            .get(address)
+            .map_or(0, |peer| peer.failed_attempts);
+        failed < self.gossip.attempts
+            && self
+                .records
+                .lock()
+                .unwrap()
+                .get(&key)
+                .is_some_and(|record| record.address == address && record.is_fresh(self.gossip.ttl))
+    }
+
+    /// Whether `public_key` may take part in the network as more than an
+    /// observer
+    fn allows(&self, public_key: VerificationKeyBytes) -> bool {
+        self.allowlist
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|allowlist| allowlist.keys.contains(&public_key))
+    }
+
+    /// Checks a peer against the bans and the allowlist once the handshake
+    /// proved its key, returning whether it is only an observer. Only peers
+    /// that dialed us can be observers.
+    fn admit(&self, public_key: VerificationKey, outbound: bool) -> Result<bool, P2pError> {
+        if self.is_banned(public_key.into()) {
+            return Err(P2pError::Banned)
+        }
+        let observers = self
+            .allowlist
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|allowlist| allowlist.observers);
+        if self.allows(public_key.into()) {
+            return Ok(false)
+        }
+        if observers && !outbound {
+            info!(target: "equity-p2p", "Accepting observer {:?}", public_key);
+            return Ok(true)
+        }
+        let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            target: "equity-p2p",
+            "Refusing peer {:?} that is not allowlisted, {} refused so far", public_key, rejected
+        );
+        Err(P2pError::NotAllowed)
+    }
+
+    /// Runs the handshake of our transport on `stream`
+    async fn secure(
+        &self,
+        stream: TcpStream,
+        initiator: bool,
+    ) -> Result<(Reader, Writer, Option<VerificationKey>), P2pError> {
+        secure(
+            stream,
+            self.transport,
+            initiator,
+            &self.credentials,
+            &self.keypair,
+        )
+        .await
+    }
+
+    /// Adds the peer and starts writing its frames to `write`, pinging it
+    /// in between. Returns nothing if the peer map keeps an earlier
+    /// connection to the peer instead.
+    fn register(
+        &self,
+        address: String,
+        public_key: VerificationKey,
+        version: u16,
+        outbound: bool,
+        observer: bool,
+        mut write: Writer,
+    ) -> Option<Outbox> {
+        let send = Outbox::new(self.limits.queue, self.limits.overflow);
+        let peer = Peer {
+            send: send.clone(),
+            public_key,
+            address,
+            version,
+            outbound,
+            observer,
+            round_trip: None,
+            limited: 0,
+        };
+        if !self.peers.lock().unwrap().insert(peer) {
+            return None
+        }
+
+        let (started, mut pings) = (self.started, interval(self.keepalive.interval));
+        let frames = send.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut message = tokio::select! {
+                    frame = frames.pop() => match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                    _ = pings.tick() => {
+                        let ping = P2pMessage::Ping(started.elapsed().as_micros() as u64);
+                        match encode(&ping) {
+                            Ok(frame) => frame,
+                            Err(_) => continue,
+                        }
+                    }
+                };
+                // frames are encoded with our newest version
+                if version != PROTOCOL_VERSION {
+                    stamp(&mut message, version);
+                }
+                if write.write_frame(&message).await.is_err() {
+                    break
+                }
+            }
+            frames.close();
+        });
+
+        Some(send)
+    }
+
+    /// Handles the messages of a peer until its connection closes, stays
+    /// silent for too long or is replaced by another connection to the peer,
+    /// then removes it
+    async fn serve(self, connection: Connection) -> Result<(), P2pError> {
+        let Connection {
+            listener: address,
+            public_key,
+            mut read,
+            send,
+        } = connection;
+        let key = VerificationKeyBytes::from(public_key);
+        let mut bucket = TokenBucket::new(self.limits.rate, self.limits.burst);
+        let mut bytes = TokenBucket::new(self.limits.bytes, self.limits.byte_burst);
+        let mut spammed: Option<Instant> = None;
+        let result = loop {
+            let read = tokio::select! {
+                read = timeout(self.keepalive.timeout, read.read_frame()) => match read {
+                    Ok(read) => read,
+                    Err(_) => break Err(P2pError::Timeout),
+                },
+                // the peer could not keep up, or writing to it failed
+                _ = send.closed() => {
+                    if send.overflowed() {
+                        break Err(P2pError::Overloaded)
+                    }
+                    if self.is_banned(key) {
+                        break Err(P2pError::Banned)
+                    }
+                    break Ok(())
+                }
+            };
+            let frame = match read {
+                Ok(frame) => frame,
+                Err(e) => break self.unreadable(key, e),
+            };
+            // frames are metered before any work goes into them
+            let allowed = bucket.take(1) && bytes.take(frame.len());
+            let observer = match self.peers.lock().unwrap().current(key, &send) {
+                Some(peer) => {
+                    peer.limited += u64::from(!allowed);
+                    peer.observer
+                }
+                None => break Ok(()),
+            };
+            if !allowed {
+                if spammed.is_none_or(|at| at.elapsed() >= self.limits.spam_window) {
+                    spammed = Some(Instant::now());
+                    self.report(key, Offense::Spam);
+                }
+                debug!(target: "equity-p2p", "Dropping message from {} over its rate limit", address);
+                continue
+            }
+            let digest = SeenCache::digest(key, &frame);
+            if digest.is_some_and(|digest| self.seen.lock().unwrap().contains(&digest)) {
+                continue
+            }
+            let message = match decode(&frame) {
+                Ok(message) => message,
+                Err(e) => break self.unreadable(key, e),
+            };
+            let read_only = matches!(
+                message,
+                P2pMessage::Ping(_) | P2pMessage::Pong(_) | P2pMessage::GetPeers
+            );
+            if observer && !read_only {
+                debug!(target: "equity-p2p", "Dropping message from observer {}", address);
+                continue
+            }
+
+            let reply = match message {
+                P2pMessage::Consensus(message) => {
+                    if let Some(digest) = digest {
+                        self.seen.lock().unwrap().insert(digest);
+                    }
+                    let inbound = Inbound {
+                        from: public_key,
+                        message,
+                    };
+                    if self.inbound.send(inbound).await.is_err() {
+                        break Ok(())
+                    }
+                    continue
+                }
+                P2pMessage::Ping(nonce) => P2pMessage::Pong(nonce),
+                P2pMessage::GetPeers => P2pMessage::Peers(self.shared_peers()),
+                P2pMessage::Pong(sent) => {
+                    let round_trip = self
+                        .started
+                        .elapsed()
+                        .saturating_sub(Duration::from_micros(sent));
+                    if let Some(peer) = self.peers.lock().unwrap().current(key, &send) {
+                        peer.round_trip = Some(round_trip);
+                    }
+                    continue
+                }
+                P2pMessage::PeerRecords(records) => {
+                    self.learn(key, records);
+                    continue
+                }
+                P2pMessage::Peers(_) => continue,
+                P2pMessage::Challenge(_) | P2pMessage::Init(_) | P2pMessage::InitResponse(_) => {
+                    warn!(target: "equity-p2p", "Dropping handshake message from {}", address);
+                    continue
+                }
+            };
+            match encode(&reply) {
+                Ok(frame) => {
+                    send.push(frame);
+                }
+                Err(e) => warn!(target: "equity-p2p", "Could not encode message: {}", e),
+            }
+        };
+
+        // the peer may have reconnected in the meantime
+        self.peers.lock().unwrap().remove(key, &send);
+        send.close();
+        result
+    }
+
+    /// Ends serving the peer with `key` after a frame could not be read or
+    /// decoded. A peer that sends garbage is dropped, the reason is logged by
+    /// whoever spawned `serve`.
+    fn unreadable(&self, key: VerificationKeyBytes, error: CodecError) -> Result<(), P2pError> {
+        match error {
+            CodecError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            e => {
+                if !matches!(e, CodecError::Io(_) | CodecError::Version(_)) {
+                    self.report(key, Offense::MalformedFrame);
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Sends a fresh challenge and reads the one of the peer, returning both
+async fn exchange_challenges(
+    read: &mut Reader,
+    write: &mut Writer,
+) -> Result<(Challenge, Challenge), P2pError> {
+    let ours = challenge();
+    write.write_message(&P2pMessage::Challenge(ours)).await?;
+    match read.read_handshake().await? {
+        P2pMessage::Challenge(theirs) => Ok((ours, theirs)),
+        _ => Err(P2pError::Handshake("expected a Challenge message")),
+    }
+}
+
+#[cfg(feature = "byzantine")]
+impl Network {
+    fn forge(
+        &self,
+        mut peer_map: BTreeMap<String, VerificationKey>,
+    ) -> BTreeMap<String, VerificationKey> {
+        if self.faults.forge_peer_map {
+            peer_map.insert("0.0.0.0:1".to_owned(), Credentials::new().public_key);
+        }
+        peer_map
+    }
+
+    /// Opens a connection to `address` only to send it garbage instead of an
+    /// `Init`
+    async fn send_malformed_init(&self, address: &str) {
+        if !self.faults.malformed_init {
+            return
+        }
+        if let Ok(mut stream) = TcpStream::connect(address).await {
+            let _ = stream
+                .write_all(&crate::frame(
+                    crate::FrameKind::Other,
+                    b"not an init message",
+                ))
+                .await;
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! The `Noise_XX_25519_ChaChaPoly_SHA256` handshake and transport, run by
+//! `snow`. Each node has a static X25519 key for the lifetime of the process,
+//! and binds it to its ed25519 identity by sending a signature over it as the
+//! payload of its handshake message that carries the static key.
+//!
+//! `NoiseHandshake` does no IO, the caller moves its messages over the wire.
+
+use std::sync::Arc;
+
+use ed25519_consensus::{Signature, VerificationKey};
+use equity_types::Credentials;
+use snow::{params::NoiseParams, Builder, HandshakeState, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{CodecError, P2pError};
+
+const PARAMETERS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+/// Mixed into the handshake so that only peers of this protocol complete it
+pub(crate) const PROLOGUE: &[u8] = b"equity_p2p";
+/// Domain of the signature binding a static key to an ed25519 identity
+const IDENTITY_DOMAIN: &[u8] = b"NOISE_STATIC_KEY";
+/// Longest Noise message, handshake or transport
+pub(crate) const MAX_MESSAGE_LENGTH: usize = 65535;
+const TAG_LENGTH: usize = 16;
+const KEY_LENGTH: usize = 32;
+/// ed25519 key and signature
+const IDENTITY_LENGTH: usize = 32 + 64;
+
+/// The longest plaintext that fits into one transport message
+pub(crate) const MAX_PLAINTEXT_LENGTH: usize = MAX_MESSAGE_LENGTH - TAG_LENGTH;
+
+fn parameters() -> NoiseParams {
+    PARAMETERS.parse().expect("Noise parameters are valid")
+}
+
+/// An X25519 key pair
+#[derive(Clone)]
+pub struct NoiseKeypair {
+    secret: Vec<u8>,
+    pub public: [u8; KEY_LENGTH],
+}
+
+impl NoiseKeypair {
+    pub fn generate() -> Self {
+        let keypair = Builder::new(parameters())
+            .generate_keypair()
+            .expect("the system RNG works");
+        Self {
+            secret: keypair.private,
+            public: key(&keypair.public),
+        }
+    }
+}
+
+impl std::fmt::Debug for NoiseKeypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseKeypair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+/// One direction of a connection after the handshake. The nonce only moves
+/// on once a message was sealed or opened, so a message that fails to open
+/// leaves the cipher as it was.
+pub struct CipherState {
+    transport: Arc<StatelessTransportState>,
+    /// Whether this is the sending direction
+    sending: bool,
+    nonce: u64,
+}
+
+impl CipherState {
+    /// Encrypts `plaintext` as one transport message
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CodecError> {
+        if !self.sending || plaintext.len() > MAX_PLAINTEXT_LENGTH {
+            return Err(CodecError::Encrypt)
+        }
+        let next = self.nonce.checked_add(1).ok_or(CodecError::Encrypt)?;
+        let mut message = vec![0; plaintext.len() + TAG_LENGTH];
+        let length = self
+            .transport
+            .write_message(self.nonce, plaintext, &mut message)
+            .map_err(|_| CodecError::Encrypt)?;
+        message.truncate(length);
+        self.nonce = next;
+        Ok(message)
+    }
+
+    pub fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CodecError> {
+        if self.sending {
+            return Err(CodecError::Decrypt)
+        }
+        let next = self.nonce.checked_add(1).ok_or(CodecError::Decrypt)?;
+        let mut plaintext = vec![0; ciphertext.len()];
+        let length = self
+            .transport
+            .read_message(self.nonce, ciphertext, &mut plaintext)
+            .map_err(|_| CodecError::Decrypt)?;
+        plaintext.truncate(length);
+        self.nonce = next;
+        Ok(plaintext)
+    }
+}
+
+/// One side of a `Noise_XX_25519_ChaChaPoly_SHA256` handshake. It turns
+/// payloads into handshake messages and back.
+pub struct NoiseHandshake {
+    state: HandshakeState,
+}
+
+impl NoiseHandshake {
+    pub fn new(initiator: bool, prologue: &[u8], keypair: &NoiseKeypair) -> Result<Self, P2pError> {
+        let builder = Builder::new(parameters())
+            .local_private_key(&keypair.secret)
+            .prologue(prologue);
+        let state = match initiator {
+            true => builder.build_initiator(),
+            false => builder.build_responder(),
+        }
+        .map_err(handshake_error)?;
+        Ok(Self { state })
+    }
+
+    /// The next handshake message, carrying `payload`
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, P2pError> {
+        let mut message = vec![0; MAX_MESSAGE_LENGTH];
+        let length = self
+            .state
+            .write_message(payload, &mut message)
+            .map_err(handshake_error)?;
+        message.truncate(length);
+        Ok(message)
+    }
+
+    /// The payload of the next handshake `message` from the remote side
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, P2pError> {
+        let mut payload = vec![0; message.len()];
+        let length = self
+            .state
+            .read_message(message, &mut payload)
+            .map_err(handshake_error)?;
+        payload.truncate(length);
+        Ok(payload)
+    }
+
+    /// The static key the remote side sent, once it did
+    pub fn remote_static(&self) -> Option<[u8; KEY_LENGTH]> {
+        self.state.get_remote_static().map(key)
+    }
+
+    /// The hash of everything sent and received so far, after the last
+    /// message it is the same on both sides
+    pub fn hash(&self) -> &[u8] {
+        self.state.get_handshake_hash()
+    }
+
+    /// The ciphers for sending and for receiving after the last message
+    pub fn into_transport(self) -> Result<(CipherState, CipherState), P2pError> {
+        let transport = Arc::new(
+            self.state
+                .into_stateless_transport_mode()
+                .map_err(handshake_error)?,
+        );
+        let cipher = |sending| CipherState {
+            transport: transport.clone(),
+            sending,
+            nonce: 0,
+        };
+        Ok((cipher(true), cipher(false)))
+    }
+}
+
+impl std::fmt::Debug for NoiseHandshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseHandshake")
+            .field("initiator", &self.state.is_initiator())
+            .field("finished", &self.state.is_handshake_finished())
+            .finish_non_exhaustive()
+    }
+}
+
+fn handshake_error(e: snow::Error) -> P2pError {
+    P2pError::Handshake(match e {
+        snow::Error::Input => "Noise handshake message is too long",
+        snow::Error::Decrypt => "could not decrypt Noise handshake message",
+        snow::Error::State(_) => "Noise handshake message out of turn",
+        snow::Error::Dh => "invalid Noise key",
+        _ => "Noise handshake failed",
+    })
+}
+
+/// The payload that proves `public` belongs to the ed25519 identity of
+/// `credentials`
+fn identity(credentials: &Credentials, public: &[u8; KEY_LENGTH]) -> Vec<u8> {
+    let signature = credentials
+        .private_key
+        .sign(&[IDENTITY_DOMAIN, public].concat());
+    [
+        credentials.public_key.to_bytes().as_slice(),
+        &signature.to_bytes(),
+    ]
+    .concat()
+}
+
+/// Checks an identity payload for the remote static key `public`
+fn verify_identity(payload: &[u8], public: &[u8; KEY_LENGTH]) -> Result<VerificationKey, P2pError> {
+    fn invalid<E>(_: E) -> P2pError {
+        P2pError::Handshake("invalid Noise identity")
+    }
+    if payload.len() != IDENTITY_LENGTH {
+        return Err(invalid(()))
+    }
+    let public_key = VerificationKey::try_from(&payload[..32]).map_err(invalid)?;
+    let signature = Signature::try_from(&payload[32..]).map_err(invalid)?;
+    public_key
+        .verify(&signature, &[IDENTITY_DOMAIN, public].concat())
+        .map_err(invalid)?;
+    Ok(public_key)
+}
+
+/// The sending and receiving ciphers of a finished handshake and the identity
+/// of the remote side
+pub(crate) struct Established {
+    pub send: CipherState,
+    pub receive: CipherState,
+    pub remote: VerificationKey,
+}
+
+/// Runs the handshake as the side that dialed
+pub(crate) async fn initiate<R, W>(
+    read: &mut R,
+    write: &mut W,
+    credentials: &Credentials,
+    keypair: &NoiseKeypair,
+) -> Result<Established, P2pError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut handshake = NoiseHandshake::new(true, PROLOGUE, keypair)?;
+    // -> e
+    write_noise(write, &handshake.write_message(&[])?).await?;
+    // <- e, ee, s, es
+    let payload = handshake.read_message(&read_noise(read).await?)?;
+    let remote = remote_identity(&handshake, &payload)?;
+    // -> s, se
+    let payload = identity(credentials, &keypair.public);
+    write_noise(write, &handshake.write_message(&payload)?).await?;
+    established(handshake, remote)
+}
+
+/// Runs the handshake as the side that accepted the connection
+pub(crate) async fn respond<R, W>(
+    read: &mut R,
+    write: &mut W,
+    credentials: &Credentials,
+    keypair: &NoiseKeypair,
+) -> Result<Established, P2pError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut handshake = NoiseHandshake::new(false, PROLOGUE, keypair)?;
+    // -> e
+    handshake.read_message(&read_noise(read).await?)?;
+    // <- e, ee, s, es
+    let payload = identity(credentials, &keypair.public);
+    write_noise(write, &handshake.write_message(&payload)?).await?;
+    // -> s, se
+    let payload = handshake.read_message(&read_noise(read).await?)?;
+    let remote = remote_identity(&handshake, &payload)?;
+    established(handshake, remote)
+}
+
+fn remote_identity(
+    handshake: &NoiseHandshake,
+    payload: &[u8],
+) -> Result<VerificationKey, P2pError> {
+    let remote_static = handshake
+        .remote_static()
+        .ok_or(P2pError::Handshake("Noise handshake key is missing"))?;
+    verify_identity(payload, &remote_static)
+}
+
+fn established(
+    handshake: NoiseHandshake,
+    remote: VerificationKey,
+) -> Result<Established, P2pError> {
+    let (send, receive) = handshake.into_transport()?;
+    Ok(Established {
+        send,
+        receive,
+        remote,
+    })
+}
+
+fn key(bytes: &[u8]) -> [u8; KEY_LENGTH] {
+    let mut key = [0; KEY_LENGTH];
+    key.copy_from_slice(bytes);
+    key
+}
+
+/// Noise messages go over the wire with a big endian `u16` length
+pub(crate) async fn write_noise<W: AsyncWrite + Unpin>(
+    write: &mut W,
+    message: &[u8],
+) -> Result<(), CodecError> {
+    let length = u16::try_from(message.len())
+        .map_err(|_| CodecError::TooLong(u32::try_from(message.len()).unwrap_or(u32::MAX)))?;
+    write.write_u16(length).await?;
+    write.write_all(message).await?;
+    Ok(())
+}
+
+pub(crate) async fn read_noise<R: AsyncRead + Unpin>(read: &mut R) -> Result<Vec<u8>, CodecError> {
+    let length = read.read_u16().await?;
+    let mut message = vec![0; length as usize];
+    read.read_exact(&mut message).await?;
+    Ok(message)
+}
@@ -0,0 +1,99 @@
+//! The registry of connected peers, keyed by public key. Two nodes that dial
+//! each other at the same time end up with two connections. Both then keep
+//! the one dialed by the node with the smaller key, so they agree on it
+//! without talking.
+
+use std::{collections::HashMap, time::Duration};
+
+use ed25519_consensus::{VerificationKey, VerificationKeyBytes};
+
+use crate::limits::Outbox;
+
+#[derive(Debug)]
+pub(crate) struct Peer {
+    /// Frames to write to the connection
+    pub send: Outbox,
+    pub public_key: VerificationKey,
+    /// The address the peer accepts connections on
+    pub address: String,
+    /// The protocol version agreed on in the handshake
+    pub version: u16,
+    /// Whether we dialed the connection
+    pub outbound: bool,
+    /// Whether the peer only receives messages
+    pub observer: bool,
+    pub round_trip: Option<Duration>,
+    /// Messages dropped because the peer sent faster than its rate limit
+    pub limited: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct PeerMap {
+    ours: VerificationKeyBytes,
+    peers: HashMap<VerificationKeyBytes, Peer>,
+}
+
+impl PeerMap {
+    pub fn new(ours: VerificationKeyBytes) -> Self {
+        Self {
+            ours,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Adds `peer`, replacing an earlier connection to it unless only the
+    /// earlier one was dialed by the node with the smaller key. Returns
+    /// whether the peer was added.
+    pub fn insert(&mut self, peer: Peer) -> bool {
+        let key = VerificationKeyBytes::from(peer.public_key);
+        if let Some(existing) = self.peers.get(&key) {
+            if self.preferred(existing) && !self.preferred(&peer) {
+                return false
+            }
+        }
+        self.peers.insert(key, peer);
+        true
+    }
+
+    fn preferred(&self, peer: &Peer) -> bool {
+        let key = VerificationKeyBytes::from(peer.public_key);
+        (self.ours.as_ref() < key.as_ref()) == peer.outbound
+    }
+
+    pub fn contains(&self, public_key: VerificationKeyBytes) -> bool {
+        self.peers.contains_key(&public_key)
+    }
+
+    /// The peer with `public_key` if `send` belongs to the connection kept
+    /// for it
+    pub fn current(
+        &mut self,
+        public_key: VerificationKeyBytes,
+        send: &Outbox,
+    ) -> Option<&mut Peer> {
+        self.peers
+            .get_mut(&public_key)
+            .filter(|peer| peer.send.same_outbox(send))
+    }
+
+    /// Removes the peer with `public_key` if `send` belongs to its connection
+    pub fn remove(&mut self, public_key: VerificationKeyBytes, send: &Outbox) {
+        if self.current(public_key, send).is_some() {
+            self.peers.remove(&public_key);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.values()
+    }
+
+    /// The outboxes of every peer
+    pub fn broadcast(&self) -> Vec<Outbox> {
+        self.peers.values().map(|peer| peer.send.clone()).collect()
+    }
+
+    /// The outbox of the peer with `public_key`
+    pub fn send_to(&self, public_key: VerificationKeyBytes) -> Option<Outbox> {
+        self.peers.get(&public_key).map(|peer| peer.send.clone())
+    }
+}
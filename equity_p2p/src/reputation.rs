@@ -0,0 +1,147 @@
+//! Scores of peers. Every offense costs a peer points for a while, and a peer
+//! whose score drops to the threshold is banned. Bans end at a wall clock
+//! time, so they can be kept across restarts.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use ed25519_consensus::VerificationKeyBytes;
+use equity_types::{Ban, Offense, PeerReputation};
+
+use crate::{message::now, Scoring};
+
+/// What an offense costs
+fn penalty(offense: Offense) -> i64 {
+    match offense {
+        Offense::InvalidSignature => 25,
+        Offense::MalformedFrame => 50,
+        Offense::ConflictingVote => 100,
+        Offense::Spam => 1,
+    }
+}
+
+fn count(offenses: &[(Instant, Offense)]) -> BTreeMap<Offense, u32> {
+    let mut counts = BTreeMap::new();
+    for (_, offense) in offenses {
+        *counts.entry(*offense).or_default() += 1;
+    }
+    counts
+}
+
+#[derive(Debug)]
+pub(crate) struct Reputation {
+    scoring: Scoring,
+    /// The offenses of every peer that are not forgotten yet
+    offenses: HashMap<VerificationKeyBytes, Vec<(Instant, Offense)>>,
+    bans: HashMap<VerificationKeyBytes, Ban>,
+}
+
+impl Reputation {
+    /// Starts with the bans of an earlier run that did not end yet
+    pub fn new(scoring: Scoring, bans: Vec<Ban>) -> Self {
+        let now = now();
+        Self {
+            scoring,
+            offenses: HashMap::new(),
+            bans: bans
+                .into_iter()
+                .filter(|ban| ban.until > now)
+                .map(|ban| (ban.public_key, ban))
+                .collect(),
+        }
+    }
+
+    /// Records an offense of the peer with `public_key`, returning its ban if
+    /// its score dropped to the threshold
+    pub fn report(&mut self, public_key: VerificationKeyBytes, offense: Offense) -> Option<Ban> {
+        let memory = self.scoring.memory;
+        let offenses = self.offenses.entry(public_key).or_default();
+        offenses.retain(|(at, _)| at.elapsed() < memory);
+        offenses.push((Instant::now(), offense));
+        if self.score(public_key) > self.scoring.threshold {
+            return None
+        }
+
+        // the peer starts over once the ban ends
+        let offenses = self.offenses.remove(&public_key).unwrap_or_default();
+        let ban = Ban {
+            public_key,
+            until: now().saturating_add(self.scoring.ban.as_millis() as u64),
+            reasons: count(&offenses),
+        };
+        self.bans.insert(public_key, ban.clone());
+        Some(ban)
+    }
+
+    /// The ban of the peer with `public_key` if it did not end yet
+    pub fn ban(&mut self, public_key: VerificationKeyBytes) -> Option<&Ban> {
+        if self
+            .bans
+            .get(&public_key)
+            .is_some_and(|ban| ban.until <= now())
+        {
+            self.bans.remove(&public_key);
+        }
+        self.bans.get(&public_key)
+    }
+
+    /// Zero minus the penalties of the offenses that are not forgotten yet
+    pub fn score(&self, public_key: VerificationKeyBytes) -> i64 {
+        self.recent(public_key)
+            .iter()
+            .map(|(_, offense)| -penalty(*offense))
+            .sum()
+    }
+
+    fn recent(&self, public_key: VerificationKeyBytes) -> Vec<(Instant, Offense)> {
+        self.offenses
+            .get(&public_key)
+            .into_iter()
+            .flatten()
+            .filter(|(at, _)| at.elapsed() < self.scoring.memory)
+            .copied()
+            .collect()
+    }
+
+    /// The bans that did not end yet
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = now();
+        self.bans
+            .values()
+            .filter(|ban| ban.until > now)
+            .cloned()
+            .collect()
+    }
+
+    /// Every peer with recent offenses or a ban
+    pub fn reputations(&self) -> Vec<PeerReputation> {
+        let mut reputations: BTreeMap<_, _> = self
+            .offenses
+            .keys()
+            .map(|public_key| {
+                (public_key.to_bytes(), PeerReputation {
+                    public_key: *public_key,
+                    score: self.score(*public_key),
+                    offenses: count(&self.recent(*public_key)),
+                    ban: None,
+                })
+            })
+            .filter(|(_, reputation)| !reputation.offenses.is_empty())
+            .collect();
+        for ban in self.bans() {
+            let public_key = ban.public_key;
+            reputations
+                .entry(public_key.to_bytes())
+                .or_insert_with(|| PeerReputation {
+                    public_key,
+                    score: 0,
+                    offenses: BTreeMap::new(),
+                    ban: None,
+                })
+                .ban = Some(ban);
+        }
+        reputations.into_values().collect()
+    }
+}
@@ -0,0 +1,98 @@
+//! Consensus messages are relayed, so the same frame arrives from several
+//! peers. The digests of recent consensus frames are remembered so repeats
+//! are dropped before they are decoded and verified. A digest is only
+//! remembered once its frame passed the checks of the network and is handed
+//! to consensus, so a frame we dropped can't make us drop its copies from
+//! other peers. Votes and subscriptions only count for the peer that sent
+//! them, so their digests cover the sender as well. Otherwise a peer relaying
+//! a vote first would make us drop the copy of its signer.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Instant,
+};
+
+use ed25519_consensus::VerificationKeyBytes;
+use equity_types::DedupStats;
+use sha2::{Digest, Sha256};
+
+use crate::{Dedup, FrameKind};
+
+#[derive(Debug)]
+pub(crate) struct SeenCache {
+    dedup: Dedup,
+    /// Digests in the order they were first seen
+    order: VecDeque<(Instant, [u8; 32])>,
+    digests: HashSet<[u8; 32]>,
+    hits: u64,
+    misses: u64,
+}
+
+impl SeenCache {
+    pub fn new(dedup: Dedup) -> Self {
+        Self {
+            dedup,
+            order: VecDeque::new(),
+            digests: HashSet::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The digest of the frame from the peer with the `from` key, without
+    /// its length prefix, if its header says it holds a consensus message.
+    /// Other frames are never repeats. A frame whose header lies fails to
+    /// decode, so its digest is never remembered.
+    pub fn digest(from: VerificationKeyBytes, frame: &[u8]) -> Option<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        match FrameKind::read(frame)? {
+            FrameKind::Other => return None,
+            FrameKind::Consensus => (),
+            FrameKind::PerSender => hasher.update(from),
+        }
+        hasher.update(frame);
+        Some(hasher.finalize().into())
+    }
+
+    /// Whether a frame with the digest was handed to consensus recently
+    pub fn contains(&mut self, digest: &[u8; 32]) -> bool {
+        self.expire();
+        if self.digests.contains(digest) {
+            self.hits += 1;
+            return true
+        }
+        self.misses += 1;
+        false
+    }
+
+    /// Remembers the digest of a frame handed to consensus
+    pub fn insert(&mut self, digest: [u8; 32]) {
+        if !self.digests.insert(digest) {
+            return
+        }
+        if self.order.len() >= self.dedup.capacity {
+            if let Some((_, oldest)) = self.order.pop_front() {
+                self.digests.remove(&oldest);
+            }
+        }
+        self.order.push_back((Instant::now(), digest));
+    }
+
+    fn expire(&mut self) {
+        while let Some((seen, digest)) = self.order.front() {
+            if seen.elapsed() < self.dedup.ttl {
+                break
+            }
+            self.digests.remove(digest);
+            self.order.pop_front();
+        }
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        DedupStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.order.len(),
+        }
+    }
+}
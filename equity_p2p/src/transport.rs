@@ -0,0 +1,160 @@
+//! What codec frames travel in. With Noise every frame is cut into chunks
+//! that fit a Noise transport message, and each chunk is sent encrypted with
+//! a `u16` length.
+
+use std::{fmt, str::FromStr};
+
+use ed25519_consensus::VerificationKey;
+use equity_types::Credentials;
+use thiserror::Error;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+use crate::{
+    codec::{frame_end, read_frame},
+    decode, encode,
+    noise::{self, CipherState, NoiseKeypair, MAX_PLAINTEXT_LENGTH},
+    CodecError, P2pError, P2pMessage, MAX_FRAME_LENGTH, MAX_HANDSHAKE_FRAME_LENGTH,
+};
+
+/// How peer connections are protected, all nodes of a network have to agree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// Encrypted and authenticated with `Noise_XX_25519_ChaChaPoly_SHA256`
+    #[default]
+    Noise,
+    /// Frames go over TCP as they are, only for local debugging
+    Plaintext,
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("Unknown transport `{0}`")]
+pub struct ParseTransportError(String);
+
+impl FromStr for Transport {
+    type Err = ParseTransportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "noise" => Ok(Self::Noise),
+            "plaintext" => Ok(Self::Plaintext),
+            _ => Err(ParseTransportError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Noise => write!(f, "noise"),
+            Self::Plaintext => write!(f, "plaintext"),
+        }
+    }
+}
+
+pub(crate) enum Reader {
+    Plaintext(OwnedReadHalf),
+    Noise {
+        read: OwnedReadHalf,
+        cipher: CipherState,
+        /// Decrypted bytes that do not make up a whole frame yet
+        buffer: Vec<u8>,
+    },
+}
+
+pub(crate) enum Writer {
+    Plaintext(OwnedWriteHalf),
+    Noise {
+        write: OwnedWriteHalf,
+        cipher: CipherState,
+    },
+}
+
+/// Runs the handshake of `transport` on `stream`. With Noise the ed25519 key
+/// the peer proved to own comes along.
+pub(crate) async fn secure(
+    stream: TcpStream,
+    transport: Transport,
+    initiator: bool,
+    credentials: &Credentials,
+    keypair: &NoiseKeypair,
+) -> Result<(Reader, Writer, Option<VerificationKey>), P2pError> {
+    let (mut read, mut write) = stream.into_split();
+    if transport == Transport::Plaintext {
+        return Ok((Reader::Plaintext(read), Writer::Plaintext(write), None))
+    }
+
+    let established = if initiator {
+        noise::initiate(&mut read, &mut write, credentials, keypair).await?
+    } else {
+        noise::respond(&mut read, &mut write, credentials, keypair).await?
+    };
+    let reader = Reader::Noise {
+        read,
+        cipher: established.receive,
+        buffer: vec![],
+    };
+    let writer = Writer::Noise {
+        write,
+        cipher: established.send,
+    };
+    Ok((reader, writer, Some(established.remote)))
+}
+
+impl Reader {
+    /// Reads a message of the handshake, whose frames are limited to
+    /// `MAX_HANDSHAKE_FRAME_LENGTH`
+    pub async fn read_handshake(&mut self) -> Result<P2pMessage, CodecError> {
+        decode(&self.read_frame_within(MAX_HANDSHAKE_FRAME_LENGTH).await?)
+    }
+
+    /// Reads a frame without decoding it, the length prefix is left out
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, CodecError> {
+        self.read_frame_within(MAX_FRAME_LENGTH).await
+    }
+
+    async fn read_frame_within(&mut self, limit: u32) -> Result<Vec<u8>, CodecError> {
+        let (read, cipher, buffer) = match self {
+            Self::Plaintext(read) => return read_frame(read, limit).await,
+            Self::Noise {
+                read,
+                cipher,
+                buffer,
+            } => (read, cipher, buffer),
+        };
+
+        loop {
+            if let Some(end) = frame_end(buffer, limit)? {
+                let frame = buffer[4..end].to_vec();
+                buffer.drain(..end);
+                return Ok(frame)
+            }
+            let chunk = cipher.open(&noise::read_noise(read).await?)?;
+            buffer.extend_from_slice(&chunk);
+        }
+    }
+}
+
+impl Writer {
+    /// Writes a frame made by the codec
+    pub async fn write_frame(&mut self, frame: &[u8]) -> Result<(), CodecError> {
+        match self {
+            Self::Plaintext(write) => write.write_all(frame).await?,
+            Self::Noise { write, cipher } => {
+                for chunk in frame.chunks(MAX_PLAINTEXT_LENGTH) {
+                    noise::write_noise(write, &cipher.seal(chunk)?).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn write_message(&mut self, message: &P2pMessage) -> Result<(), CodecError> {
+        self.write_frame(&encode(message)?).await
+    }
+}
@@ -0,0 +1,82 @@
+//! A database file kept by redb, with every entry in a single table of raw
+//! keys and values.
+
+use std::{io, path::Path};
+
+use redb::{Database, TableDefinition};
+
+use crate::{DatabaseResult, EquityStorage};
+
+const ENTRIES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("entries");
+
+/// Keeps the entries in a redb database file. Every `set` and `write` is a
+/// transaction of its own that only returns once it is committed and synced
+/// to disk, so a value that was set survives a crash of the process or of
+/// the machine and a batch is kept entirely or not at all. The file can only
+/// be open once at a time.
+#[derive(Debug)]
+pub struct FileDb {
+    database: Database,
+}
+
+impl FileDb {
+    /// Opens the database at `path`, creating it if it does not exist
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let database = Database::create(path).map_err(error)?;
+        // the table exists from here on, so reads can't miss it
+        let transaction = database.begin_write().map_err(error)?;
+        transaction.open_table(ENTRIES).map_err(error)?;
+        transaction.commit().map_err(error)?;
+        Ok(Self { database })
+    }
+
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let transaction = self.database.begin_read().map_err(error)?;
+        let table = transaction.open_table(ENTRIES).map_err(error)?;
+        let value = table.get(key).map_err(error)?;
+        Ok(value.map(|value| value.value().to_vec()))
+    }
+
+    /// Sets the `entries` in one transaction, returning the value the first
+    /// of them replaced
+    fn write(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> io::Result<Option<Vec<u8>>> {
+        let transaction = self.database.begin_write().map_err(error)?;
+        let mut previous = None;
+        {
+            let mut table = transaction.open_table(ENTRIES).map_err(error)?;
+            for (i, (key, value)) in entries.iter().enumerate() {
+                let replaced = table
+                    .insert(key.as_slice(), value.as_slice())
+                    .map_err(error)?;
+                if i == 0 {
+                    previous = replaced.map(|value| value.value().to_vec());
+                }
+            }
+        }
+        transaction.commit().map_err(error)?;
+        Ok(previous)
+    }
+}
+
+impl EquityStorage for FileDb {
+    fn get(&self, key: &[u8]) -> DatabaseResult<Option<Vec<u8>>> {
+        self.get(key).map_err(serde_json::Error::io)
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> DatabaseResult<Option<Vec<u8>>> {
+        self.write(&[(key, value)]).map_err(serde_json::Error::io)
+    }
+
+    fn write(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> DatabaseResult<()> {
+        if entries.is_empty() {
+            return Ok(())
+        }
+        self.write(&entries)
+            .map(|_| ())
+            .map_err(serde_json::Error::io)
+    }
+}
+
+fn error(e: impl Into<redb::Error>) -> io::Error {
+    io::Error::other(e.into())
+}
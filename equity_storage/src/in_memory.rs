@@ -24,4 +24,12 @@ impl EquityStorage for InMemoryDb {
             .expect("Lock is Poisoned")
             .insert(key, value))
     }
+
+    fn write(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> crate::DatabaseResult<()> {
+        self.data_holder
+            .lock()
+            .expect("Lock is Poisoned")
+            .extend(entries);
+        Ok(())
+    }
 }
@@ -1,18 +1,56 @@
+mod file;
 mod in_memory;
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::*;
 
+pub use crate::file::FileDb;
+
+/// Where an `EquityDatabase` keeps its data
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DatabaseType {
+    /// Lost when the process exits
     InMemory,
+    /// A redb database file at the path, created if it does not exist. See
+    /// `FileDb` for its durability.
+    File(PathBuf),
 }
 
 pub trait EquityStorage: Debug + Send + Sync {
     fn get(&self, key: &[u8]) -> DatabaseResult<Option<Vec<u8>>>;
     /// Sets `key` and corresponding `value` into the database. If an entry
-    /// with `key` already existed, the previous value is returned
+    /// with `key` already existed, the previous value is returned. Whether
+    /// the value survives a crash once this returns is up to the backend.
     fn set(&self, key: Vec<u8>, value: Vec<u8>) -> DatabaseResult<Option<Vec<u8>>>;
+    /// Sets all of the `entries` or, if this fails, none of them. Later
+    /// entries win over earlier ones with the same key.
+    fn write(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> DatabaseResult<()>;
+}
+
+/// Entries that are written to an `EquityDatabase` together, see
+/// `EquityDatabase::write`
+#[derive(Debug, Default, Clone)]
+pub struct Batch {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Batch {
+    /// Adds `key` and `value`, serialized the same way as in
+    /// `EquityDatabase::set`
+    pub fn set<K: Serialize, V: Serialize>(&mut self, key: K, value: V) -> Result<()> {
+        self.entries
+            .push((serde_json::to_vec(&key)?, serde_json::to_vec(&value)?));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -40,8 +78,23 @@ impl EquityDatabase {
         }
     }
 
-    pub fn get<V: DeserializeOwned + Debug>(&self, key: &[u8]) -> Result<Option<V>> {
-        match self.data.get(key) {
+    /// Opens or creates the database in the file at `path`
+    pub fn file(path: &Path) -> Result<Self> {
+        Ok(Self {
+            data: Arc::new(FileDb::open(path).map_err(serde_json::Error::io)?),
+        })
+    }
+
+    pub fn open(database: &DatabaseType) -> Result<Self> {
+        match database {
+            DatabaseType::InMemory => Ok(Self::in_memory()),
+            DatabaseType::File(path) => Self::file(path),
+        }
+    }
+
+    /// Gets the value at `key`, which is serialized the same way as in `set`
+    pub fn get<K: Serialize, V: DeserializeOwned + Debug>(&self, key: K) -> Result<Option<V>> {
+        match self.data.get(&serde_json::to_vec(&key)?) {
             Ok(Some(bytes)) => {
                 let val: V = serde_json::from_slice(&bytes)?;
                 Ok(Some(val))
@@ -51,6 +104,8 @@ impl EquityDatabase {
         }
     }
 
+    /// Sets `key` to `value` and returns the previous value. With a file
+    /// database the value is on disk once this returns.
     pub fn set<K: Serialize, V: Serialize + DeserializeOwned>(
         &self,
         key: K,
@@ -68,4 +123,9 @@ impl EquityDatabase {
             Err(e) => Err(e),
         }
     }
+
+    /// Sets every entry of `batch`, or none of them if this fails
+    pub fn write(&self, batch: Batch) -> Result<()> {
+        self.data.write(batch.entries)
+    }
 }
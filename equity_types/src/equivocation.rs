@@ -0,0 +1,104 @@
+use ed25519_consensus::VerificationKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{hash, EquityError, FullMessage, ValidatorSet, Vote, VoteKind};
+
+/// Who equivocated and how
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Equivocation {
+    /// The sender signed two different bodies with the same nonce
+    Sender,
+    /// A validator echoed two different messages of the same nonce
+    Echo,
+    /// A validator sent READY for two different messages of the same nonce
+    Ready,
+}
+
+/// Evidence that a key signed two conflicting things. It can be checked by
+/// anyone, without trusting the node that found it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EquivocationProof {
+    pub kind: Equivocation,
+    pub first: FullMessage,
+    pub second: FullMessage,
+    /// The votes for `first` and `second`, empty for `Equivocation::Sender`
+    pub votes: Vec<Vote>,
+}
+
+impl EquivocationProof {
+    pub fn sender(first: FullMessage, second: FullMessage) -> Self {
+        Self {
+            kind: Equivocation::Sender,
+            first,
+            second,
+            votes: vec![],
+        }
+    }
+
+    /// Conflicting ECHO or READY votes, each given with the message it is for
+    pub fn votes(
+        kind: Equivocation,
+        first: (FullMessage, Vote),
+        second: (FullMessage, Vote),
+    ) -> Self {
+        Self {
+            kind,
+            first: first.0,
+            second: second.0,
+            votes: vec![first.1, second.1],
+        }
+    }
+
+    /// The key that equivocated
+    pub fn offender(&self) -> VerificationKey {
+        match (self.kind, self.votes.first()) {
+            (Equivocation::Sender, _) | (_, None) => self.first.body.public_key,
+            (_, Some(vote)) => vote.public_key,
+        }
+    }
+
+    /// Identifies the equivocation independent of the order of the messages
+    pub fn id(&self) -> String {
+        let mut hashes = [&self.first.hash, &self.second.hash];
+        hashes.sort_unstable();
+        hash(&format!(
+            "{:?}:{:?}:{}:{}",
+            self.kind,
+            self.offender().as_bytes(),
+            hashes[0],
+            hashes[1]
+        ))
+    }
+
+    /// Checks the proof, its votes have to be of the chain and epoch of
+    /// `validators`
+    pub fn verify(&self, validators: &ValidatorSet) -> Result<(), EquityError> {
+        let (first, second) = (&self.first, &self.second);
+        if first.body.public_key != second.body.public_key || first.body.nonce != second.body.nonce
+        {
+            return Err(EquityError::InvalidProof(
+                "the messages are for different nonces",
+            ))
+        }
+        if first.hash == second.hash {
+            return Err(EquityError::InvalidProof("the messages are the same"))
+        }
+        first.verify()?;
+        second.verify()?;
+
+        let kind = match self.kind {
+            Equivocation::Sender if self.votes.is_empty() => return Ok(()),
+            Equivocation::Sender => return Err(EquityError::InvalidProof("unexpected votes")),
+            Equivocation::Echo => VoteKind::Echo,
+            Equivocation::Ready => VoteKind::Ready,
+        };
+        match self.votes.as_slice() {
+            [a, b] if a.public_key == b.public_key => {
+                a.verify(kind, validators, &first.hash)?;
+                b.verify(kind, validators, &second.hash)?;
+                Ok(())
+            }
+            _ => Err(EquityError::InvalidProof("expected two votes of one key")),
+        }
+    }
+}
@@ -1,17 +1,22 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    sync::{Arc, Mutex},
-};
+use std::{collections::BTreeMap, ops::Range, time::Duration};
+
+mod equivocation;
+mod reputation;
+mod validators;
 
 pub use borsh;
 use borsh::{BorshDeserialize, BorshSerialize};
 use derive_alias::derive_alias;
 use ed25519_consensus::{Signature, SigningKey, VerificationKey};
+pub use equivocation::*;
 use rand::thread_rng;
+pub use reputation::*;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha512};
-use tokio::sync::mpsc::Sender;
-use tungstenite::Message;
+pub use validators::*;
+
+/// The chain of nodes that are not given one
+pub const DEFAULT_CHAIN_ID: &str = "equity";
 
 // TODO common derive macro
 
@@ -39,11 +44,70 @@ pub struct EquityAddressResponse {
 }
 }
 
-derive_common! {
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct PostTransactionResponse {
     pub success: bool,
     pub msg: String,
+    /// Proof of delivery, present when the transaction was delivered
+    pub certificate: Option<QuorumCertificate>,
+    /// Present when the transaction was applied
+    pub receipt: Option<Receipt>,
 }
+
+/// A delivered transaction that is held back instead of being applied
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct WaitingTransaction {
+    pub hash: String,
+    pub public_key: VerificationKey,
+    pub nonce: u64,
+    /// Lower nonces of the account that were not delivered yet
+    pub missing_nonces: Vec<Range<u64>>,
+    /// Dependencies that were not applied yet
+    pub missing_dependencies: Vec<String>,
+}
+
+/// Liveness of a peer as seen by the node
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerStatus {
+    pub public_key: VerificationKey,
+    /// False while the node tries to reconnect
+    pub connected: bool,
+    /// The protocol version agreed on with the peer, known while connected
+    pub version: Option<u16>,
+    /// Connected to a permissioned node without being allowlisted, only
+    /// receives messages
+    pub observer: bool,
+    /// Of the last answered ping
+    pub round_trip: Option<Duration>,
+    /// Reconnection attempts that failed since the connection dropped
+    pub failed_attempts: u32,
+    /// Frames waiting to be written to the peer
+    pub queued: usize,
+    /// Frames to the peer dropped because its queue was full
+    pub dropped: u64,
+    /// Messages from the peer dropped because of its rate limit
+    pub limited: u64,
+}
+
+/// How well the cache of seen consensus messages of a node catches repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub struct DedupStats {
+    /// Repeated messages that were dropped
+    pub hits: u64,
+    /// Messages seen for the first time
+    pub misses: u64,
+    /// Messages remembered right now
+    pub entries: usize,
+}
+
+impl DedupStats {
+    /// The share of consensus messages that were repeats
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
 }
 
 derive_common! {
@@ -64,22 +128,198 @@ pub struct Body {
     pub public_key: VerificationKey,
     pub nonce: u64,
     pub keys_values: BTreeMap<u64, u64>,
+    /// Hashes of transactions, usually of other accounts, that have to be
+    /// applied before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum EquityError {
-    #[error("An api server error occurred {0}")]
-    ApiServer(#[from] hyper::Error),
+impl FullMessage {
+    /// Hashes and signs `body` with `credentials`
+    pub fn sign(body: Body, credentials: &Credentials) -> Result<Self, EquityError> {
+        let (hash, signature) = credentials.hash_sign(&serde_json::to_string(&body)?);
+        Ok(Self {
+            body,
+            hash,
+            signature,
+        })
+    }
+
+    /// Checks that `hash` is the digest of `body` and that `signature` was made
+    /// over it by the `public_key` of the body
+    pub fn verify(&self) -> Result<(), EquityError> {
+        let body_string = serde_json::to_string(&self.body)?;
+        if hash(&body_string) != self.hash {
+            return Err(EquityError::HashMismatch)
+        }
+        self.body
+            .public_key
+            .verify(&self.signature, self.hash.as_bytes())
+            .map_err(Into::into)
+    }
 }
 
-#[derive(Debug)]
-pub struct Peer {
-    pub send: Sender<Message>,
+/// What a `Vote` is for, part of the signed payload so that a signature for one
+/// purpose can't pass as one for another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum VoteKind {
+    Echo,
+    Ready,
+    Reconfiguration,
+}
+
+/// A node's signature over a hash, made for the chain and epoch of a
+/// `ValidatorSet` so that it can't be passed off as one of another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Vote {
     pub public_key: VerificationKey,
-    pub peer_map: HashMap<String, VerificationKey>,
+    pub signature: Signature,
 }
 
-pub type PeerMap = Arc<Mutex<HashMap<String, Peer>>>;
+impl Vote {
+    pub fn sign(
+        kind: VoteKind,
+        validators: &ValidatorSet,
+        hash: &str,
+        credentials: &Credentials,
+    ) -> Self {
+        Self {
+            public_key: credentials.public_key,
+            signature: credentials
+                .private_key
+                .sign(&Self::payload(kind, validators, hash)),
+        }
+    }
+
+    pub fn verify(
+        &self,
+        kind: VoteKind,
+        validators: &ValidatorSet,
+        hash: &str,
+    ) -> Result<(), EquityError> {
+        self.public_key
+            .verify(&self.signature, &Self::payload(kind, validators, hash))
+            .map_err(Into::into)
+    }
+
+    fn payload(kind: VoteKind, validators: &ValidatorSet, hash: &str) -> Vec<u8> {
+        let prefix = match kind {
+            VoteKind::Echo => "ECHO",
+            VoteKind::Ready => "READY",
+            VoteKind::Reconfiguration => "RECONFIGURATION",
+        };
+        // the chain id is the only part that can hold a ':', it goes last
+        format!(
+            "{}:{}:{}:{}",
+            prefix, validators.epoch, hash, validators.chain_id
+        )
+        .into_bytes()
+    }
+}
+
+/// The READY votes of a quorum for a delivered message. Anyone who knows the
+/// validators of the epoch can check that the message was reliably broadcast
+/// without trusting the node that handed it out.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QuorumCertificate {
+    pub hash: String,
+    pub epoch: u64,
+    pub votes: Vec<Vote>,
+}
+
+impl QuorumCertificate {
+    pub fn verify(&self, validators: &ValidatorSet) -> Result<(), EquityError> {
+        if self.epoch != validators.epoch {
+            return Err(EquityError::EpochMismatch {
+                expected: validators.epoch,
+                found: self.epoch,
+            })
+        }
+        validators.verify_quorum(&self.votes, VoteKind::Ready, &self.hash)
+    }
+}
+
+/// What is stored for a delivered transaction
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TxRecord {
+    pub message: FullMessage,
+    pub certificate: QuorumCertificate,
+    pub receipt: Receipt,
+}
+
+/// The outcome of applying a delivered message to the state
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Receipt {
+    pub hash: String,
+    /// Root of the sending account's state after the message
+    pub account_root: String,
+    /// Root of the whole state after the message
+    pub state_root: String,
+}
+
+/// Messages of the SEND/ECHO/READY phases of Bracha's reliable broadcast. Every
+/// phase carries the full message so that a node which missed the SEND can
+/// still deliver.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ConsensusMessage {
+    Send(FullMessage),
+    Echo(FullMessage, Box<Vote>),
+    Ready(FullMessage, Box<Vote>),
+    Reconfigure(Box<Reconfiguration>),
+    /// Gossip of evidence against a sender or validator
+    Equivocation(Box<EquivocationProof>),
+    /// A message relayed to a random sample of peers, used instead of SEND by
+    /// the probabilistic broadcast
+    Gossip(FullMessage),
+    /// Asks the receiver for its ECHO or READY of a broadcast instance, used by
+    /// the probabilistic broadcast
+    Subscribe(Subscription),
+}
+
+/// The account and nonce of a broadcast instance and the votes wanted for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Subscription {
+    pub kind: VoteKind,
+    pub public_key: VerificationKey,
+    pub nonce: u64,
+}
+
+impl ConsensusMessage {
+    /// The message being broadcast, if this is part of a broadcast
+    pub fn message(&self) -> Option<&FullMessage> {
+        match self {
+            ConsensusMessage::Send(message)
+            | ConsensusMessage::Gossip(message)
+            | ConsensusMessage::Echo(message, _)
+            | ConsensusMessage::Ready(message, _) => Some(message),
+            ConsensusMessage::Reconfigure(_)
+            | ConsensusMessage::Equivocation(_)
+            | ConsensusMessage::Subscribe(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EquityError {
+    #[error("An api server error occurred {0}")]
+    ApiServer(#[from] hyper::Error),
+    #[error("Signature verification failed {0}")]
+    Signature(#[from] ed25519_consensus::Error),
+    #[error("Message hash does not match its body")]
+    HashMismatch,
+    #[error("Serialization failed {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Vote from a key that is not a validator")]
+    UnknownValidator,
+    #[error("Not enough voting weight for a quorum")]
+    NoQuorum,
+    #[error("Expected epoch {expected}, found {found}")]
+    EpochMismatch { expected: u64, found: u64 },
+    #[error("Expected chain {expected}, found {found}")]
+    ChainMismatch { expected: String, found: String },
+    #[error("Invalid equivocation proof, {0}")]
+    InvalidProof(&'static str),
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Credentials {
@@ -106,18 +346,35 @@ impl Credentials {
         }
     }
 
+    /// Credentials derived from `seed`, for reproducible keys in tests
+    pub fn from_seed(seed: [u8; 32]) -> Credentials {
+        let sk = SigningKey::from(seed);
+        let vk = VerificationKey::from(&sk);
+
+        Self {
+            private_key: sk,
+            public_key: vk,
+            nonce: 1,
+        }
+    }
+
     pub fn hash_sign(&self, message: &str) -> (String, Signature) {
         let private_key = self.private_key.clone();
 
         // Hash + Signature operation may be considered blocking
 
-        let mut digest: Sha512 = Sha512::new();
-        digest.update(message);
-
-        let digest_string: String = format!("{:X}", digest.clone().finalize());
+        let digest_string = hash(message);
 
         let signature: Signature = private_key.sign(digest_string.as_bytes());
 
         (digest_string, signature)
     }
 }
+
+/// The uppercase hex Sha512 digest used for message hashes
+pub fn hash(message: &str) -> String {
+    let mut digest: Sha512 = Sha512::new();
+    digest.update(message);
+
+    format!("{:X}", digest.finalize())
+}
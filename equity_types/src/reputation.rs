@@ -0,0 +1,39 @@
+use std::collections::BTreeMap;
+
+use ed25519_consensus::VerificationKeyBytes;
+use serde::{Deserialize, Serialize};
+
+/// Why a peer lost score
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum Offense {
+    /// A message, vote or peer record whose signature does not verify
+    InvalidSignature,
+    /// A frame that does not decode
+    MalformedFrame,
+    /// Two different votes of the same validator for the same nonce
+    ConflictingVote,
+    /// Messages over the rate limit, counted once per window of them
+    Spam,
+}
+
+/// A peer that may not connect until `until`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Ban {
+    pub public_key: VerificationKeyBytes,
+    /// Milliseconds since the Unix epoch
+    pub until: u64,
+    /// The offenses that brought the peer below the threshold, with how often
+    /// they happened
+    pub reasons: BTreeMap<Offense, u32>,
+}
+
+/// What a node holds against a peer
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerReputation {
+    pub public_key: VerificationKeyBytes,
+    /// Starts at zero and drops with every offense, recent offenses only
+    pub score: i64,
+    /// The recent offenses, with how often they happened
+    pub offenses: BTreeMap<Offense, u32>,
+    pub ban: Option<Ban>,
+}
@@ -0,0 +1,152 @@
+use ed25519_consensus::{VerificationKey, VerificationKeyBytes};
+use serde::{Deserialize, Serialize};
+
+use crate::{hash, Credentials, EquityError, Vote, VoteKind, DEFAULT_CHAIN_ID};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Validator {
+    pub public_key: VerificationKey,
+    pub weight: u64,
+}
+
+/// The validators of an epoch and their voting weights. Quorums are measured in
+/// weight, tolerating faulty validators with up to `faulty()` weight in total.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ValidatorSet {
+    /// The chain the validators vote for, votes of one chain are no good on
+    /// another
+    #[serde(default = "default_chain_id")]
+    pub chain_id: String,
+    pub epoch: u64,
+    pub validators: Vec<Validator>,
+}
+
+fn default_chain_id() -> String {
+    DEFAULT_CHAIN_ID.to_owned()
+}
+
+impl ValidatorSet {
+    /// A set where every key has a weight of 1
+    pub fn equal_weight(epoch: u64, keys: impl IntoIterator<Item = VerificationKey>) -> Self {
+        Self {
+            chain_id: default_chain_id(),
+            epoch,
+            validators: keys
+                .into_iter()
+                .map(|public_key| Validator {
+                    public_key,
+                    weight: 1,
+                })
+                .collect(),
+        }
+    }
+
+    /// The weight of `public_key`, 0 if it is not a validator
+    pub fn weight(&self, public_key: VerificationKeyBytes) -> u64 {
+        self.validators
+            .iter()
+            .filter(|validator| VerificationKeyBytes::from(validator.public_key) == public_key)
+            .map(|validator| validator.weight)
+            .sum()
+    }
+
+    pub fn contains(&self, public_key: VerificationKeyBytes) -> bool {
+        self.weight(public_key) > 0
+    }
+
+    pub fn total_weight(&self) -> u64 {
+        self.validators
+            .iter()
+            .map(|validator| validator.weight)
+            .sum()
+    }
+
+    /// The most weight that can be faulty, `f = (total - 1) / 3`
+    pub fn faulty(&self) -> u64 {
+        self.total_weight().saturating_sub(1) / 3
+    }
+
+    /// The weight of `2f + 1`
+    pub fn quorum(&self) -> u64 {
+        2 * self.faulty() + 1
+    }
+
+    pub fn hash(&self) -> Result<String, EquityError> {
+        Ok(hash(&serde_json::to_string(self)?))
+    }
+
+    /// Checks that `votes` of `kind` for `hash` are from validators and carry
+    /// at least a quorum of weight
+    pub fn verify_quorum(
+        &self,
+        votes: &[Vote],
+        kind: VoteKind,
+        hash: &str,
+    ) -> Result<(), EquityError> {
+        let mut signers: Vec<VerificationKeyBytes> = vec![];
+        for vote in votes {
+            if !self.contains(vote.public_key.into()) {
+                return Err(EquityError::UnknownValidator)
+            }
+            vote.verify(kind, self, hash)?;
+            signers.push(vote.public_key.into());
+        }
+        signers.sort_unstable();
+        signers.dedup();
+
+        let weight: u64 = signers.into_iter().map(|signer| self.weight(signer)).sum();
+        if weight < self.quorum() {
+            return Err(EquityError::NoQuorum)
+        }
+        Ok(())
+    }
+}
+
+/// Moves the network to the `validators` of the next epoch. It is signed by the
+/// validators of the current epoch and takes effect once it carries a quorum of
+/// their weight. A node that missed an epoch can't verify later ones.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Reconfiguration {
+    pub validators: ValidatorSet,
+    pub votes: Vec<Vote>,
+}
+
+impl Reconfiguration {
+    /// The vote of `credentials`, a validator of `current`, for moving to
+    /// `validators`
+    pub fn sign(
+        current: &ValidatorSet,
+        validators: &ValidatorSet,
+        credentials: &Credentials,
+    ) -> Result<Vote, EquityError> {
+        Ok(Vote::sign(
+            VoteKind::Reconfiguration,
+            current,
+            &validators.hash()?,
+            credentials,
+        ))
+    }
+
+    pub fn verify(&self, current: &ValidatorSet) -> Result<(), EquityError> {
+        if self.validators.chain_id != current.chain_id {
+            return Err(EquityError::ChainMismatch {
+                expected: current.chain_id.clone(),
+                found: self.validators.chain_id.clone(),
+            })
+        }
+        if self.validators.epoch != current.epoch + 1 {
+            return Err(EquityError::EpochMismatch {
+                expected: current.epoch + 1,
+                found: self.validators.epoch,
+            })
+        }
+        if self.validators.total_weight() == 0 {
+            return Err(EquityError::NoQuorum)
+        }
+        current.verify_quorum(
+            &self.votes,
+            VoteKind::Reconfiguration,
+            &self.validators.hash()?,
+        )
+    }
+}
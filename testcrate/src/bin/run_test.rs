@@ -1,4 +1,7 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use clap::Parser;
 use common::{
@@ -6,6 +9,7 @@ use common::{
     container_network::{Container, ContainerNetwork},
     test_mode::TestMode,
 };
+use equity_types::{Credentials, ValidatorSet};
 
 /// `docker-compose` is not programmable among other problems. What this does is
 /// create an `--internal` network of containers using locally built binaries.
@@ -51,19 +55,19 @@ async fn main() {
     let log_dir = base_dir.join("testcrate/logs");
     assert_dir_exists(&log_dir).unwrap();
 
-    println!("running `cargo build --release --target {}`", args.target);
-    ComplexCommand::new(
-        "cargo",
-        &["build", "--release", "--target", &args.target],
-        ci,
-    )
-    .unwrap()
-    .stderr_to_file(&log_dir.join("cmd_cargo_build_err.log"))
-    .await
-    .unwrap()
-    .wait()
-    .await
-    .unwrap();
+    let mut build = vec!["build", "--release", "--target", &args.target];
+    if args.test_mode == TestMode::Byzantine {
+        build.extend(["--features", "equity_core/byzantine"]);
+    }
+    println!("running `cargo {}`", build.join(" "));
+    ComplexCommand::new("cargo", &build, ci)
+        .unwrap()
+        .stderr_to_file(&log_dir.join("cmd_cargo_build_err.log"))
+        .await
+        .unwrap()
+        .wait()
+        .await
+        .unwrap();
 
     // after the build we should have a release directory with the binaries
     let bin_dir = base_dir.join(format!("target/{}/release", args.target));
@@ -76,21 +80,77 @@ async fn main() {
         containers: vec![],
         log_dir,
     };
-    match args.test_mode {
-        mode @ (TestMode::Health | TestMode::GetResponse) => {
+    // nodes listen on every interface of their container, so they have to
+    // advertise their hostname for peers to be able to dial them
+    if args.test_mode == TestMode::Byzantine {
+        cn.containers
+            .extend(validators(&cn.log_dir, &bin_dir, base_image));
+    } else {
+        cn.containers.push(Container::new(
+            "equity_core",
+            base_image,
+            &bin_dir.join("equity_core"),
+            "0.0.0.0:4040 0.0.0.0:5050 --advertise-address=equity_core:5050",
+        ));
+    }
+    if args.test_mode == TestMode::Peers {
+        for name in ["equity_core1", "equity_core2"] {
             cn.containers.push(Container::new(
-                "equity_core",
+                name,
                 base_image,
                 &bin_dir.join("equity_core"),
-                "--listener=0.0.0.0:4040",
-            ));
-            cn.containers.push(Container::new(
-                "test_runner",
-                base_image,
-                &bin_dir.join("test_runner"),
-                mode.typed(),
+                &format!(
+                    "0.0.0.0:4040 0.0.0.0:5050 equity_core:5050 --advertise-address={}:5050",
+                    name
+                ),
             ));
         }
     }
+    cn.containers.push(Container::new(
+        "test_runner",
+        base_image,
+        &bin_dir.join("test_runner"),
+        args.test_mode.typed(),
+    ));
     cn.run(args.ci).await.unwrap();
 }
+
+/// Four validators of one genesis, `equity_core3` is silent and sends
+/// malformed handshakes. Their credentials and the genesis are written to
+/// `dir`.
+fn validators(dir: &Path, bin_dir: &Path, image: &str) -> Vec<Container> {
+    let credentials: Vec<_> = (1..=4)
+        .map(|seed| Credentials::from_seed([seed; 32]))
+        .collect();
+    let genesis = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let genesis_path = dir.join("genesis.json");
+    fs::write(&genesis_path, serde_json::to_vec(&genesis).unwrap()).unwrap();
+
+    let mut containers = vec![];
+    for (i, credentials) in credentials.iter().enumerate() {
+        let name = match i {
+            0 => "equity_core".to_owned(),
+            i => format!("equity_core{}", i),
+        };
+        let credentials_path = dir.join(format!("{}_credentials.json", name));
+        fs::write(&credentials_path, serde_json::to_vec(credentials).unwrap()).unwrap();
+
+        let mut args = format!(
+            "0.0.0.0:4040 0.0.0.0:5050 --advertise-address={}:5050 \
+             --credentials=/etc/equity/credentials.json --genesis=/etc/equity/genesis.json",
+            name
+        );
+        if i > 0 {
+            args.push_str(" equity_core:5050");
+        }
+        if i == 3 {
+            args.push_str(" --byzantine=silent --byzantine=malformed-init");
+        }
+        containers.push(
+            Container::new(&name, image, &bin_dir.join("equity_core"), &args)
+                .with_file(&credentials_path, "/etc/equity/credentials.json")
+                .with_file(&genesis_path, "/etc/equity/genesis.json"),
+        );
+    }
+    containers
+}
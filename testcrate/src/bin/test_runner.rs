@@ -1,9 +1,13 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
 
 use clap::Parser;
 use common::test_mode::TestMode;
 use equity_client::EquityClient;
-use equity_types::{EquityAddressResponse, Value};
+use equity_types::{EquityAddressResponse, PeerStatus, Value};
+use tokio::time::sleep;
 
 const TIMEOUT: Duration = Duration::from_secs(15);
 
@@ -17,26 +21,63 @@ pub struct CliArgs {
 #[tokio::main]
 async fn main() {
     let args = CliArgs::parse();
+    let client = EquityClient::new("http://equity_core:4040").unwrap();
+    client.wait_for_healthy(TIMEOUT).await.unwrap();
     match args.test_mode {
-        mode @ (TestMode::Health | TestMode::GetResponse) => {
-            let client = EquityClient::new("http://equity_core:4040").unwrap();
-            client.wait_for_healthy(TIMEOUT).await.unwrap();
-            match mode {
-                TestMode::Health => {
-                    dbg!(client.health().await.unwrap());
-                    assert!(client.health().await.unwrap().up);
+        TestMode::Health => {
+            dbg!(client.health().await.unwrap());
+            assert!(client.health().await.unwrap().up);
+        }
+        TestMode::GetResponse => {
+            dbg!(client.get_account_details("testkey").await.unwrap());
+            assert_eq!(
+                client.get_account_details("testkey").await.unwrap(),
+                EquityAddressResponse {
+                    owner: "testkey".to_owned(),
+                    value: Value(1337)
                 }
-                TestMode::GetResponse => {
-                    dbg!(client.get_account_details("testkey").await.unwrap());
-                    assert_eq!(
-                        client.get_account_details("testkey").await.unwrap(),
-                        EquityAddressResponse {
-                            owner: "testkey".to_owned(),
-                            value: Value(1337)
-                        }
-                    );
+            );
+        }
+        TestMode::Peers => {
+            dbg!(wait_for_peers(&client, &["equity_core1:5050", "equity_core2:5050"]).await);
+        }
+        TestMode::Byzantine => {
+            wait_for_peers(&client, &[
+                "equity_core1:5050",
+                "equity_core2:5050",
+                "equity_core3:5050",
+            ])
+            .await;
+            let transaction = client.create_transaction(&client.test_transaction(&10, &100, &3));
+            let response = client.post_transaction(transaction.clone()).await.unwrap();
+            assert!(response.success, "{:?}", response);
+            // the other honest nodes delivered it as well
+            for name in ["equity_core1", "equity_core2"] {
+                let peer = EquityClient::new(&format!("http://{}:4040", name)).unwrap();
+                let start = Instant::now();
+                while peer.get_transaction(&transaction.hash).await.is_err() {
+                    assert!(start.elapsed() < TIMEOUT, "{} did not deliver", name);
+                    sleep(Duration::from_millis(500)).await;
                 }
             }
+            dbg!(client.get_reputations().await.unwrap());
         }
     }
 }
+
+/// Waits until the peers with the listener addresses `names` are connected
+async fn wait_for_peers(client: &EquityClient, names: &[&str]) -> BTreeMap<String, PeerStatus> {
+    let joined = |peers: &BTreeMap<String, PeerStatus>| {
+        names
+            .iter()
+            .all(|name| peers.get(*name).is_some_and(|peer| peer.connected))
+    };
+    let start = Instant::now();
+    let mut peers = client.get_peers().await.unwrap();
+    while !joined(&peers) {
+        assert!(start.elapsed() < TIMEOUT, "peers never joined: {:?}", peers);
+        sleep(Duration::from_millis(500)).await;
+        peers = client.get_peers().await.unwrap();
+    }
+    peers
+}
@@ -1,4 +1,6 @@
 pub mod command;
 pub mod container_network;
 pub mod docker;
+pub mod simulator;
 pub mod test_mode;
+pub mod transaction;
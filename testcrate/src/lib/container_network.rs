@@ -20,7 +20,10 @@ pub struct Container {
     pub name: String,
     pub image: String,
     pub bin_path: PathBuf,
+    /// Arguments of the binary, separated by whitespace
     pub extra_args: String,
+    /// Files mounted read-only into the container, by path in the container
+    pub files: Vec<(PathBuf, String)>,
 }
 
 impl Container {
@@ -30,8 +33,15 @@ impl Container {
             image: image.to_owned(),
             bin_path: bin_path.to_owned(),
             extra_args: extra_args.to_owned(),
+            files: vec![],
         }
     }
+
+    /// Mounts the file at `path` to `target` in the container
+    pub fn with_file(mut self, path: &Path, target: &str) -> Self {
+        self.files.push((path.to_owned(), target.to_owned()));
+        self
+    }
 }
 
 pub struct ContainerNetwork {
@@ -74,6 +84,11 @@ impl ContainerNetwork {
             let bin_s = container.bin_path.file_name().unwrap().to_str().unwrap();
             // just include the needed binary
             let volume = format!("{}:/usr/bin/{}", bin_path_s, bin_s);
+            let files: Vec<String> = container
+                .files
+                .iter()
+                .map(|(path, target)| format!("{}:{}:ro", path.to_str().unwrap(), target))
+                .collect();
             let mut args = vec![
                 "create",
                 "--rm",
@@ -85,12 +100,12 @@ impl ContainerNetwork {
                 &container.name,
                 "--volume",
                 &volume,
-                &container.image,
-                bin_s,
             ];
-            if !container.extra_args.is_empty() {
-                args.push(&container.extra_args);
+            for file in &files {
+                args.extend(["--volume", file]);
             }
+            args.extend([container.image.as_str(), bin_s]);
+            args.extend(container.extra_args.split_whitespace());
             match ComplexCommand::new("docker", &args, ci)
                 .unwrap()
                 .stderr_to_file(&self.log_dir.join("cmd_docker_create_err.log"))
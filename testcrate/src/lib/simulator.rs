@@ -0,0 +1,273 @@
+//! Runs many consensus engines in one process over a virtual network. All
+//! randomness, from the node keys to latencies and drops, comes from one seed,
+//! so a failing seed reproduces the same run exactly.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+};
+
+use ed25519_consensus::{VerificationKey, VerificationKeyBytes};
+use equity_consensus::{Behavior, Bracha, Output, SampleParameters};
+use equity_types::{
+    ConsensusMessage, Credentials, EquivocationProof, FullMessage, QuorumCertificate, ValidatorSet,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    pub nodes: usize,
+    /// Ticks a message takes to arrive, drawn uniformly for every message.
+    /// Messages overtake each other unless the range holds a single value.
+    pub latency: Range<u64>,
+    /// Probability that a message is lost
+    pub drop_rate: f64,
+    /// Use the probabilistic broadcast instead of Bracha
+    pub sampling: Option<SampleParameters>,
+    /// The faulty behaviors of nodes, every other node is honest
+    pub byzantine: BTreeMap<usize, Vec<Behavior>>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            nodes: 4,
+            latency: 1..10,
+            drop_rate: 0.0,
+            sampling: None,
+            byzantine: BTreeMap::new(),
+        }
+    }
+}
+
+/// A message handed to a node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub time: u64,
+    pub from: usize,
+    pub to: usize,
+    pub message: ConsensusMessage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub time: u64,
+    pub message: FullMessage,
+    pub certificate: QuorumCertificate,
+}
+
+pub struct Simulator {
+    config: SimulatorConfig,
+    rng: StdRng,
+    now: u64,
+    credentials: Vec<Credentials>,
+    nodes: Vec<Bracha>,
+    index: BTreeMap<VerificationKeyBytes, usize>,
+    /// In flight messages by arrival time, ties are broken by send order
+    in_flight: BTreeMap<(u64, u64), (usize, usize, ConsensusMessage)>,
+    sent: u64,
+    crashed: BTreeSet<usize>,
+    /// The partition of every node, nodes only reach nodes in the same one
+    partitions: Vec<usize>,
+    trace: Vec<Event>,
+    deliveries: Vec<Vec<Delivery>>,
+    equivocations: Vec<Vec<EquivocationProof>>,
+}
+
+impl Simulator {
+    pub fn new(seed: u64, config: SimulatorConfig) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let credentials: Vec<Credentials> = (0..config.nodes)
+            .map(|_| Credentials::from_seed(rng.gen()))
+            .collect();
+        let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+        let nodes = credentials
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let node = Bracha::new(c.clone(), validators.clone());
+                let node = match config.sampling {
+                    Some(parameters) => node.with_sampling(parameters, rng.gen()),
+                    None => node,
+                };
+                match config.byzantine.get(&i) {
+                    Some(behaviors) => node.with_byzantine(behaviors.clone()),
+                    None => node,
+                }
+            })
+            .collect();
+        let index = credentials
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.public_key.into(), i))
+            .collect();
+
+        Self {
+            rng,
+            now: 0,
+            nodes,
+            index,
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            crashed: BTreeSet::new(),
+            partitions: vec![0; config.nodes],
+            trace: vec![],
+            deliveries: vec![vec![]; config.nodes],
+            equivocations: vec![vec![]; config.nodes],
+            credentials,
+            config,
+        }
+    }
+
+    /// A transaction of a client account derived from `seed`
+    pub fn transaction(seed: u8, nonce: u64, keys_values: &[(u64, u64)]) -> FullMessage {
+        crate::transaction::transaction(&Credentials::from_seed([seed; 32]), nonce, keys_values)
+    }
+
+    /// Starts a broadcast of `message` from `node`
+    pub fn broadcast(&mut self, node: usize, message: FullMessage) {
+        let outputs = self.nodes[node].broadcast(message);
+        self.outputs(node, outputs);
+    }
+
+    /// Hands `message` to `node` as if `from` had sent it, bypassing the
+    /// network. Lets tests inject messages of faulty nodes.
+    pub fn inject(&mut self, from: usize, node: usize, message: ConsensusMessage) {
+        let outputs = self.nodes[node].handle(self.credentials[from].public_key, message);
+        self.outputs(node, outputs);
+    }
+
+    /// Stops `node` from sending and receiving anything
+    pub fn crash(&mut self, node: usize) {
+        self.crashed.insert(node);
+    }
+
+    /// Splits the network, nodes can only reach nodes of the same group. Nodes
+    /// not in any group form a group of their own.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        self.partitions = vec![groups.len(); self.config.nodes];
+        for (group, nodes) in groups.iter().enumerate() {
+            for node in *nodes {
+                self.partitions[*node] = group;
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.partitions = vec![0; self.config.nodes];
+    }
+
+    /// Hands the next message to its receiver, returns false once nothing is
+    /// in flight
+    pub fn step(&mut self) -> bool {
+        let ((time, _), (from, to, message)) = match self.in_flight.pop_first() {
+            Some(next) => next,
+            None => return false,
+        };
+        self.now = time;
+        // partitions and crashes also hit messages that are already in flight
+        if self.crashed.contains(&to) || self.partitions[from] != self.partitions[to] {
+            return true
+        }
+
+        self.trace.push(Event {
+            time,
+            from,
+            to,
+            message: message.clone(),
+        });
+        let outputs = self.nodes[to].handle(self.credentials[from].public_key, message);
+        self.outputs(to, outputs);
+        true
+    }
+
+    /// Steps until nothing is in flight
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Steps until `time` or until nothing is in flight
+    pub fn run_until(&mut self, time: u64) {
+        while self
+            .in_flight
+            .keys()
+            .next()
+            .is_some_and(|(next, _)| *next <= time)
+        {
+            self.step();
+        }
+        self.now = self.now.max(time);
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn node(&self, node: usize) -> &Bracha {
+        &self.nodes[node]
+    }
+
+    pub fn public_key(&self, node: usize) -> VerificationKey {
+        self.credentials[node].public_key
+    }
+
+    pub fn deliveries(&self, node: usize) -> &[Delivery] {
+        &self.deliveries[node]
+    }
+
+    /// The equivocation proofs `node` found or was sent
+    pub fn equivocations(&self, node: usize) -> &[EquivocationProof] {
+        &self.equivocations[node]
+    }
+
+    /// Every message handed to a node so far, in order
+    pub fn trace(&self) -> &[Event] {
+        &self.trace
+    }
+
+    fn outputs(&mut self, node: usize, outputs: Vec<Output>) {
+        for output in outputs {
+            match output {
+                Output::Broadcast(message) => {
+                    for to in 0..self.config.nodes {
+                        if to != node {
+                            self.send(node, to, message.clone());
+                        }
+                    }
+                }
+                Output::Send(to, message) => {
+                    if let Some(to) = self.index.get(&to) {
+                        self.send(node, *to, message)
+                    }
+                }
+                Output::Deliver(message, certificate) => self.deliveries[node].push(Delivery {
+                    time: self.now,
+                    message,
+                    certificate,
+                }),
+                Output::Equivocation(proof) => self.equivocations[node].push(*proof),
+                Output::Late(from, hash, message) => {
+                    let delivered = self.deliveries[node]
+                        .iter()
+                        .find(|delivery| delivery.message.hash == hash)
+                        .map(|delivery| delivery.message.clone());
+                    if let Some(delivered) = delivered {
+                        let outputs = self.nodes[node].late(from, delivered, message);
+                        self.outputs(node, outputs);
+                    }
+                }
+                Output::Reconfigured(_) | Output::Invalid(_) => (),
+            }
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, message: ConsensusMessage) {
+        self.sent += 1;
+        if self.crashed.contains(&from) || self.rng.gen_bool(self.config.drop_rate) {
+            return
+        }
+        let latency = self.rng.gen_range(self.config.latency.clone());
+        self.in_flight
+            .insert((self.now + latency, self.sent), (from, to, message));
+    }
+}
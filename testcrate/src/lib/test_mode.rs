@@ -5,6 +5,12 @@ pub enum TestMode {
     /// The most basic test with the health of `equity_core` being checked
     Health,
     GetResponse,
+    /// Two more nodes join `equity_core` and have to show up under their
+    /// container hostnames
+    Peers,
+    /// Four validators where one is faulty, the honest ones still have to
+    /// deliver a transaction
+    Byzantine,
 }
 
 impl TestMode {
@@ -12,6 +18,8 @@ impl TestMode {
         match self {
             TestMode::Health => "health",
             TestMode::GetResponse => "get-response",
+            TestMode::Peers => "peers",
+            TestMode::Byzantine => "byzantine",
         }
     }
 }
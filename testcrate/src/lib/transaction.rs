@@ -0,0 +1,28 @@
+//! Signed transactions for tests
+
+use equity_types::{Body, Credentials, FullMessage};
+
+/// A transaction of the account of `credentials` that sets `keys_values`
+pub fn transaction(
+    credentials: &Credentials,
+    nonce: u64,
+    keys_values: &[(u64, u64)],
+) -> FullMessage {
+    dependent(credentials, nonce, keys_values, &[])
+}
+
+/// A transaction that is only applied after the `dependencies`
+pub fn dependent(
+    credentials: &Credentials,
+    nonce: u64,
+    keys_values: &[(u64, u64)],
+    dependencies: &[&FullMessage],
+) -> FullMessage {
+    let body = Body {
+        public_key: credentials.public_key,
+        nonce,
+        keys_values: keys_values.iter().copied().collect(),
+        dependencies: dependencies.iter().map(|m| m.hash.clone()).collect(),
+    };
+    FullMessage::sign(body, credentials).unwrap()
+}
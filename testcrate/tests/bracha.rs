@@ -0,0 +1,425 @@
+use std::collections::VecDeque;
+
+use common::transaction::transaction;
+use ed25519_consensus::VerificationKeyBytes;
+use equity_consensus::{Bracha, Output, Thresholds};
+use equity_types::{
+    ConsensusMessage, Credentials, EquityError, Equivocation, EquivocationProof, FullMessage,
+    QuorumCertificate, Reconfiguration, Validator, ValidatorSet, Vote, VoteKind, DEFAULT_CHAIN_ID,
+};
+
+/// Broadcasts `message` from node 0 and passes messages around until nothing is
+/// left to send. Nodes in `silent` never send anything. Returns what each node
+/// delivered.
+fn run(n: usize, silent: &[usize], message: FullMessage) -> Vec<Vec<FullMessage>> {
+    let credentials: Vec<Credentials> = (0..n).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let mut nodes: Vec<Bracha> = credentials
+        .iter()
+        .map(|c| Bracha::new(c.clone(), validators.clone()))
+        .collect();
+    let mut delivered = vec![vec![]; n];
+
+    let mut queue: VecDeque<(usize, Output)> = nodes[0]
+        .broadcast(message)
+        .into_iter()
+        .map(|output| (0, output))
+        .collect();
+    while let Some((from, output)) = queue.pop_front() {
+        match output {
+            Output::Broadcast(message) => {
+                if silent.contains(&from) {
+                    continue
+                }
+                for (i, node) in nodes.iter_mut().enumerate() {
+                    if i != from {
+                        let outputs = node.handle(credentials[from].public_key, message.clone());
+                        queue.extend(outputs.into_iter().map(|output| (i, output)));
+                    }
+                }
+            }
+            Output::Deliver(message, certificate) => {
+                // every delivery comes with a certificate checkable against the validators
+                certificate.verify(&validators).unwrap();
+                let next_epoch = ValidatorSet {
+                    epoch: 1,
+                    ..validators.clone()
+                };
+                assert!(certificate.verify(&next_epoch).is_err());
+                // the votes are signed for their epoch and chain, relabelling
+                // the certificate does not make it pass for another
+                let relabelled = QuorumCertificate {
+                    epoch: 1,
+                    ..certificate.clone()
+                };
+                assert!(relabelled.verify(&next_epoch).is_err());
+                let other_chain = ValidatorSet {
+                    chain_id: "other".to_owned(),
+                    ..validators.clone()
+                };
+                assert!(certificate.verify(&other_chain).is_err());
+                assert_eq!(certificate.hash, message.hash);
+                delivered[from].push(message)
+            }
+            Output::Reconfigured(_) => (),
+            Output::Equivocation(proof) => panic!("unexpected equivocation {:?}", proof),
+            Output::Invalid(_) => (),
+            Output::Send(..) => panic!("only the probabilistic broadcast sends to single peers"),
+            Output::Late(..) => panic!("only conflicting messages need the delivered one"),
+        }
+    }
+    delivered
+}
+
+#[test]
+fn all_deliver() {
+    let message = transaction(&Credentials::new(), 1, &[(1, 2)]);
+    for delivered in run(4, &[], message.clone()) {
+        assert_eq!(delivered, vec![message.clone()]);
+    }
+}
+
+#[test]
+fn tolerates_silent_node() {
+    let message = transaction(&Credentials::new(), 1, &[(1, 2)]);
+    let delivered = run(4, &[3], message.clone());
+    for delivered in &delivered[..3] {
+        assert_eq!(delivered, &vec![message.clone()]);
+    }
+}
+
+#[test]
+fn invalid_message_is_not_delivered() {
+    let mut message = transaction(&Credentials::new(), 1, &[(1, 2)]);
+    message.body.nonce = 2;
+    assert!(run(4, &[], message).iter().all(Vec::is_empty));
+}
+
+#[test]
+fn thresholds() {
+    let keys: Vec<_> = (0..4).map(|_| Credentials::new().public_key).collect();
+    let t = Thresholds::new(&ValidatorSet::equal_weight(0, keys.clone()));
+    assert_eq!((t.f, t.echo(), t.ready(), t.deliver()), (1, 3, 2, 3));
+    let t = Thresholds::new(&ValidatorSet::equal_weight(0, keys[..1].to_vec()));
+    assert_eq!((t.f, t.echo(), t.ready(), t.deliver()), (0, 1, 1, 1));
+    // thresholds are in weight, not in number of validators
+    let weighted = ValidatorSet {
+        chain_id: DEFAULT_CHAIN_ID.to_owned(),
+        epoch: 0,
+        validators: vec![
+            Validator {
+                public_key: keys[0],
+                weight: 7,
+            },
+            Validator {
+                public_key: keys[1],
+                weight: 3,
+            },
+        ],
+    };
+    let t = Thresholds::new(&weighted);
+    assert_eq!((t.n, t.f, t.deliver()), (10, 3, 7));
+    // `ConsensusMessage` must survive the JSON used on the wire
+    let send = ConsensusMessage::Send(transaction(&Credentials::new(), 1, &[(1, 2)]));
+    let bytes = serde_json::to_vec(&send).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<ConsensusMessage>(&bytes).unwrap(),
+        send
+    );
+}
+
+#[test]
+fn reconfiguration_needs_quorum() {
+    let credentials: Vec<Credentials> = (0..4).map(|_| Credentials::new()).collect();
+    let genesis = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let next = ValidatorSet::equal_weight(1, credentials[..3].iter().map(|c| c.public_key));
+    let sign = |signers: &[Credentials]| Reconfiguration {
+        validators: next.clone(),
+        votes: signers
+            .iter()
+            .map(|c| Reconfiguration::sign(&genesis, &next, c).unwrap())
+            .collect(),
+    };
+
+    let mut node = Bracha::new(credentials[0].clone(), genesis.clone());
+    assert!(matches!(
+        node.reconfigure(sign(&credentials[..2])),
+        Err(EquityError::NoQuorum)
+    ));
+    // the same signer twice does not add weight
+    let mut repeated = sign(&credentials[..2]);
+    repeated.votes.push(repeated.votes[0]);
+    assert!(matches!(
+        node.reconfigure(repeated),
+        Err(EquityError::NoQuorum)
+    ));
+    // signers outside the current set are not counted
+    let outsider = sign(&[
+        credentials[0].clone(),
+        credentials[1].clone(),
+        Credentials::new(),
+    ]);
+    assert!(node.reconfigure(outsider).is_err());
+
+    let outputs = node.reconfigure(sign(&credentials[..3])).unwrap();
+    assert!(outputs.contains(&Output::Reconfigured(next.clone())));
+    assert_eq!(node.validators(), &next);
+    // applying it again is an epoch mismatch
+    assert!(matches!(
+        node.reconfigure(sign(&credentials[..3])),
+        Err(EquityError::EpochMismatch {
+            expected: 2,
+            found: 1
+        })
+    ));
+
+    // peers pick it up from the broadcast
+    let mut peer = Bracha::new(credentials[1].clone(), genesis);
+    for output in outputs {
+        if let Output::Broadcast(message) = output {
+            peer.handle(credentials[0].public_key, message);
+        }
+    }
+    assert_eq!(peer.validators(), &next);
+}
+
+fn proofs(outputs: &[Output]) -> Vec<EquivocationProof> {
+    outputs
+        .iter()
+        .filter_map(|output| match output {
+            Output::Equivocation(proof) => Some(*proof.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn equivocating_sender_is_proven() {
+    let credentials: Vec<Credentials> = (0..4).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let mut node = Bracha::new(credentials[1].clone(), validators.clone());
+    let sender = &credentials[0];
+    let (first, second) = (
+        transaction(sender, 1, &[(1, 1)]),
+        transaction(sender, 1, &[(1, 2)]),
+    );
+
+    assert!(proofs(&node.handle(sender.public_key, ConsensusMessage::Send(first))).is_empty());
+    let outputs = node.handle(sender.public_key, ConsensusMessage::Send(second));
+    let proof = match proofs(&outputs).as_slice() {
+        [proof] => proof.clone(),
+        proofs => panic!("expected one proof, got {:?}", proofs),
+    };
+    assert_eq!(proof.kind, Equivocation::Sender);
+    assert_eq!(proof.offender(), sender.public_key);
+    proof.verify(&validators).unwrap();
+    // the second message is not echoed, only the proof is passed on
+    assert_eq!(outputs.len(), 2);
+    assert!(
+        outputs.contains(&Output::Broadcast(ConsensusMessage::Equivocation(
+            Box::new(proof.clone())
+        )))
+    );
+
+    // peers report gossiped evidence once
+    let mut peer = Bracha::new(credentials[2].clone(), validators);
+    let gossip = ConsensusMessage::Equivocation(Box::new(proof));
+    assert_eq!(
+        proofs(&peer.handle(credentials[1].public_key, gossip.clone())).len(),
+        1
+    );
+    assert!(peer.handle(credentials[3].public_key, gossip).is_empty());
+}
+
+#[test]
+fn conflicting_votes_are_proven() {
+    let credentials: Vec<Credentials> = (0..4).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let mut node = Bracha::new(credentials[0].clone(), validators.clone());
+    let account = Credentials::new();
+    let (first, second) = (
+        transaction(&account, 1, &[(1, 1)]),
+        transaction(&account, 1, &[(1, 2)]),
+    );
+    let voter = &credentials[1];
+    let echo = |message: &FullMessage| {
+        let vote = Vote::sign(VoteKind::Echo, &validators, &message.hash, voter);
+        ConsensusMessage::Echo(message.clone(), Box::new(vote))
+    };
+
+    node.handle(voter.public_key, echo(&first));
+    let found = proofs(&node.handle(voter.public_key, echo(&second)));
+    let proof = found
+        .iter()
+        .find(|proof| proof.kind == Equivocation::Echo)
+        .unwrap();
+    assert_eq!(proof.offender(), voter.public_key);
+    proof.verify(&validators).unwrap();
+    // the messages conflict as well
+    assert!(found.iter().any(|proof| proof.kind == Equivocation::Sender));
+
+    // votes that don't match their messages are not evidence
+    let mut swapped = proof.clone();
+    swapped.votes.reverse();
+    assert!(swapped.verify(&validators).is_err());
+    let mut unrelated = proof.clone();
+    unrelated.second = transaction(&account, 2, &[(1, 2)]);
+    assert!(unrelated.verify(&validators).is_err());
+    // votes of another epoch are not evidence in this one
+    let next_epoch = ValidatorSet {
+        epoch: 1,
+        ..validators.clone()
+    };
+    assert!(proof.verify(&next_epoch).is_err());
+}
+
+#[test]
+fn invalid_signatures_are_blamed_on_the_peer() {
+    let credentials: Vec<Credentials> = (0..4).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let mut node = Bracha::new(credentials[0].clone(), validators.clone());
+    let message = transaction(&Credentials::new(), 1, &[(1, 2)]);
+    let voter = &credentials[1];
+
+    let vote = Vote::sign(VoteKind::Ready, &validators, &message.hash, voter);
+    let echo = ConsensusMessage::Echo(message.clone(), Box::new(vote));
+    assert_eq!(node.handle(voter.public_key, echo), vec![Output::Invalid(
+        voter.public_key.into()
+    )]);
+
+    let mut forged = transaction(&Credentials::new(), 1, &[(1, 2)]);
+    forged.body.nonce = 2;
+    let relay = &credentials[2];
+    assert_eq!(
+        node.handle(relay.public_key, ConsensusMessage::Send(forged)),
+        vec![Output::Invalid(relay.public_key.into())]
+    );
+}
+
+#[test]
+fn delivered_instances_only_keep_the_hash() {
+    let credentials: Vec<Credentials> = (0..4).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let mut node = Bracha::new(credentials[0].clone(), validators.clone());
+    let sender = Credentials::new();
+    let message = transaction(&sender, 1, &[(1, 2)]);
+    let ready = |voter: &Credentials| {
+        let vote = Vote::sign(VoteKind::Ready, &validators, &message.hash, voter);
+        ConsensusMessage::Ready(message.clone(), Box::new(vote))
+    };
+
+    let mut outputs = vec![];
+    for voter in &credentials[1..] {
+        outputs.extend(node.handle(voter.public_key, ready(voter)));
+    }
+    assert_eq!(
+        outputs
+            .iter()
+            .filter(|output| matches!(output, Output::Deliver(..)))
+            .count(),
+        1
+    );
+    assert!(node.pending(&(sender.public_key.into(), 1)).is_none());
+
+    // copies of the message and its votes are dropped
+    let send = ConsensusMessage::Send(message.clone());
+    assert!(node.handle(sender.public_key, send).is_empty());
+    assert!(node
+        .handle(credentials[1].public_key, ready(&credentials[1]))
+        .is_empty());
+
+    // a conflicting message needs the delivered one to prove the sender
+    let conflicting = |value| ConsensusMessage::Send(transaction(&sender, 1, &[(1, value)]));
+    let from = VerificationKeyBytes::from(sender.public_key);
+    let late = node.handle(sender.public_key, conflicting(3));
+    assert_eq!(late, vec![Output::Late(
+        from,
+        message.hash.clone(),
+        conflicting(3)
+    )]);
+    // only the delivered message is taken
+    let other = transaction(&sender, 1, &[(1, 5)]);
+    assert!(node.late(from, other, conflicting(3)).is_empty());
+    let found = proofs(&node.late(from, message.clone(), conflicting(3)));
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].kind, Equivocation::Sender);
+    // once the sender is proven, conflicting messages are dropped
+    assert!(node.handle(sender.public_key, conflicting(4)).is_empty());
+}
+
+#[test]
+fn idle_instances_expire_with_our_votes_kept() {
+    let credentials: Vec<Credentials> = (0..4).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let mut node = Bracha::new(credentials[0].clone(), validators);
+    let sender = Credentials::new();
+    let (first, second) = (
+        transaction(&sender, 1, &[(1, 2)]),
+        transaction(&sender, 1, &[(1, 3)]),
+    );
+    let id = (sender.public_key.into(), 1);
+    let echoes = |outputs: &[Output]| {
+        outputs
+            .iter()
+            .filter(|output| matches!(output, Output::Broadcast(ConsensusMessage::Echo(..))))
+            .count()
+    };
+
+    assert_eq!(
+        echoes(&node.handle(sender.public_key, ConsensusMessage::Send(first.clone()))),
+        1
+    );
+    // an instance is only expired once it went a whole round without a message
+    assert!(node.expire().is_empty());
+    assert_eq!(node.pending(&id), Some(&first));
+    assert_eq!(node.expire(), vec![id]);
+    assert!(node.pending(&id).is_none());
+    assert!(node.expire().is_empty());
+
+    // the instance starts over, but we never echo another message of it
+    let outputs = node.handle(sender.public_key, ConsensusMessage::Send(second));
+    assert_eq!(echoes(&outputs), 0);
+    assert_eq!(
+        echoes(&node.handle(sender.public_key, ConsensusMessage::Send(first))),
+        0
+    );
+}
+
+#[test]
+fn restored_state_is_kept_after_a_restart() {
+    let credentials: Vec<Credentials> = (0..4).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let sender = Credentials::new();
+    let (first, second) = (
+        transaction(&sender, 1, &[(1, 2)]),
+        transaction(&sender, 1, &[(1, 3)]),
+    );
+    let send = |message: &FullMessage| ConsensusMessage::Send(message.clone());
+    let echoes = |outputs: &[Output]| {
+        outputs
+            .iter()
+            .filter_map(|output| match output {
+                Output::Broadcast(echo @ ConsensusMessage::Echo(..)) => Some(echo.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut node = Bracha::new(credentials[0].clone(), validators.clone());
+    let echo = echoes(&node.handle(sender.public_key, send(&first)));
+    assert_eq!(echo.len(), 1);
+
+    // after a restart our ECHO is sent again and no other message is echoed
+    let mut node = Bracha::new(credentials[0].clone(), validators.clone());
+    assert_eq!(echoes(&node.restore_vote(echo[0].clone())), echo);
+    let outputs = node.handle(sender.public_key, send(&second));
+    assert!(echoes(&outputs).is_empty());
+    assert_eq!(proofs(&outputs)[0].kind, Equivocation::Sender);
+    assert_eq!(node.pending(&(sender.public_key.into(), 1)), Some(&first));
+
+    // copies of a restored delivery are dropped
+    let mut node = Bracha::new(credentials[0].clone(), validators);
+    node.restore_delivered(first.clone());
+    assert!(node.handle(sender.public_key, send(&first)).is_empty());
+    assert!(node.pending(&(sender.public_key.into(), 1)).is_none());
+}
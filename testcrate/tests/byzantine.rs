@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+
+use common::simulator::{Simulator, SimulatorConfig};
+use equity_consensus::Behavior;
+use equity_types::{ConsensusMessage, Equivocation};
+
+fn config(nodes: usize, byzantine: &[(usize, &[Behavior])]) -> SimulatorConfig {
+    SimulatorConfig {
+        nodes,
+        latency: 1..20,
+        byzantine: byzantine
+            .iter()
+            .map(|(node, behaviors)| (*node, behaviors.to_vec()))
+            .collect::<BTreeMap<_, _>>(),
+        ..SimulatorConfig::default()
+    }
+}
+
+#[test]
+fn behaviors_parse() {
+    for behavior in [
+        Behavior::Silent,
+        Behavior::EquivocateEcho,
+        Behavior::DelayReady(12),
+        Behavior::MalformedInit,
+        Behavior::ForgePeerMap,
+    ] {
+        assert_eq!(behavior.to_string().parse(), Ok(behavior));
+    }
+    assert!("delay-ready:".parse::<Behavior>().is_err());
+    assert!("loud".parse::<Behavior>().is_err());
+}
+
+#[test]
+fn silent_node_is_tolerated() {
+    for seed in 0..10 {
+        let mut simulator = Simulator::new(seed, config(4, &[(3, &[Behavior::Silent])]));
+        simulator.broadcast(0, Simulator::transaction(1, 1, &[(1, 2)]));
+        simulator.run();
+        // it still listens, so it delivers as well
+        for node in 0..4 {
+            assert_eq!(simulator.deliveries(node).len(), 1, "seed {}", seed);
+        }
+        assert!(simulator.trace().iter().all(|event| event.from != 3));
+    }
+}
+
+#[test]
+fn delayed_ready_still_delivers() {
+    let mut simulator = Simulator::new(1, config(4, &[(3, &[Behavior::DelayReady(5)])]));
+    simulator.broadcast(3, Simulator::transaction(1, 1, &[(1, 2)]));
+    simulator.run();
+    for node in 0..4 {
+        assert_eq!(simulator.deliveries(node).len(), 1);
+    }
+}
+
+#[test]
+fn equivocating_echo_is_proven() {
+    let mut simulator = Simulator::new(2, config(4, &[(3, &[Behavior::EquivocateEcho])]));
+    // the faulty node sees both messages of an equivocating sender and echoes
+    // each of them
+    let first = Simulator::transaction(1, 1, &[(1, 1)]);
+    let second = Simulator::transaction(1, 1, &[(1, 2)]);
+    for (node, message) in [(1, &first), (2, &second), (3, &first), (3, &second)] {
+        simulator.inject(0, node, ConsensusMessage::Send(message.clone()));
+    }
+    simulator.run();
+
+    let offender = simulator.public_key(3);
+    for node in 0..3 {
+        assert!(simulator
+            .equivocations(node)
+            .iter()
+            .any(|proof| proof.kind == Equivocation::Echo && proof.offender() == offender));
+    }
+}
+
+#[test]
+fn faulty_minority_keeps_agreement() {
+    let faulty: &[Behavior] = &[Behavior::EquivocateEcho, Behavior::DelayReady(3)];
+    for seed in 0..20 {
+        let mut simulator = Simulator::new(seed, config(7, &[(5, faulty), (6, faulty)]));
+        let first = Simulator::transaction(1, 1, &[(1, 1)]);
+        let second = Simulator::transaction(1, 1, &[(1, 2)]);
+        for node in 0..7 {
+            let message = if node % 2 == 0 { &first } else { &second };
+            simulator.inject(0, node, ConsensusMessage::Send(message.clone()));
+        }
+        simulator.run();
+
+        let delivered: Vec<_> = (0..5)
+            .flat_map(|node| simulator.deliveries(node))
+            .map(|delivery| delivery.message.hash.clone())
+            .collect();
+        assert!(
+            delivered.windows(2).all(|pair| pair[0] == pair[1]),
+            "seed {}",
+            seed
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use equity_p2p::{CipherState, NoiseHandshake, NoiseKeypair, P2pError};
+
+/// Runs a whole handshake with a payload in every message, returning the
+/// ciphers of the initiator and of the responder
+fn handshake(
+    initiator_static: &NoiseKeypair,
+    responder_static: &NoiseKeypair,
+) -> ((CipherState, CipherState), (CipherState, CipherState)) {
+    let mut initiator = NoiseHandshake::new(true, b"prologue", initiator_static).unwrap();
+    let mut responder = NoiseHandshake::new(false, b"prologue", responder_static).unwrap();
+
+    for i in 0..3 {
+        let (writer, reader) = match i % 2 {
+            0 => (&mut initiator, &mut responder),
+            _ => (&mut responder, &mut initiator),
+        };
+        let payload = format!("payload {}", i).into_bytes();
+        let message = writer.write_message(&payload).unwrap();
+        assert_eq!(reader.read_message(&message).unwrap(), payload);
+    }
+    assert_eq!(initiator.hash(), responder.hash());
+    assert_eq!(initiator.remote_static(), Some(responder_static.public));
+    assert_eq!(responder.remote_static(), Some(initiator_static.public));
+
+    (
+        initiator.into_transport().unwrap(),
+        responder.into_transport().unwrap(),
+    )
+}
+
+#[test]
+fn handshake_sets_up_both_directions() {
+    let (initiator_static, responder_static) = (NoiseKeypair::generate(), NoiseKeypair::generate());
+    let (mut initiator, mut responder) = handshake(&initiator_static, &responder_static);
+
+    for i in 0..4 {
+        let ((send, _), (_, receive)) = match i % 2 {
+            0 => (&mut initiator, &mut responder),
+            _ => (&mut responder, &mut initiator),
+        };
+        let payload = format!("message {}", i).into_bytes();
+        let message = send.seal(&payload).unwrap();
+        assert_ne!(message, payload);
+        assert_eq!(receive.open(&message).unwrap(), payload);
+    }
+
+    // a side only receives with its receiving cipher
+    let message = initiator.0.seal(b"to the responder").unwrap();
+    assert!(initiator.1.open(&message).is_err());
+    assert!(responder.1.seal(b"wrong direction").is_err());
+}
+
+#[test]
+fn tampered_messages_leave_the_cipher_usable() {
+    let (initiator_static, responder_static) = (NoiseKeypair::generate(), NoiseKeypair::generate());
+    let ((mut send, _), (_, mut receive)) = handshake(&initiator_static, &responder_static);
+    let (first, second) = (send.seal(b"first").unwrap(), send.seal(b"second").unwrap());
+
+    let mut tampered = first.clone();
+    tampered[0] ^= 1;
+    assert!(receive.open(&tampered).is_err());
+    // messages only open in order, once
+    assert!(receive.open(&second).is_err());
+    assert_eq!(receive.open(&first).unwrap(), b"first");
+    assert!(receive.open(&first).is_err());
+    assert_eq!(receive.open(&second).unwrap(), b"second");
+}
+
+#[test]
+fn refuses_oversized_and_out_of_turn_messages() {
+    let mut initiator = NoiseHandshake::new(true, b"", &NoiseKeypair::generate()).unwrap();
+    assert!(matches!(
+        initiator.write_message(&vec![0; 1 << 16]),
+        Err(P2pError::Handshake(_))
+    ));
+
+    // messages only go in turns
+    let mut responder = NoiseHandshake::new(false, b"", &NoiseKeypair::generate()).unwrap();
+    assert!(matches!(
+        responder.write_message(&[]),
+        Err(P2pError::Handshake(_))
+    ));
+}
@@ -0,0 +1,1105 @@
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use common::transaction::transaction;
+use equity_p2p::{
+    challenge, decode, decode_prefix, encode, frame, read_message, write_message, Allowlist,
+    CodecError, FrameKind, Gossip, InitMessage, InitResponse, Keepalive, Limits, Network,
+    NetworkConfig, Overflow, P2pError, P2pMessage, PeerRecord, Protocol, Transport,
+    MAX_FRAME_LENGTH, MAX_HANDSHAKE_FRAME_LENGTH, PROTOCOL_VERSION,
+};
+use equity_types::{
+    Body, ConsensusMessage, Credentials, FullMessage, Offense, Subscription, ValidatorSet, Vote,
+    VoteKind,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+fn consensus_message() -> ConsensusMessage {
+    ConsensusMessage::Subscribe(Subscription {
+        kind: VoteKind::Echo,
+        public_key: Credentials::new().public_key,
+        nonce: 1,
+    })
+}
+
+#[tokio::test]
+async fn frames_round_trip() {
+    let messages = [
+        P2pMessage::Ping(7),
+        P2pMessage::GetPeers,
+        P2pMessage::Consensus(consensus_message()),
+    ];
+    let mut stream = vec![];
+    for message in &messages {
+        stream.extend(encode(message).unwrap());
+    }
+
+    let mut reader = stream.as_slice();
+    for message in messages {
+        assert_eq!(read_message(&mut reader).await.unwrap(), message);
+    }
+    assert!(matches!(
+        read_message(&mut reader).await,
+        Err(CodecError::Io(_))
+    ));
+}
+
+#[tokio::test]
+async fn bad_frames_are_rejected() {
+    let mut other_version = (PROTOCOL_VERSION + 1).to_be_bytes().to_vec();
+    other_version.extend(serde_json::to_vec(&P2pMessage::Ping(1)).unwrap());
+    assert!(matches!(
+        decode(&other_version),
+        Err(CodecError::Version(_))
+    ));
+    assert!(matches!(decode(&[0]), Err(CodecError::Truncated)));
+    assert!(matches!(
+        decode(&frame(FrameKind::Other, b"garbage")[4..]),
+        Err(CodecError::Json(_))
+    ));
+    // the kind in the header has to match the message
+    let ping = serde_json::to_vec(&P2pMessage::Ping(1)).unwrap();
+    assert!(decode(&frame(FrameKind::Other, &ping)[4..]).is_ok());
+    assert!(matches!(
+        decode(&frame(FrameKind::Consensus, &ping)[4..]),
+        Err(CodecError::Kind(1))
+    ));
+
+    let too_long = (MAX_FRAME_LENGTH + 1).to_be_bytes();
+    assert!(matches!(
+        read_message(&mut too_long.as_slice()).await,
+        Err(CodecError::TooLong(_))
+    ));
+}
+
+#[tokio::test]
+async fn nodes_join_through_a_seed() {
+    let config = |seeds| NetworkConfig::new("127.0.0.1:0".parse().unwrap(), seeds);
+    let credentials: Vec<_> = (0..3).map(|_| Arc::new(Credentials::new())).collect();
+    let (seed, _) = Network::start(config(vec![]), credentials[0].clone())
+        .await
+        .unwrap();
+    let (second, mut second_inbound) =
+        Network::start(config(vec![seed.address()]), credentials[1].clone())
+            .await
+            .unwrap();
+    let (third, _) = Network::start(config(vec![seed.address()]), credentials[2].clone())
+        .await
+        .unwrap();
+
+    // the third node learned about the second one from the seed
+    let peers = third.peers();
+    assert_eq!(peers.len(), 2);
+    assert_eq!(
+        peers[&second.address().to_string()],
+        credentials[1].public_key
+    );
+
+    // longer than a single Noise transport message
+    let signer = Credentials::new();
+    let message = ConsensusMessage::Send(FullMessage {
+        body: Body {
+            public_key: signer.public_key,
+            nonce: 1,
+            keys_values: (0..10_000).map(|key| (key, key)).collect(),
+            dependencies: vec![],
+        },
+        hash: "hash".to_owned(),
+        signature: signer.private_key.sign(b"hash"),
+    });
+    third
+        .send_to(
+            credentials[1].public_key,
+            &P2pMessage::Consensus(message.clone()),
+        )
+        .await;
+    let inbound = timeout(Duration::from_secs(5), second_inbound.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(inbound.from, credentials[2].public_key);
+    assert_eq!(inbound.message, message);
+}
+
+#[test]
+fn handshakes_only_answer_their_challenge() {
+    let credentials = Credentials::new();
+    let (ours, old) = (challenge(), challenge());
+    let protocol = Protocol::default();
+
+    let mut init = InitMessage::sign(&credentials, "127.0.0.1:5050", &protocol, &ours).unwrap();
+    assert!(init.verify(&ours).is_ok());
+    assert!(init.verify(&old).is_err());
+    init.listener = "127.0.0.1:6060".to_owned();
+    assert!(init.verify(&ours).is_err());
+    init.listener = "127.0.0.1:5050".to_owned();
+    init.protocol.chain_id = "other".to_owned();
+    assert!(init.verify(&ours).is_err());
+
+    let peer_map = [("127.0.0.1:7070".to_owned(), Credentials::new().public_key)].into();
+    let mut response =
+        InitResponse::sign(&credentials, "127.0.0.1:5050", &protocol, peer_map, &ours).unwrap();
+    assert!(response.verify(&ours).is_ok());
+    assert!(response.verify(&old).is_err());
+    response.peer_map.clear();
+    assert!(response.verify(&ours).is_err());
+}
+
+#[tokio::test]
+async fn transports_do_not_mix() {
+    let config = |seeds, transport| NetworkConfig {
+        transport,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), seeds)
+    };
+    let (noise, _) = Network::start(
+        config(vec![], Transport::Noise),
+        Arc::new(Credentials::new()),
+    )
+    .await
+    .unwrap();
+    let joined = timeout(
+        Duration::from_secs(5),
+        Network::start(
+            config(vec![noise.address()], Transport::Plaintext),
+            Arc::new(Credentials::new()),
+        ),
+    )
+    .await
+    .unwrap();
+    assert!(joined.is_err());
+    assert!(noise.peers().is_empty());
+}
+
+#[tokio::test]
+async fn replayed_handshake_is_refused() {
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (network, _) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+
+    let stream = TcpStream::connect(network.address()).await.unwrap();
+    let (mut read, mut write) = stream.into_split();
+    assert!(matches!(
+        read_message(&mut read).await.unwrap(),
+        P2pMessage::Challenge(_)
+    ));
+    write_message(&mut write, &P2pMessage::Challenge(challenge()))
+        .await
+        .unwrap();
+    // signed for a challenge of an earlier connection
+    let init = InitMessage::sign(
+        &Credentials::new(),
+        "127.0.0.1:5050",
+        &Protocol::default(),
+        &challenge(),
+    )
+    .unwrap();
+    write_message(&mut write, &P2pMessage::Init(init))
+        .await
+        .unwrap();
+
+    assert!(read_message(&mut read).await.is_err());
+    assert!(network.peers().is_empty());
+}
+
+/// Answers the next plaintext handshake on `listener` as `credentials`
+async fn answer(listener: &TcpListener, credentials: &Credentials) -> TcpStream {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let ours = challenge();
+    write_message(&mut stream, &P2pMessage::Challenge(ours))
+        .await
+        .unwrap();
+    let theirs = match read_message(&mut stream).await.unwrap() {
+        P2pMessage::Challenge(theirs) => theirs,
+        message => panic!("expected a challenge, got {:?}", message),
+    };
+    assert!(matches!(
+        read_message(&mut stream).await.unwrap(),
+        P2pMessage::Init(_)
+    ));
+    let address = listener.local_addr().unwrap().to_string();
+    let response = InitResponse::sign(
+        credentials,
+        &address,
+        &Protocol::default(),
+        [].into(),
+        &theirs,
+    )
+    .unwrap();
+    write_message(&mut stream, &P2pMessage::InitResponse(response))
+        .await
+        .unwrap();
+    stream
+}
+
+#[tokio::test]
+async fn dropped_peers_are_dialed_again() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let credentials = Credentials::new();
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        keepalive: Keepalive {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(300),
+            handshake: Duration::from_millis(300),
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(200),
+        },
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![address])
+    };
+
+    let (stream, started) = tokio::join!(
+        answer(&listener, &credentials),
+        Network::start(config, Arc::new(Credentials::new()))
+    );
+    let (network, _) = started.unwrap();
+    let connected = || {
+        network
+            .status()
+            .get(&address.to_string())
+            .map(|status| status.connected)
+    };
+    assert_eq!(connected(), Some(true));
+    assert!(network.is_reachable(credentials.public_key.into()));
+
+    // a closed connection is dialed again
+    drop(stream);
+    let mut stream = timeout(Duration::from_secs(5), answer(&listener, &credentials))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read_message(&mut stream).await.unwrap(),
+        P2pMessage::Ping(_)
+    ));
+
+    // a peer that stops answering is dropped
+    sleep(Duration::from_millis(600)).await;
+    assert_eq!(connected(), Some(false));
+    assert!(!network.is_reachable(credentials.public_key.into()));
+    drop(stream);
+}
+
+#[tokio::test]
+async fn stalled_handshakes_time_out() {
+    let keepalive = Keepalive {
+        handshake: Duration::from_millis(300),
+        ..Keepalive::default()
+    };
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        keepalive,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (network, _) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+
+    // a peer that never answers the challenge is dropped
+    let mut stream = TcpStream::connect(network.address()).await.unwrap();
+    assert!(matches!(
+        read_message(&mut stream).await.unwrap(),
+        P2pMessage::Challenge(_)
+    ));
+    timeout(Duration::from_secs(5), read_message(&mut stream))
+        .await
+        .unwrap()
+        .unwrap_err();
+
+    // a seed that accepts but never answers does not hang the start
+    let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        keepalive,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![silent
+            .local_addr()
+            .unwrap()])
+    };
+    let started = timeout(
+        Duration::from_secs(5),
+        Network::start(config, Arc::new(Credentials::new())),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(started, Err(P2pError::Unreachable)));
+}
+
+#[tokio::test]
+async fn handshakes_are_bounded() {
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        limits: Limits {
+            handshakes: 1,
+            ..Limits::default()
+        },
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (network, _) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+
+    // a long frame before the handshake is refused, even if it is within
+    // the limit of established connections
+    let mut stream = TcpStream::connect(network.address()).await.unwrap();
+    read_message(&mut stream).await.unwrap();
+    stream
+        .write_all(&(MAX_HANDSHAKE_FRAME_LENGTH + 1).to_be_bytes())
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), read_message(&mut stream))
+        .await
+        .unwrap()
+        .unwrap_err();
+    // the permit of a handshake is given back just after its connection
+    sleep(Duration::from_millis(100)).await;
+
+    // while one handshake goes on, other connections are closed
+    let mut stalled = TcpStream::connect(network.address()).await.unwrap();
+    read_message(&mut stalled).await.unwrap();
+    let mut refused = TcpStream::connect(network.address()).await.unwrap();
+    timeout(Duration::from_secs(5), read_message(&mut refused))
+        .await
+        .unwrap()
+        .unwrap_err();
+    drop(stalled);
+    sleep(Duration::from_millis(100)).await;
+
+    // and accepted again once it is over
+    let credentials = Credentials::new();
+    timeout(
+        Duration::from_secs(5),
+        introduce(network.address(), &credentials, "127.0.0.1:1"),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn peers_see_the_advertised_address() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let advertised = format!("localhost:{}", port);
+    let config = NetworkConfig {
+        advertise: Some(advertised.clone()),
+        ..NetworkConfig::new(SocketAddr::from(([127, 0, 0, 1], port)), vec![])
+    };
+    let (seed, _) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+    assert_eq!(seed.advertised_address(), advertised);
+
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![seed.address()]);
+    let (joined, _) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+    assert!(joined.peers().contains_key(&advertised));
+    timeout(Duration::from_secs(5), async {
+        while !seed.peers().contains_key(joined.advertised_address()) {
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn gossiped_records_are_dialed() {
+    let config = |seeds| NetworkConfig {
+        transport: Transport::Plaintext,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), seeds)
+    };
+    // only the node with the smaller key dials
+    let mut credentials = [Credentials::new(), Credentials::new()];
+    credentials.sort_by_key(|credentials| credentials.public_key.to_bytes());
+    let [dialing, dialed] = credentials;
+    let (known, _) = Network::start(config(vec![]), Arc::new(dialed.clone()))
+        .await
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let seed = Credentials::new();
+    let (mut stream, started) = tokio::join!(
+        answer(&listener, &seed),
+        Network::start(
+            config(vec![listener.local_addr().unwrap()]),
+            Arc::new(dialing)
+        )
+    );
+    let (network, _) = started.unwrap();
+
+    let expired = PeerRecord::sign_at(&Credentials::new(), "127.0.0.1:1", 0).unwrap();
+    let mut forged = PeerRecord::sign(&Credentials::new(), "127.0.0.1:2").unwrap();
+    forged.address = "127.0.0.1:3".to_owned();
+    let record = PeerRecord::sign(&dialed, &known.address().to_string()).unwrap();
+    let records = P2pMessage::PeerRecords(vec![expired, forged, record]);
+    write_message(&mut stream, &records).await.unwrap();
+
+    timeout(Duration::from_secs(5), async {
+        while !network.is_reachable(dialed.public_key.into()) {
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(network.status().len(), 2);
+}
+
+#[tokio::test]
+async fn gossip_is_bounded() {
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        keepalive: Keepalive {
+            backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(50),
+            ..Keepalive::default()
+        },
+        gossip: Gossip {
+            per_message: 3,
+            capacity: 4,
+            attempts: 2,
+            ..Gossip::default()
+        },
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let credentials = Credentials::new();
+    let (network, _) = Network::start(config, Arc::new(credentials.clone()))
+        .await
+        .unwrap();
+    let mut stream = introduce(network.address(), &Credentials::new(), "127.0.0.1:1").await;
+
+    // records of nodes with larger keys, so that the network dials them
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let mut port = 10;
+    let mut records = |count, timestamp| {
+        let mut records = vec![];
+        while records.len() < count {
+            let node = Credentials::new();
+            if node.public_key.to_bytes() > credentials.public_key.to_bytes() {
+                port += 1;
+                let address = format!("127.0.0.1:{}", port);
+                records.push(PeerRecord::sign_at(&node, &address, timestamp).unwrap());
+            }
+        }
+        P2pMessage::PeerRecords(records)
+    };
+    let (old, new) = (records(5, now - 60_000), records(3, now));
+
+    write_message(&mut stream, &old).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(network.address_book().len(), 3);
+    // newer records push out the oldest
+    write_message(&mut stream, &new).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let book = network.address_book();
+    assert_eq!(book.len(), 4);
+    assert_eq!(
+        book.iter().filter(|record| record.timestamp == now).count(),
+        3
+    );
+
+    // the unreachable addresses are given up
+    timeout(Duration::from_secs(5), async {
+        while network.status().len() > 1 {
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn seeds_are_resolved_when_dialed() {
+    let credentials = Arc::new(Credentials::new());
+    let (first, _) = Network::start(
+        NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![]),
+        credentials.clone(),
+    )
+    .await
+    .unwrap();
+
+    // a seed that does not resolve is skipped like one that is down
+    let config = NetworkConfig {
+        seeds: vec![
+            "unresolvable.invalid:5050".to_owned(),
+            format!("localhost:{}", first.address().port()),
+        ],
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (second, _) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+    assert!(second.is_reachable(credentials.public_key.into()));
+}
+
+#[tokio::test]
+async fn restarts_rejoin_through_the_address_book() {
+    let config = |seeds| NetworkConfig {
+        gossip: Gossip {
+            interval: Duration::from_millis(50),
+            ..Gossip::default()
+        },
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), seeds)
+    };
+    let down = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let credentials = Arc::new(Credentials::new());
+    let (first, _) = Network::start(config(vec![]), credentials.clone())
+        .await
+        .unwrap();
+    assert!(matches!(
+        Network::start(config(vec![down]), Arc::new(Credentials::new())).await,
+        Err(P2pError::Unreachable)
+    ));
+    // seeds that are down are skipped
+    let (second, _) = Network::start(
+        config(vec![down, first.address()]),
+        Arc::new(Credentials::new()),
+    )
+    .await
+    .unwrap();
+    assert!(second.is_reachable(credentials.public_key.into()));
+
+    let address_book = timeout(Duration::from_secs(5), async {
+        loop {
+            let address_book = second.address_book();
+            if !address_book.is_empty() {
+                break address_book
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(address_book[0].public_key, credentials.public_key);
+
+    let restarted = NetworkConfig {
+        address_book,
+        ..config(vec![])
+    };
+    let (third, _) = Network::start(restarted, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+    assert!(third.is_reachable(credentials.public_key.into()));
+}
+
+/// Dials `address` and runs a plaintext handshake as `credentials`
+async fn introduce(address: SocketAddr, credentials: &Credentials, listener: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let theirs = match read_message(&mut stream).await.unwrap() {
+        P2pMessage::Challenge(theirs) => theirs,
+        message => panic!("expected a challenge, got {:?}", message),
+    };
+    write_message(&mut stream, &P2pMessage::Challenge(challenge()))
+        .await
+        .unwrap();
+    let init = InitMessage::sign(credentials, listener, &Protocol::default(), &theirs).unwrap();
+    write_message(&mut stream, &P2pMessage::Init(init))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read_message(&mut stream).await.unwrap(),
+        P2pMessage::InitResponse(_)
+    ));
+    stream
+}
+
+#[tokio::test]
+async fn simultaneous_connections_are_deduplicated() {
+    let mut credentials = [Credentials::new(), Credentials::new()];
+    credentials.sort_by_key(|credentials| credentials.public_key.to_bytes());
+    let [smaller, larger] = credentials;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![address])
+    };
+    let (mut dialed, started) = tokio::join!(
+        answer(&listener, &smaller),
+        Network::start(config, Arc::new(larger))
+    );
+    let (network, _) = started.unwrap();
+
+    // the connection dialed by the smaller key wins on both sides
+    let _kept = introduce(network.address(), &smaller, &address.to_string()).await;
+    write_message(&mut dialed, &P2pMessage::Ping(1))
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), async {
+        while read_message(&mut dialed).await.is_ok() {}
+    })
+    .await
+    .unwrap();
+
+    let status = network.status();
+    assert_eq!(status.len(), 1);
+    assert!(status[&address.to_string()].connected);
+    assert!(network.is_reachable(smaller.public_key.into()));
+}
+
+#[test]
+fn garbage_never_panics_the_decoder() {
+    let mut rng = StdRng::seed_from_u64(18);
+    for _ in 0..10_000 {
+        let mut bytes = vec![0; rng.gen_range(0..64)];
+        rng.fill(bytes.as_mut_slice());
+        if rng.gen() {
+            // a plausible header makes the payload reach the JSON decoder
+            bytes.splice(..0, frame(FrameKind::Other, &bytes.clone()));
+        }
+        let _ = decode(&bytes);
+        let mut buffer = bytes.as_slice();
+        while let Ok(Some((_, length))) = decode_prefix(buffer) {
+            buffer = &buffer[length..];
+        }
+    }
+}
+
+#[tokio::test]
+async fn garbage_closes_only_its_connection() {
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (network, _) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+
+    let credentials = Credentials::new();
+    let mut stream = introduce(network.address(), &credentials, "127.0.0.1:1").await;
+    stream
+        .write_all(&frame(FrameKind::Other, b"{\"Ping\":"))
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), async {
+        while read_message(&mut stream).await.is_ok() {}
+    })
+    .await
+    .unwrap();
+    assert!(!network.is_reachable(credentials.public_key.into()));
+
+    let _stream = introduce(network.address(), &credentials, "127.0.0.1:1").await;
+    assert!(network.is_reachable(credentials.public_key.into()));
+}
+
+fn permissioned(allowed: &[&Credentials], observers: bool) -> NetworkConfig {
+    NetworkConfig {
+        allowlist: Some(Allowlist {
+            keys: allowed
+                .iter()
+                .map(|credentials| credentials.public_key.into())
+                .collect::<BTreeSet<_>>(),
+            observers,
+        }),
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    }
+}
+
+#[tokio::test]
+async fn only_allowlisted_peers_connect() {
+    let (validator, allowed) = (Arc::new(Credentials::new()), Arc::new(Credentials::new()));
+    let config = permissioned(&[&validator, &allowed], false);
+    let (network, _) = Network::start(config, validator.clone()).await.unwrap();
+    let seed = vec![network.address()];
+
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), seed.clone());
+    assert!(matches!(
+        Network::start(config, Arc::new(Credentials::new())).await,
+        Err(P2pError::Unreachable)
+    ));
+    assert_eq!(network.rejected(), 1);
+
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), seed);
+    let (joined, _) = Network::start(config, allowed.clone()).await.unwrap();
+    assert!(joined.is_reachable(validator.public_key.into()));
+
+    // a permissioned node does not dial keys outside of its allowlist either
+    let (open, _) = Network::start(
+        NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![]),
+        Arc::new(Credentials::new()),
+    )
+    .await
+    .unwrap();
+    let config = NetworkConfig {
+        seeds: vec![open.address().to_string()],
+        ..permissioned(&[&validator], false)
+    };
+    assert!(Network::start(config, validator).await.is_err());
+}
+
+#[tokio::test]
+async fn allowlist_follows_reconfigurations() {
+    let (validator, leaving, joining) = (
+        Arc::new(Credentials::new()),
+        Arc::new(Credentials::new()),
+        Arc::new(Credentials::new()),
+    );
+    let config = permissioned(&[&validator, &leaving], false);
+    let (network, _) = Network::start(config, validator.clone()).await.unwrap();
+    let seed = || NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![network.address()]);
+    let (_left, _) = Network::start(seed(), leaving.clone()).await.unwrap();
+    assert!(network.is_reachable(leaving.public_key.into()));
+
+    let validators = |keys: &[&Arc<Credentials>]| {
+        ValidatorSet::equal_weight(0, keys.iter().map(|credentials| credentials.public_key))
+    };
+    let mut allowlist = network.allowlist().unwrap();
+    allowlist.reconfigure(
+        &validators(&[&validator, &leaving]),
+        &validators(&[&validator, &joining]),
+    );
+    network.set_allowlist(Some(allowlist));
+
+    // peers that left are disconnected and the new ones let in
+    timeout(Duration::from_secs(5), async {
+        while network.is_reachable(leaving.public_key.into()) {
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let (joined, _) = Network::start(seed(), joining.clone()).await.unwrap();
+    assert!(joined.is_reachable(validator.public_key.into()));
+    assert!(matches!(
+        Network::start(seed(), leaving).await,
+        Err(P2pError::Unreachable)
+    ));
+}
+
+#[tokio::test]
+async fn observers_only_receive() {
+    let (validator, other) = (Arc::new(Credentials::new()), Arc::new(Credentials::new()));
+    let config = permissioned(&[&validator, &other], true);
+    let (network, mut inbound) = Network::start(config, validator.clone()).await.unwrap();
+
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![network.address()]);
+    let (observer, mut observed) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+    let status = network.status();
+    assert!(status[observer.advertised_address()].observer);
+    assert_eq!(network.rejected(), 0);
+
+    let message = P2pMessage::Consensus(consensus_message());
+    observer.send_to(validator.public_key, &message).await;
+    assert!(timeout(Duration::from_millis(500), inbound.recv())
+        .await
+        .is_err());
+
+    // what an observer sent does not hide the same message from others
+    let config = NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![network.address()]);
+    let (peer, _) = Network::start(config, other.clone()).await.unwrap();
+    peer.send_to(validator.public_key, &message).await;
+    let received = timeout(Duration::from_secs(5), inbound.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.from, other.public_key);
+
+    network.broadcast(&message).await;
+    let received = timeout(Duration::from_secs(5), observed.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.from, validator.public_key);
+}
+
+#[tokio::test]
+async fn chatty_peers_are_rate_limited() {
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        limits: Limits {
+            rate: 1,
+            burst: 10,
+            ..Limits::default()
+        },
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (network, mut inbound) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+
+    let (chatty, quiet) = (Credentials::new(), Credentials::new());
+    let mut stream = introduce(network.address(), &chatty, "127.0.0.1:1").await;
+    // distinct messages, so that none of them is dropped as a repeat
+    for _ in 0..50 {
+        let message = P2pMessage::Consensus(consensus_message());
+        write_message(&mut stream, &message).await.unwrap();
+    }
+    let mut received = 0;
+    while timeout(Duration::from_millis(500), inbound.recv())
+        .await
+        .is_ok()
+    {
+        received += 1;
+    }
+    assert!((10..=11).contains(&received), "received {}", received);
+    assert!(network.status()["127.0.0.1:1"].limited >= 39);
+    // all dropped within the same window
+    assert_eq!(network.reputations()[0].offenses[&Offense::Spam], 1);
+
+    let mut stream = introduce(network.address(), &quiet, "127.0.0.1:2").await;
+    let message = P2pMessage::Consensus(consensus_message());
+    write_message(&mut stream, &message).await.unwrap();
+    let inbound = timeout(Duration::from_secs(5), inbound.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(inbound.from, quiet.public_key);
+}
+
+#[tokio::test]
+async fn peers_are_limited_by_bytes() {
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        limits: Limits {
+            bytes: 1,
+            byte_burst: 4096,
+            ..Limits::default()
+        },
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (network, mut inbound) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+
+    let chatty = Credentials::new();
+    let mut stream = introduce(network.address(), &chatty, "127.0.0.1:1").await;
+    for _ in 0..50 {
+        let message = P2pMessage::Consensus(consensus_message());
+        write_message(&mut stream, &message).await.unwrap();
+    }
+    let mut received = 0;
+    while timeout(Duration::from_millis(500), inbound.recv())
+        .await
+        .is_ok()
+    {
+        received += 1;
+    }
+    // far fewer than the message rate allows
+    assert!((1..50).contains(&received), "received {}", received);
+    assert!(network.status()["127.0.0.1:1"].limited >= 50 - received);
+}
+
+#[tokio::test]
+async fn slow_peers_are_disconnected() {
+    let config = |seeds| NetworkConfig {
+        transport: Transport::Plaintext,
+        limits: Limits {
+            queue: 16,
+            overflow: Overflow::Disconnect,
+            ..Limits::default()
+        },
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), seeds)
+    };
+    let (network, _) = Network::start(config(vec![]), Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+    let fast = Arc::new(Credentials::new());
+    let (_fast, _) = Network::start(config(vec![network.address()]), fast.clone())
+        .await
+        .unwrap();
+    // never reads what it is sent
+    let slow = Credentials::new();
+    let _stream = introduce(network.address(), &slow, "127.0.0.1:1").await;
+
+    let large = P2pMessage::Peers([("a".repeat(256 * 1024), slow.public_key)].into());
+    timeout(Duration::from_secs(20), async {
+        while network.is_reachable(slow.public_key.into()) {
+            network.broadcast(&large).await;
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(network.is_reachable(fast.public_key.into()));
+}
+
+#[tokio::test]
+async fn peers_sending_garbage_are_banned() {
+    let config = |bans| NetworkConfig {
+        transport: Transport::Plaintext,
+        bans,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (network, _) = Network::start(config(vec![]), Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+
+    let credentials = Credentials::new();
+    let key = credentials.public_key.into();
+    for _ in 0..2 {
+        let mut stream = introduce(network.address(), &credentials, "127.0.0.1:1").await;
+        stream
+            .write_all(&frame(FrameKind::Other, b"garbage"))
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), async {
+            while read_message(&mut stream).await.is_ok() {}
+        })
+        .await
+        .unwrap();
+    }
+    assert!(network.is_banned(key));
+    let ban = network.reputations()[0].ban.clone().unwrap();
+    assert_eq!(ban.reasons, [(Offense::MalformedFrame, 2)].into());
+
+    // bans survive a restart
+    let (restarted, _) = Network::start(config(network.bans()), Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+    for network in [network, restarted] {
+        let mut stream = TcpStream::connect(network.address()).await.unwrap();
+        let theirs = match read_message(&mut stream).await.unwrap() {
+            P2pMessage::Challenge(theirs) => theirs,
+            message => panic!("expected a challenge, got {:?}", message),
+        };
+        write_message(&mut stream, &P2pMessage::Challenge(challenge()))
+            .await
+            .unwrap();
+        let init =
+            InitMessage::sign(&credentials, "127.0.0.1:1", &Protocol::default(), &theirs).unwrap();
+        write_message(&mut stream, &P2pMessage::Init(init))
+            .await
+            .unwrap();
+        assert!(read_message(&mut stream).await.is_err());
+        assert!(!network.is_reachable(key));
+    }
+}
+
+#[tokio::test]
+async fn repeated_consensus_messages_are_dropped() {
+    let config = NetworkConfig {
+        transport: Transport::Plaintext,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let (network, mut inbound) = Network::start(config, Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+    let (first, second) = (Credentials::new(), Credentials::new());
+    let mut streams = [
+        introduce(network.address(), &first, "127.0.0.1:1").await,
+        introduce(network.address(), &second, "127.0.0.1:2").await,
+    ];
+
+    let message = transaction(&Credentials::new(), 1, &[(1, 1)]);
+    let validators = ValidatorSet::equal_weight(0, [first.public_key]);
+    let vote = Vote::sign(VoteKind::Echo, &validators, &message.hash, &first);
+    let echo = ConsensusMessage::Echo(message.clone(), Box::new(vote));
+    for consensus in [ConsensusMessage::Send(message), echo] {
+        for stream in &mut streams {
+            write_message(stream, &P2pMessage::Consensus(consensus.clone()))
+                .await
+                .unwrap();
+        }
+    }
+
+    // the SEND arrives once, votes arrive once from every peer that sent them
+    let mut received = vec![];
+    while let Ok(Some(inbound)) = timeout(Duration::from_millis(500), inbound.recv()).await {
+        received.push(inbound.from);
+    }
+    assert_eq!(received.len(), 3);
+    let stats = network.dedup_stats();
+    assert_eq!((stats.hits, stats.misses), (1, 3));
+}
+
+#[tokio::test]
+async fn incompatible_peers_are_refused() {
+    let config = |protocol| NetworkConfig {
+        protocol,
+        ..NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![])
+    };
+    let ours = Protocol {
+        capabilities: BTreeSet::from(["quorum-broadcast".to_owned(), "extra".to_owned()]),
+        required: BTreeSet::from(["quorum-broadcast".to_owned()]),
+        ..Protocol::default()
+    };
+    let (network, _) = Network::start(config(ours.clone()), Arc::new(Credentials::new()))
+        .await
+        .unwrap();
+
+    // an upgraded node that still speaks our version is fine
+    let upgraded = Protocol {
+        version: PROTOCOL_VERSION + 1,
+        ..ours.clone()
+    };
+    assert_eq!(ours.negotiate(&upgraded).unwrap(), PROTOCOL_VERSION);
+    let (peer, _) = Network::start(
+        NetworkConfig {
+            seeds: vec![network.address().to_string()],
+            ..config(upgraded)
+        },
+        Arc::new(Credentials::new()),
+    )
+    .await
+    .unwrap();
+    let status = peer.status();
+    let status = status.values().next().unwrap();
+    assert!(status.connected);
+    assert_eq!(status.version, Some(PROTOCOL_VERSION));
+
+    let other_chain = Protocol {
+        chain_id: "other".to_owned(),
+        ..ours.clone()
+    };
+    let too_new = Protocol {
+        min_version: PROTOCOL_VERSION + 1,
+        version: PROTOCOL_VERSION + 1,
+        ..ours.clone()
+    };
+    assert!(matches!(
+        ours.negotiate(&other_chain),
+        Err(P2pError::WrongChain { .. })
+    ));
+    assert!(matches!(
+        ours.negotiate(&too_new),
+        Err(P2pError::IncompatibleVersion { .. })
+    ));
+    assert!(matches!(
+        ours.negotiate(&Protocol::default()),
+        Err(P2pError::MissingCapabilities(missing)) if missing == ["quorum-broadcast"]
+    ));
+    assert!(matches!(
+        Protocol::default().negotiate(&ours),
+        Err(P2pError::UnsupportedCapabilities(missing)) if missing == ["quorum-broadcast"]
+    ));
+    // the dialing side refuses as well
+    for protocol in [other_chain, too_new, Protocol::default()] {
+        let joined = Network::start(
+            NetworkConfig {
+                seeds: vec![network.address().to_string()],
+                ..config(protocol)
+            },
+            Arc::new(Credentials::new()),
+        )
+        .await;
+        assert!(matches!(joined, Err(P2pError::Unreachable)));
+    }
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(network.peers().len(), 1);
+}
@@ -0,0 +1,155 @@
+use std::{sync::Arc, time::Duration};
+
+use common::transaction::transaction;
+use equity_consensus::{Bracha, KeyValueState, SequenceError};
+use equity_core::{
+    load_bans, start_consensus_server, start_peer_store, ConsensusHandle, Submission,
+};
+use equity_p2p::{Network, NetworkConfig};
+use equity_storage::{DatabaseType, EquityDatabase};
+use equity_types::{ConsensusMessage, Credentials, FullMessage, Offense, ValidatorSet, VoteKind};
+
+fn log_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("equity-{:016x}.redb", rand::random::<u64>()))
+}
+
+async fn submit(consensus: &ConsensusHandle, message: &FullMessage) -> Submission {
+    consensus
+        .submit(message.clone())
+        .await
+        .unwrap()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn consensus_resumes_after_a_restart() {
+    let path = log_path();
+    let credentials = Credentials::new();
+    let validators = ValidatorSet::equal_weight(0, [credentials.public_key]);
+    let (network, _inbound) = Network::start(
+        NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![]),
+        Arc::new(credentials.clone()),
+    )
+    .await
+    .unwrap();
+    let start = || {
+        let db = EquityDatabase::open(&DatabaseType::File(path.clone())).unwrap();
+        let bracha = Bracha::new(credentials.clone(), validators.clone());
+        let (consensus, handle) =
+            start_consensus_server(db.clone(), network.clone(), bracha, KeyValueState);
+        (db, consensus, handle)
+    };
+
+    let account = Credentials::new();
+    let first = transaction(&account, 1, &[(1, 10)]);
+    let third = transaction(&account, 3, &[(1, 30)]);
+    let (_, consensus, handle) = start();
+    assert!(matches!(
+        submit(&consensus, &first).await,
+        Submission::Delivered(_)
+    ));
+    assert!(matches!(
+        submit(&consensus, &third).await,
+        Submission::Buffered(_)
+    ));
+    handle.abort();
+    let _ = handle.await;
+
+    // the nonces that were used stay used
+    let (db, consensus, _handle) = start();
+    assert!(matches!(
+        submit(&consensus, &first).await,
+        Submission::Rejected(SequenceError::Duplicate { nonce: 1 })
+    ));
+    let conflicting = transaction(&account, 1, &[(1, 11)]);
+    assert!(matches!(
+        submit(&consensus, &conflicting).await,
+        Submission::Rejected(SequenceError::Conflict { nonce: 1, .. })
+    ));
+    assert_eq!(consensus.waiting().await.unwrap().len(), 1);
+
+    // the held back transaction is applied once the gap is filled
+    let second = transaction(&account, 2, &[(1, 20)]);
+    assert!(matches!(
+        submit(&consensus, &second).await,
+        Submission::Delivered(_)
+    ));
+    assert!(consensus.waiting().await.unwrap().is_empty());
+    assert_eq!(
+        KeyValueState::get(&db, account.public_key, 1).unwrap(),
+        Some(30)
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn votes_are_kept_across_a_restart() {
+    let path = log_path();
+    let credentials: Vec<Credentials> = (0..4).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let (network, _inbound) = Network::start(
+        NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![]),
+        Arc::new(credentials[0].clone()),
+    )
+    .await
+    .unwrap();
+    let start = || {
+        let db = EquityDatabase::open(&DatabaseType::File(path.clone())).unwrap();
+        let bracha = Bracha::new(credentials[0].clone(), validators.clone());
+        let (consensus, handle) =
+            start_consensus_server(db.clone(), network.clone(), bracha, KeyValueState);
+        (db, consensus, handle)
+    };
+
+    // the other validators are down, so the message is echoed but never
+    // delivered
+    let account = Credentials::new();
+    let first = transaction(&account, 1, &[(1, 10)]);
+    let (db, consensus, handle) = start();
+    let _submitted = consensus.submit(first.clone()).await.unwrap();
+    let key = ("vote", &first.hash, VoteKind::Echo);
+    while db.get::<_, ConsensusMessage>(key).unwrap().is_none() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    handle.abort();
+    let _ = handle.await;
+    drop(db);
+
+    // after a restart we still won't echo another message for the nonce
+    let (_, consensus, _handle) = start();
+    let conflicting = transaction(&account, 1, &[(1, 11)]);
+    let submitted = tokio::time::timeout(Duration::from_secs(5), submit(&consensus, &conflicting));
+    assert!(matches!(
+        submitted.await,
+        Ok(Submission::Rejected(SequenceError::Conflict {
+            nonce: 1,
+            ..
+        }))
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn bans_are_stored_when_issued() {
+    let (network, _inbound) = Network::start(
+        NetworkConfig::new("127.0.0.1:0".parse().unwrap(), vec![]),
+        Arc::new(Credentials::new()),
+    )
+    .await
+    .unwrap();
+    let db = EquityDatabase::in_memory();
+    let _store = start_peer_store(db.clone(), network.clone());
+
+    // well before the address book is written again
+    let offender = Credentials::new().public_key;
+    network.report(offender.into(), Offense::ConflictingVote);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while load_bans(&db).is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(load_bans(&db), network.bans());
+}
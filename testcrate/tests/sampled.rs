@@ -0,0 +1,137 @@
+use std::collections::VecDeque;
+
+use common::transaction::transaction;
+use ed25519_consensus::VerificationKeyBytes;
+use equity_consensus::{Bracha, Output, SampleError, SampleParameters};
+use equity_types::{Credentials, ValidatorSet};
+
+/// Broadcasts a message from node 0 with samples of `size` until nothing is
+/// left to send. Nodes in `silent` never send anything. Returns how many nodes
+/// delivered and how many messages were sent.
+fn run(n: usize, size: usize, silent: &[usize]) -> (usize, usize) {
+    let credentials: Vec<Credentials> = (0..n).map(|_| Credentials::new()).collect();
+    let validators = ValidatorSet::equal_weight(0, credentials.iter().map(|c| c.public_key));
+    let mut nodes: Vec<Bracha> = credentials
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            Bracha::new(c.clone(), validators.clone())
+                .with_sampling(SampleParameters::new(size), i as u64)
+        })
+        .collect();
+    let index = |key| {
+        credentials
+            .iter()
+            .position(|c| VerificationKeyBytes::from(c.public_key) == key)
+            .unwrap()
+    };
+
+    let message = transaction(&Credentials::new(), 1, &[(1, 2)]);
+    let mut queue: VecDeque<(usize, Output)> = nodes[0]
+        .broadcast(message.clone())
+        .into_iter()
+        .map(|output| (0, output))
+        .collect();
+    let (mut delivered, mut sent) = (0, 0);
+    while let Some((from, output)) = queue.pop_front() {
+        let (targets, message) = match output {
+            Output::Send(to, message) => (vec![index(to)], message),
+            Output::Broadcast(message) => ((0..n).filter(|i| *i != from).collect(), message),
+            Output::Deliver(delivered_message, _) => {
+                assert_eq!(delivered_message, message);
+                delivered += 1;
+                continue
+            }
+            // late subscriptions are answered with the delivered message
+            Output::Late(peer, _, late) => {
+                let outputs = nodes[from].late(peer, message.clone(), late);
+                queue.extend(outputs.into_iter().map(|output| (from, output)));
+                continue
+            }
+            output => panic!("unexpected output {:?}", output),
+        };
+        if silent.contains(&from) {
+            continue
+        }
+        for to in targets {
+            sent += 1;
+            let outputs = nodes[to].handle(credentials[from].public_key, message.clone());
+            queue.extend(outputs.into_iter().map(|output| (to, output)));
+        }
+    }
+    (delivered, sent)
+}
+
+#[test]
+fn all_deliver_with_fewer_messages() {
+    let n = 60;
+    let (delivered, sent) = run(n, 20, &[]);
+    assert_eq!(delivered, n);
+    // Bracha sends ECHO and READY from every node to every other
+    assert!(sent < 2 * n * (n - 1), "sent {} messages", sent);
+}
+
+#[test]
+fn tolerates_silent_nodes() {
+    let (delivered, _) = run(60, 20, &[57, 58, 59]);
+    assert!(delivered >= 57, "only {} delivered", delivered);
+}
+
+#[test]
+fn sample_parameters() {
+    let parameters = SampleParameters::new(30);
+    assert_eq!(
+        (
+            parameters.echo_threshold,
+            parameters.ready_threshold,
+            parameters.delivery_threshold
+        ),
+        (21, 11, 21)
+    );
+}
+
+#[test]
+fn thresholds_are_checked() {
+    let parameters = SampleParameters::with_thresholds(30, 16, 8, 20).unwrap();
+    assert_eq!(
+        (
+            parameters.echo_threshold,
+            parameters.ready_threshold,
+            parameters.delivery_threshold
+        ),
+        (16, 8, 20)
+    );
+    assert!(SampleParameters::new(30).check().is_ok());
+
+    assert_eq!(
+        SampleParameters::with_thresholds(30, 31, 11, 21),
+        Err(SampleError::OutOfRange {
+            step: "echo",
+            threshold: 31,
+            size: 30
+        })
+    );
+    assert_eq!(
+        SampleParameters::with_thresholds(30, 21, 0, 21),
+        Err(SampleError::OutOfRange {
+            step: "ready",
+            threshold: 0,
+            size: 30
+        })
+    );
+    assert_eq!(
+        SampleParameters::with_thresholds(30, 21, 11, 15),
+        Err(SampleError::NoMajority {
+            step: "delivery",
+            threshold: 15,
+            size: 30
+        })
+    );
+    assert_eq!(
+        SampleParameters::with_thresholds(30, 21, 25, 21),
+        Err(SampleError::ReadyAboveDelivery {
+            ready: 25,
+            delivery: 21
+        })
+    );
+}
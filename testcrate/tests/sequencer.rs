@@ -0,0 +1,117 @@
+use common::transaction::{dependent, transaction};
+use equity_consensus::{SequenceError, Sequenced, Sequencer};
+use equity_types::Credentials;
+
+#[test]
+fn releases_in_nonce_order() {
+    let credentials = Credentials::new();
+    let mut sequencer = Sequencer::new();
+    let (first, second, third) = (
+        transaction(&credentials, 1, &[(1, 0)]),
+        transaction(&credentials, 2, &[(1, 0)]),
+        transaction(&credentials, 3, &[(1, 0)]),
+    );
+
+    assert_eq!(sequencer.push(third.clone()), Ok(Sequenced::Buffered(1..3)));
+    assert_eq!(
+        sequencer.push(second.clone()),
+        Ok(Sequenced::Buffered(1..2))
+    );
+    assert_eq!(sequencer.gaps().len(), 1);
+    assert_eq!(
+        sequencer.push(first.clone()),
+        Ok(Sequenced::Released(vec![first, second, third]))
+    );
+    assert_eq!(sequencer.last_nonce(&credentials.public_key.into()), 3);
+    assert!(sequencer.gaps().is_empty());
+}
+
+#[test]
+fn rejects_reused_nonces() {
+    let credentials = Credentials::new();
+    let mut sequencer = Sequencer::new();
+    let spend = transaction(&credentials, 1, &[(1, 10)]);
+    let double_spend = transaction(&credentials, 1, &[(1, 20)]);
+
+    sequencer.push(spend.clone()).unwrap();
+    assert_eq!(
+        sequencer.check(&spend),
+        Err(SequenceError::Duplicate { nonce: 1 })
+    );
+    assert_eq!(
+        sequencer.push(double_spend),
+        Err(SequenceError::Conflict {
+            nonce: 1,
+            existing: spend.hash
+        })
+    );
+
+    // conflicts with buffered messages are caught as well
+    sequencer
+        .push(transaction(&credentials, 3, &[(1, 10)]))
+        .unwrap();
+    assert!(matches!(
+        sequencer.check(&transaction(&credentials, 3, &[(1, 20)])),
+        Err(SequenceError::Conflict { nonce: 3, .. })
+    ));
+    assert_eq!(
+        sequencer.check(&transaction(&credentials, 0, &[(1, 10)])),
+        Err(SequenceError::ZeroNonce)
+    );
+}
+
+#[test]
+fn holds_until_dependencies_are_released() {
+    let (alice, bob, carol) = (Credentials::new(), Credentials::new(), Credentials::new());
+    let mut sequencer = Sequencer::new();
+    let payment = transaction(&alice, 1, &[(1, 10)]);
+    let other_payment = transaction(&carol, 1, &[(1, 5)]);
+    let spend = dependent(&bob, 1, &[(1, 15)], &[&payment, &other_payment]);
+    let next = transaction(&bob, 2, &[(1, 0)]);
+
+    assert_eq!(
+        sequencer.push(spend.clone()),
+        Ok(Sequenced::Waiting(vec![
+            payment.hash.clone(),
+            other_payment.hash.clone()
+        ]))
+    );
+    // later nonces wait behind it
+    assert!(matches!(
+        sequencer.push(next.clone()),
+        Ok(Sequenced::Waiting(_))
+    ));
+    let waiting = sequencer.waiting();
+    assert_eq!(waiting.len(), 2);
+    assert_eq!(waiting[0].missing_dependencies.len(), 2);
+    assert!(sequencer.gaps().is_empty());
+
+    assert_eq!(
+        sequencer.push(payment.clone()),
+        Ok(Sequenced::Released(vec![payment]))
+    );
+    assert_eq!(sequencer.waiting()[0].missing_dependencies, vec![
+        other_payment.hash.clone()
+    ]);
+    assert_eq!(
+        sequencer.push(other_payment.clone()),
+        Ok(Sequenced::Released(vec![other_payment, spend, next]))
+    );
+    assert!(sequencer.waiting().is_empty());
+}
+
+#[test]
+fn waiting_lists_missing_nonces() {
+    let credentials = Credentials::new();
+    let mut sequencer = Sequencer::new();
+    sequencer
+        .push(transaction(&credentials, 3, &[(1, 0)]))
+        .unwrap();
+    sequencer
+        .push(transaction(&credentials, 6, &[(1, 0)]))
+        .unwrap();
+
+    let waiting = sequencer.waiting();
+    assert_eq!(waiting[0].missing_nonces, vec![1..3]);
+    assert_eq!(waiting[1].missing_nonces, vec![1..3, 4..6]);
+}
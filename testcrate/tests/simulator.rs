@@ -0,0 +1,108 @@
+use common::simulator::{Simulator, SimulatorConfig};
+use equity_consensus::SampleParameters;
+use equity_types::ConsensusMessage;
+
+fn run(seed: u64, config: SimulatorConfig) -> Simulator {
+    let mut simulator = Simulator::new(seed, config);
+    simulator.broadcast(0, Simulator::transaction(1, 1, &[(1, 2)]));
+    simulator.run();
+    simulator
+}
+
+#[test]
+fn same_seed_same_run() {
+    let config = SimulatorConfig {
+        nodes: 7,
+        latency: 1..50,
+        drop_rate: 0.05,
+        ..SimulatorConfig::default()
+    };
+    let (a, b) = (run(7, config.clone()), run(7, config.clone()));
+    assert_eq!(a.trace(), b.trace());
+    for node in 0..7 {
+        assert_eq!(a.deliveries(node), b.deliveries(node));
+    }
+    assert_ne!(run(8, config).trace(), a.trace());
+}
+
+#[test]
+fn delivers_despite_reordering() {
+    for seed in 0..20 {
+        let simulator = run(seed, SimulatorConfig {
+            nodes: 7,
+            latency: 1..100,
+            ..SimulatorConfig::default()
+        });
+        for node in 0..7 {
+            assert_eq!(simulator.deliveries(node).len(), 1, "seed {}", seed);
+        }
+    }
+}
+
+#[test]
+fn majority_partition_delivers() {
+    let mut simulator = Simulator::new(1, SimulatorConfig {
+        nodes: 7,
+        ..SimulatorConfig::default()
+    });
+    simulator.partition(&[&[0, 1, 2, 3, 4], &[5, 6]]);
+    simulator.broadcast(0, Simulator::transaction(1, 1, &[(1, 2)]));
+    simulator.run();
+    for node in 0..5 {
+        assert_eq!(simulator.deliveries(node).len(), 1);
+    }
+    for node in 5..7 {
+        assert!(simulator.deliveries(node).is_empty());
+    }
+
+    // a minority can't deliver on its own
+    simulator.broadcast(5, Simulator::transaction(2, 1, &[(1, 2)]));
+    simulator.run();
+    assert!((0..7).all(|node| simulator.deliveries(node).len() < 2));
+}
+
+#[test]
+fn equivocating_sender_is_never_delivered_twice() {
+    for seed in 0..20 {
+        let mut simulator = Simulator::new(seed, SimulatorConfig {
+            nodes: 4,
+            latency: 1..20,
+            drop_rate: 0.1,
+            ..SimulatorConfig::default()
+        });
+        // node 0 sends a different message for the same nonce to each half
+        let first = Simulator::transaction(1, 1, &[(1, 1)]);
+        let second = Simulator::transaction(1, 1, &[(1, 2)]);
+        for node in 1..4 {
+            let message = if node < 2 { &first } else { &second };
+            simulator.inject(0, node, ConsensusMessage::Send(message.clone()));
+        }
+        simulator.run();
+
+        let delivered: Vec<_> = (0..4)
+            .flat_map(|node| simulator.deliveries(node))
+            .map(|delivery| delivery.message.hash.clone())
+            .collect();
+        assert!(
+            delivered.windows(2).all(|pair| pair[0] == pair[1]),
+            "seed {}",
+            seed
+        );
+    }
+}
+
+#[test]
+fn crashed_node_is_tolerated_with_sampling() {
+    let mut simulator = Simulator::new(3, SimulatorConfig {
+        nodes: 30,
+        latency: 1..20,
+        sampling: Some(SampleParameters::new(15)),
+        ..SimulatorConfig::default()
+    });
+    simulator.crash(29);
+    simulator.broadcast(0, Simulator::transaction(1, 1, &[(1, 2)]));
+    simulator.run();
+    for node in 0..29 {
+        assert_eq!(simulator.deliveries(node).len(), 1, "node {}", node);
+    }
+}
@@ -0,0 +1,43 @@
+use common::transaction::transaction;
+use equity_consensus::{KeyValueState, StateMachine};
+use equity_storage::{Batch, EquityDatabase};
+use equity_types::{Credentials, FullMessage};
+
+fn apply_all(messages: &[&FullMessage]) -> (EquityDatabase, String) {
+    let db = EquityDatabase::in_memory();
+    let mut state = KeyValueState;
+    let mut root = state.state_root(&db).unwrap();
+    for message in messages {
+        let mut batch = Batch::default();
+        let receipt = state.apply(&db, &mut batch, message).unwrap();
+        db.write(batch).unwrap();
+        assert_eq!(receipt.hash, message.hash);
+        assert_ne!(receipt.state_root, root);
+        root = receipt.state_root;
+    }
+    assert_eq!(state.state_root(&db).unwrap(), root);
+    (db, root)
+}
+
+#[test]
+fn same_root_in_any_account_order() {
+    let (alice, bob) = (Credentials::new(), Credentials::new());
+    let a1 = transaction(&alice, 1, &[(1, 10), (2, 20)]);
+    let a2 = transaction(&alice, 2, &[(1, 11)]);
+    let b1 = transaction(&bob, 1, &[(1, 99)]);
+
+    let (db, root) = apply_all(&[&a1, &a2, &b1]);
+    assert_eq!(apply_all(&[&b1, &a1, &a2]).1, root);
+    assert_eq!(apply_all(&[&a1, &b1, &a2]).1, root);
+
+    // accounts write to their own keys
+    let get = |account: &Credentials, key| KeyValueState::get(&db, account.public_key, key);
+    assert_eq!(get(&alice, 1).unwrap(), Some(11));
+    assert_eq!(get(&alice, 2).unwrap(), Some(20));
+    assert_eq!(get(&bob, 1).unwrap(), Some(99));
+    assert_eq!(get(&bob, 2).unwrap(), None);
+
+    // the history of an account is part of the root, not just its values
+    let a2_again = transaction(&alice, 2, &[(1, 11), (2, 20)]);
+    assert_ne!(apply_all(&[&a1, &a2_again, &b1]).1, root);
+}
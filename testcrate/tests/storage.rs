@@ -0,0 +1,57 @@
+use std::{fs, path::PathBuf};
+
+use equity_storage::{Batch, DatabaseType, EquityDatabase};
+
+fn database_path() -> PathBuf {
+    std::env::temp_dir().join(format!("equity-{:016x}.redb", rand::random::<u64>()))
+}
+
+#[test]
+fn file_database_survives_reopening() {
+    let path = database_path();
+    let database = DatabaseType::File(path.clone());
+    let db = EquityDatabase::open(&database).unwrap();
+    assert_eq!(db.get::<_, u64>("missing").unwrap(), None);
+    for value in 0..100u64 {
+        db.set(("state", 1), value).unwrap();
+    }
+    assert_eq!(db.set("epoch", 3u64).unwrap(), None);
+    // the file is only open once at a time
+    assert!(EquityDatabase::open(&database).is_err());
+    drop(db);
+
+    let db = EquityDatabase::open(&database).unwrap();
+    assert_eq!(db.get::<_, u64>(("state", 1)).unwrap(), Some(99));
+    assert_eq!(db.get::<_, u64>("epoch").unwrap(), Some(3));
+    assert_eq!(db.set("epoch", 4u64).unwrap(), Some(3));
+    drop(db);
+
+    let db = EquityDatabase::open(&database).unwrap();
+    assert_eq!(db.get::<_, u64>("epoch").unwrap(), Some(4));
+    drop(db);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn file_database_writes_batches_at_once() {
+    let path = database_path();
+    let database = DatabaseType::File(path.clone());
+    let db = EquityDatabase::open(&database).unwrap();
+    db.set("epoch", 1u64).unwrap();
+    db.write(Batch::default()).unwrap();
+    let mut batch = Batch::default();
+    batch.set(("state", 1), 10u64).unwrap();
+    batch.set(("state", 2), 20u64).unwrap();
+    // later entries win over earlier ones
+    batch.set(("state", 1), 11u64).unwrap();
+    db.write(batch).unwrap();
+    assert_eq!(db.get::<_, u64>(("state", 2)).unwrap(), Some(20));
+    drop(db);
+
+    let db = EquityDatabase::open(&database).unwrap();
+    assert_eq!(db.get::<_, u64>("epoch").unwrap(), Some(1));
+    assert_eq!(db.get::<_, u64>(("state", 1)).unwrap(), Some(11));
+    assert_eq!(db.get::<_, u64>(("state", 2)).unwrap(), Some(20));
+    drop(db);
+    fs::remove_file(path).unwrap();
+}